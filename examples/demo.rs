@@ -1,7 +1,7 @@
 use disarm::{Program, arm_cpu};
 
 fn main() {
-    let mut chunk: &[u8] = &[
+    let chunk: &[u8] = &[
         0x09, 0x20, // mov r0, #9
         //0x0a, 0x20, // mov r0, #10
         //0x0e, 0x20, // mov r0, #14
@@ -17,7 +17,7 @@ fn main() {
     ];
     let program = Program::build(chunk, 0x104, 0x20000000);
     let mut cpu = arm_cpu::build();
-    cpu.load_program(&program);
+    cpu.load_program(&program).unwrap();
 
     cpu.start(0x104);
 }
\ No newline at end of file
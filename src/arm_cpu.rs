@@ -1,148 +1,576 @@
 mod arm_memmory;
-use arm_memmory::*;
+pub use arm_memmory::{Access, Fault, Memmory, Permissions, Region, Size};
 
-use crate::{ast::{Thumb, Thumb16, Register, SpecialRegister, Thumb32, DpOpcode, Cond, RegisterList}, disassemble, Program};
+mod peripheral;
+pub use peripheral::Peripheral;
 
-macro_rules! deprecated {
-    () => {
-        panic!("deprecated instruction")
-    };
-    ($($arg:tt)+) => {
-        panic!("deprecated instruction", format_args!($($arg)+))
-    };
+mod exception;
+pub use exception::{Exception, HardFault};
+use exception::EXCEPTION_COUNT;
+
+mod nvic;
+use nvic::{Nvic, NvicRegisters, NVIC_SIZE, NVIC_START};
+
+mod systick;
+use systick::{SysTick, SYSTICK_SIZE, SYSTICK_START};
+
+mod scb;
+pub use scb::Core;
+use scb::{SCB_SIZE, SCB_START};
+
+mod semihosting;
+use semihosting::{Semihosting, SEMIHOSTING_BKPT};
+
+mod debug;
+pub use debug::{WatchKind, Watchpoint};
+
+use std::{cell::RefCell, collections::BTreeSet, rc::Rc, time::{Duration, Instant}};
+
+use crate::{ast::{Thumb, Thumb16, Register, SpecialRegister, Thumb32, DpOpcode, Cond, RegisterList}, disassemble, instructions::Instructions, Error, Program};
+
+// The program status register. APSR, IPSR and EPSR are views of different bits of the one
+// register, MRS and MSR select the views with the bits of SYSm.
+#[derive(Debug, Clone, Copy)]
+struct Xpsr(u32);
+
+impl Xpsr {
+    // APSR<31:28>
+    const N: u32 = 1 << 31; // Negative condition code flag. Set to bit [31] of the result of the instruction. If the result is regarded as a two's complement signed integer, then N is set to 1 if the result is negative and set to 0 if it is positive or zero.
+    const Z: u32 = 1 << 30; // Zero condition code flag. Set to 1 if the result of the instruction is zero, and to 0 otherwise. A result of zero often indicates an equal result from a comparison.
+    const C: u32 = 1 << 29; // Carry condition code flag. Set to 1 if the instruction results in a carry condition, for example an unsigned overflow on an addition.
+    const V: u32 = 1 << 28; // Overflow condition code flag. Set to 1 if the instruction results in an overflow condition, for example a signed overflow on an addition.
+    const APSR: u32 = 0xf0000000;
+    // EPSR<24>, thumb state. Always 1 while executing, clearing it faults on the next instruction
+    const T: u32 = 1 << 24;
+    // IPSR<5:0>, the number of the exception being handled or 0 in thread mode
+    const IPSR: u32 = 0x3f;
+
+    fn get(&self, mask: u32) -> bool {
+        self.0 & mask != 0
+    }
+
+    fn set(&mut self, mask: u32, value: bool) {
+        if value {
+            self.0 |= mask;
+        } else {
+            self.0 &= !mask;
+        }
+    }
+
+    fn n(&self) -> bool {
+        self.get(Self::N)
+    }
+
+    fn z(&self) -> bool {
+        self.get(Self::Z)
+    }
+
+    fn c(&self) -> bool {
+        self.get(Self::C)
+    }
+
+    fn v(&self) -> bool {
+        self.get(Self::V)
+    }
+
+    fn ipsr(&self) -> u32 {
+        self.0 & Self::IPSR
+    }
+
+    fn set_ipsr(&mut self, exception_number: u32) {
+        self.0 = (self.0 & !Self::IPSR) | (exception_number & Self::IPSR);
+    }
 }
 
-#[derive(Debug)]
-struct Flags {
-    n: bool, // N bit [31] Negative condition code flag. Set to bit [31] of the result of the instruction. If the result is regarded as a two's complement signed integer, then N is set to 1 if the result is negative and set to 0 if it is positive or zero.
-    z: bool, // Z bit [30] Zero condition code flag. Set to 1 if the result of the instruction is zero, and to 0 otherwise. A result of zero often indicates an equal result from a comparison. 
-    c: bool, // C, bit [29] Carry condition code flag. Set to 1 if the instruction results in a carry condition, for example an unsigned overflow on an addition.
-    v: bool, // V, bit [28] Overflow condition code flag. Set to 1 if the instruction results in an overflow condition, for example a signed overflow on an addition.
+// CONTROL<0>, thread mode is unprivileged
+const CONTROL_NPRIV: u32 = 1 << 0;
+// CONTROL<1>, thread mode uses the process stack
+const CONTROL_SPSEL: u32 = 1 << 1;
+
+// the Private Peripheral Bus holding the System Control Space, privileged access only
+const PPB_START: u32 = 0xe0000000;
+const PPB_END: u32 = 0xe00fffff;
+
+// instructions run between reads of the clock for the timeout
+const TIMEOUT_CHECK_INTERVAL: u64 = 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    // application code, privileged or not as CONTROL.nPRIV says
+    Thread,
+    // exception handlers, always privileged
+    Handler,
+}
+
+// the stack R13 refers to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SpSel {
+    Main,
+    Process,
+}
+
+// why execution stopped
+#[derive(Debug, PartialEq, Eq)]
+pub enum StopReason {
+    // BKPT with its immediate, the instruction is not executed
+    Breakpoint(u32),
+    // a breakpoint added at the address, the instruction is not executed
+    AddressBreakpoint(u32),
+    // the instruction before accessed the address, inside the range of a watchpoint
+    Watchpoint(WatchKind, u32),
+    // run_until got to the address
+    AddressReached(u32),
+    // step_over or step_out finished
+    StepComplete,
+    // the instruction at PC could not be decoded
+    DecodeError(Error),
+    // the instruction limit given to run was reached
+    InstructionLimit,
+    // max_cycles of RunOptions were executed
+    CycleLimit,
+    // the timeout of RunOptions elapsed
+    Timeout,
+    // the branch at the address branched to itself with nothing left to interrupt it
+    SelfBranch(u32),
+    // WFI at the address with nothing left to wake it up
+    WaitForInterrupt(u32),
+    // a debugger interrupted execution
+    Interrupted,
+    // the program exited through semihosting with its exit status
+    Exit(u32),
+    // a fault at the priority of HardFault or above locked the processor up
+    Lockup(HardFault),
+}
+
+impl std::fmt::Display for StopReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StopReason::Breakpoint(imm32) => write!(f, "breakpoint #{imm32}"),
+            StopReason::AddressBreakpoint(address) => write!(f, "breakpoint at {address:#x}"),
+            StopReason::Watchpoint(kind, address) => write!(f, "{kind} watchpoint at {address:#x}"),
+            StopReason::AddressReached(address) => write!(f, "reached {address:#x}"),
+            StopReason::StepComplete => write!(f, "step complete"),
+            StopReason::DecodeError(e) => write!(f, "{e}"),
+            StopReason::InstructionLimit => write!(f, "instruction limit reached"),
+            StopReason::CycleLimit => write!(f, "cycle limit reached"),
+            StopReason::Timeout => write!(f, "timed out"),
+            StopReason::SelfBranch(address) => write!(f, "branch to itself at {address:#x}"),
+            StopReason::WaitForInterrupt(address) => write!(f, "wfi at {address:#x} with no interrupt to wait for"),
+            StopReason::Interrupted => write!(f, "interrupted"),
+            StopReason::Exit(status) => write!(f, "exited with status {status}"),
+            StopReason::Lockup(cause) => write!(f, "lockup: {cause}"),
+        }
+    }
+}
+
+// What stops run_with besides breakpoints, faults and the program exiting. The default runs
+// until one of those.
+#[derive(Debug, Clone, Default)]
+pub struct RunOptions {
+    pub max_instructions: Option<u64>,
+    pub max_cycles: Option<u64>,
+    // Wall clock time, read every TIMEOUT_CHECK_INTERVAL instructions and after every
+    // semihosting call. A call blocked on the host, like a read of stdin, is not interrupted.
+    pub timeout: Option<Duration>,
+    // `b .`, like the `loop {}` firmware ends in, when no exception is pending or can become so
+    pub stop_on_self_branch: bool,
+    // WFI when no exception is pending or can become so, it would sleep forever
+    pub stop_on_wfi: bool,
 }
 
 #[derive(Debug)]
 pub struct Cpu {
     should_branch: bool,
+    // R13 is not used, SP is banked in sp_main and sp_process
     registers: [u32;16],
-    special_registers: [u32; 12],
-    flags: Flags,
+    sp_main: u32,
+    sp_process: u32,
+    xpsr: Xpsr,
+    primask: u32,
+    control: u32,
+    mode: Mode,
+    exception_active: [bool; EXCEPTION_COUNT],
+    // shared with the NVIC registers in memmory
+    nvic: Rc<RefCell<Nvic>>,
     memmory: Memmory,
+    // base of the vector table, and what it is set to on reset
+    vtor: u32,
+    reset_vtor: u32,
+    // the part CPUID reports
+    core: Core,
+    // SCR sleep bits
+    scr: u32,
+    // the host side of semihosting calls
+    semihosting: Semihosting,
+    breakpoints: BTreeSet<u32>,
+    watchpoints: Vec<Watchpoint>,
+    // the watchpoint the current instruction hit and the address accessed
+    watchpoint_hit: Option<(WatchKind, u32)>,
+    // executed since the cpu was built
+    cycles: u64,
+    // the current instruction was a WFI
+    wfi: bool,
+    // the current instruction was a semihosting call
+    host_call: bool,
 }
 
 pub fn build() -> Cpu {
-    let memmory = Memmory{data: vec![0 as u8;(u32::MAX as usize) + 1]};
-    Cpu { should_branch: false, registers: [0;16], special_registers: [0;12], memmory: memmory, flags: Flags { n: false, z: false, c: false, v: false }}
+    build_with_memmory(Memmory::default())
+}
+
+pub fn build_with_memmory(mut memmory: Memmory) -> Cpu {
+    let nvic = Rc::new(RefCell::new(Nvic::new()));
+    memmory.attach("NVIC", NVIC_START, NVIC_SIZE, Box::new(NvicRegisters(nvic.clone())));
+    memmory.attach("SysTick", SYSTICK_START, SYSTICK_SIZE, Box::new(SysTick::new(nvic.clone())));
+    Cpu { should_branch: false, registers: [0;16], sp_main: 0, sp_process: 0, memmory, xpsr: Xpsr(Xpsr::T), primask: 0, control: 0, mode: Mode::Thread, exception_active: [false; EXCEPTION_COUNT], nvic, vtor: 0, reset_vtor: 0, core: Core::CortexM0Plus, scr: 0, semihosting: Semihosting::new(), breakpoints: BTreeSet::new(), watchpoints: Vec::new(), watchpoint_hit: None, cycles: 0, wfi: false, host_call: false}
 }
 
 impl Cpu {
-    pub fn load_program(&mut self, program: &Program) {
-        println!("loading program");
+    pub fn load_program(&mut self, program: &Program) -> Result<(), Fault> {
+        if let Some(stack_start) = program.get_start_stack() {
+            self.write_register(Register::SP, stack_start);
+        }
+
+        for segment in program.get_segments() {
+            // the image at its load address, and where it runs from when that differs,
+            // like .data copied to RAM by the startup code
+            self.memmory.write_chunk(segment.paddr, segment.data)?;
+            if segment.vaddr != segment.paddr {
+                self.memmory.write_chunk(segment.vaddr, segment.data)?;
+            }
 
-        let start_addr = program.get_start_addr();
-        println!("start address: {start_addr:x}");
+            // .bss
+            let zeros = vec![0; (segment.mem_size as usize).saturating_sub(segment.data.len())];
+            self.memmory.write_chunk(segment.vaddr.wrapping_add(segment.data.len() as u32), &zeros)?;
+        }
+        Ok(())
+    }
+
+    // the vector table from now on and after every reset, where the part boots from
+    pub fn set_vtor(&mut self, vtor: u32) {
+        self.vtor = vtor;
+        self.reset_vtor = vtor;
+    }
+
+    pub fn get_vtor(&self) -> u32 {
+        self.vtor
+    }
+
+    pub fn reset(&mut self) -> Result<(), Fault> {
+        /*
+        // B1.5.5 TakeReset()
+        CurrentMode = Mode_Thread;
+        PRIMASK<0> = '0'; /* priority mask cleared at reset */
+        CONTROL<1> = '0'; /* current stack is Main */
+        CONTROL<0> = '0'; /* Thread is privileged */
+        for i = 0 to 12
+            R[i] = bits(32) UNKNOWN;
+        bits(32) vectortable = VTOR;
+        SP_main = MemA[vectortable,4] AND 0xFFFFFFFC<31:0>;
+        SP_process = ((bits(30) UNKNOWN):'00');
+        LR = 0xFFFFFFFF<31:0>; /* preset to an illegal exception return value */
+        tmp = MemA[vectortable+4,4];
+        tbit = tmp<0>;
+        APSR = bits(32) UNKNOWN; /* flags UNPREDICTABLE from reset */
+        IPSR<5:0> = 0x0; /* Exception Number cleared */
+        EPSR.T = tbit; /* T bit set from vector */
+        EPSR.IT<7:0> = 0x0; /* IT/ICI bits cleared */
+        BranchTo(tmp AND 0xFFFFFFFE<31:0>); /* address of reset service routine */
+        */
+        self.mode = Mode::Thread;
+        self.exception_active = [false; EXCEPTION_COUNT];
+        *self.nvic.borrow_mut() = Nvic::new();
+        self.memmory.reset();
+        self.primask = 0;
+        self.control = 0;
+        self.registers = [0; 16];
+        self.vtor = self.reset_vtor;
+        self.scr = 0;
 
-        let stack_start = program.get_start_stack();
-        self.write_register(Register::MSP, stack_start);
+        self.sp_main = self.memmory.read_u32(self.vtor)? & 0xfffffffc;
+        self.write_register(Register::LR, 0xffffffff);
 
-        self.memmory.write_chunk(start_addr, program.get_text());
+        let tmp = self.memmory.read_u32(self.vtor.wrapping_add(4))?;
+        self.xpsr.set_ipsr(0);
+        self.xpsr.set(Xpsr::T, tmp & 0b1 == 1);
+        self.write_register(Register::PC, tmp);
+        Ok(())
     }
 
-    pub fn start(&mut self, start_addr: u32) {
+    pub fn start(&mut self, start_addr: u32) -> StopReason {
         self.write_register(Register::PC, start_addr);
 
-        while self.tick() {}
+        self.run(None)
+    }
+
+    // Steps until something stops execution or max_instructions have been executed.
+    pub fn run(&mut self, max_instructions: Option<u64>) -> StopReason {
+        self.run_with(&RunOptions { max_instructions, ..RunOptions::default() })
+    }
+
+    // Steps until something stops execution or a condition of options is met. A breakpoint at
+    // PC does not stop the first instruction, so a run resumes from it.
+    pub fn run_with(&mut self, options: &RunOptions) -> StopReason {
+        self.run_tracing(options, |_| {}, |_| None)
     }
 
-    pub fn tick(&mut self) -> bool {
+    // run_with calling before ahead of every instruction, also stopping when stop returns a
+    // reason after one
+    pub fn run_tracing(
+        &mut self,
+        options: &RunOptions,
+        mut before: impl FnMut(&Cpu),
+        mut stop: impl FnMut(&Cpu) -> Option<StopReason>,
+    ) -> StopReason {
+        let start = Instant::now();
+        let start_cycles = self.cycles;
+        let mut executed: u64 = 0;
+        loop {
+            if options.max_instructions.is_some_and(|x| executed >= x) {
+                return StopReason::InstructionLimit;
+            }
+            if options.max_cycles.is_some_and(|x| self.cycles - start_cycles >= x) {
+                return StopReason::CycleLimit;
+            }
+            let check_time = executed.is_multiple_of(TIMEOUT_CHECK_INTERVAL) || self.host_call;
+            if check_time && options.timeout.is_some_and(|x| start.elapsed() >= x) {
+                return StopReason::Timeout;
+            }
+            let pc = self.registers[15];
+            if executed > 0 && self.has_breakpoint(pc) {
+                return StopReason::AddressBreakpoint(pc);
+            }
+            before(self);
+            if let Err(reason) = self.step() {
+                return reason;
+            }
+            executed += 1;
+            if let Some(reason) = stop(self) {
+                return reason;
+            }
+            // only a branch leaves PC where it was
+            if options.stop_on_self_branch && self.registers[15] == pc && self.is_idle() {
+                return StopReason::SelfBranch(pc);
+            }
+            if options.stop_on_wfi && self.wfi && self.is_idle() {
+                return StopReason::WaitForInterrupt(pc);
+            }
+        }
+    }
+
+    // cycles executed since the cpu was built, one per instruction
+    pub fn get_cycles(&self) -> u64 {
+        self.cycles
+    }
+
+    // one cycle per instruction until instruction timings are modelled
+    fn tick(&mut self) {
+        self.memmory.tick(1);
+        self.cycles += 1;
+    }
+
+    // Nothing is pending and no peripheral can pend anything by itself, so the program can only
+    // be woken up from the outside.
+    fn is_idle(&self) -> bool {
+        self.nvic.borrow().highest_pending().is_none() && !self.memmory.can_interrupt()
+    }
+
+    // executes the instruction at PC, or the first of the handler of a pending exception
+    pub fn step(&mut self) -> Result<(), StopReason> {
+        if let Some(exception) = self.pending_exception() {
+            // taken between instructions, returning to the one that would have executed next
+            let return_address = self.registers[15];
+            if let Err(cause) = self.exception_entry(exception, return_address) {
+                self.take_hard_fault(cause, return_address).map_err(StopReason::Lockup)?;
+            }
+            // a breakpoint on the first instruction of the handler stops before it, the next
+            // step executes it
+            let handler = self.registers[15];
+            if self.has_breakpoint(handler) {
+                return Err(StopReason::AddressBreakpoint(handler));
+            }
+        }
+
         // reset should branch
         self.should_branch = false;
+        self.watchpoint_hit = None;
+        self.wfi = false;
+        self.host_call = false;
 
         let pc = self.registers[15];
-        let data = self.memmory.read_4B(pc);
-        let (instruction, rest) = match disassemble(&data) {
+        // an INVSTATE UsageFault, escalated to HardFault like every fault in ARMv6-M
+        if !self.xpsr.get(Xpsr::T) {
+            return self.take_hard_fault(HardFault::InvalidState, pc).map_err(StopReason::Lockup);
+        }
+        let data = match self.fetch(pc) {
             Ok(x) => x,
-            Err(_) => panic!(),
+            Err(fault) => return self.take_hard_fault(HardFault::Bus(fault), pc).map_err(StopReason::Lockup),
+        };
+        let decoded = match Instructions::new(&data, pc).next() {
+            Some(Ok(x)) => x,
+            Some(Err(e)) => return Err(StopReason::DecodeError(e)),
+            None => unreachable!("an instruction was fetched"),
         };
+        let instruction = decoded.instruction;
 
-        if matches!(instruction, Thumb::Thumb16(Thumb16::Bkpt(_))) {
-            println!("Reached Break point");
-            return false
+        if let Thumb::Thumb16(Thumb16::Bkpt(imm32)) = instruction {
+            if imm32 != SEMIHOSTING_BKPT || !self.semihosting_enabled() {
+                return Err(StopReason::Breakpoint(imm32))
+            }
+            // the host performs the call and execution resumes after the bkpt, which takes its
+            // cycle like any instruction
+            let result = self.semihosting_call();
+            self.tick();
+            self.host_call = true;
+            result?;
+            self.registers[15] = self.registers[15].wrapping_add(decoded.size);
+            return Ok(())
         }
 
-        println!("executing: {instruction:?}");
-        self.execute(&instruction);
-
-        self.print_registers_and_flags();
+        let result = self.execute(&instruction);
+        self.tick();
+        if let Err(cause) = result {
+            // the faulting instruction returns to itself
+            return self.take_hard_fault(cause, pc).map_err(StopReason::Lockup);
+        }
 
         if !self.should_branch {
             // increment PC
-            match instruction {
-                Thumb::Thumb16(_) => {
-                    self.registers[15] += 2;
-                },
-                Thumb::Thumb32(_) => {
-                    self.registers[15] += 4;
-                }
-            }
+            self.registers[15] = self.registers[15].wrapping_add(decoded.size);
+        }
+        if let Some((kind, address)) = self.watchpoint_hit {
+            return Err(StopReason::Watchpoint(kind, address));
         }
-        true
+        Ok(())
     }
 
-    pub fn print_instruction(&self, addr: u32) {
-        let instruction = self.memmory.read_4B(addr);
+    pub fn get_register(&self, register: Register) -> u32 {
+        match register {
+            Register::SP => self.read_register(Register::SP),
+            _ => self.registers[u8::from(register) as usize],
+        }
+    }
 
-        let interpretation = match disassemble(&instruction) {
-            Ok((x, y)) => x,
-            Err(_) => return,
+    pub fn set_register(&mut self, register: Register, value: u32) {
+        self.write_register(register, value);
+    }
+
+    // N, Z, C and V in bits [31:28]
+    pub fn get_apsr(&self) -> u32 {
+        self.xpsr.0 & Xpsr::APSR
+    }
+
+    // APSR, IPSR and EPSR combined
+    pub fn get_xpsr(&self) -> u32 {
+        self.xpsr.0
+    }
+
+    // Pends external interrupt irq, taken once enabled in the NVIC and its priority allows. There
+    // are 32 of them, others are ignored like the hardware ignores unimplemented interrupts.
+    pub fn raise_irq(&mut self, irq: u32) {
+        if irq < (EXCEPTION_COUNT - 16) as u32 {
+            self.nvic.borrow_mut().set_pending(Exception::Irq(irq));
+        }
+    }
+
+    pub fn get_mode(&self) -> Mode {
+        self.mode
+    }
+
+    // the whole register whatever the privilege, unlike MRS
+    pub fn get_special_register(&self, sr: SpecialRegister) -> u32 {
+        match sr {
+            SpecialRegister::APSR => self.xpsr.0 & Xpsr::APSR,
+            SpecialRegister::IPSR => self.xpsr.ipsr(),
+            SpecialRegister::EPSR => self.xpsr.0 & Xpsr::T,
+            SpecialRegister::IAPSR => self.xpsr.0 & (Xpsr::APSR | Xpsr::IPSR),
+            SpecialRegister::EAPSR => self.xpsr.0 & (Xpsr::APSR | Xpsr::T),
+            SpecialRegister::IEPSR => self.xpsr.0 & (Xpsr::IPSR | Xpsr::T),
+            SpecialRegister::XPSR => self.xpsr.0,
+            SpecialRegister::MSP => self.sp_main,
+            SpecialRegister::PSP => self.sp_process,
+            SpecialRegister::PRIMASK => self.primask,
+            SpecialRegister::CONTROL => self.control,
+        }
+    }
+
+    // the whole register like a debugger writes it, unlike MSR
+    pub fn set_special_register(&mut self, sr: SpecialRegister, value: u32) {
+        let psr_mask = match sr {
+            SpecialRegister::APSR => Xpsr::APSR,
+            SpecialRegister::IPSR => Xpsr::IPSR,
+            SpecialRegister::EPSR => Xpsr::T,
+            SpecialRegister::IAPSR => Xpsr::APSR | Xpsr::IPSR,
+            SpecialRegister::EAPSR => Xpsr::APSR | Xpsr::T,
+            SpecialRegister::IEPSR => Xpsr::IPSR | Xpsr::T,
+            SpecialRegister::XPSR => Xpsr::APSR | Xpsr::IPSR | Xpsr::T,
+            SpecialRegister::MSP => return self.sp_main = value & !0b11,
+            SpecialRegister::PSP => return self.sp_process = value & !0b11,
+            SpecialRegister::PRIMASK => return self.primask = value & 0b1,
+            SpecialRegister::CONTROL => return self.control = value & (CONTROL_NPRIV | CONTROL_SPSEL),
         };
+        self.xpsr.0 = (self.xpsr.0 & !psr_mask) | (value & psr_mask);
+    }
 
-        println!("{:?}", interpretation);
+    pub fn read_u8(&mut self, addr: u32) -> Result<u8, Fault> {
+        self.bus_read(addr, Size::Byte).map(|x| x as u8)
     }
 
-    fn read_special_register(&self, register: SpecialRegister) -> u32 {
-        let idx: u8 = register.into();
-        match idx {
-            0..=9 => {
-                self.special_registers[idx as usize]
-            }
-            16 => { // PRIMASK register
-                self.special_registers[10]
-            }
-            20 => { // CONTROL register
-                self.special_registers[11]
-            }
-            _ => {
-                println!("no a special register - should never get here");
-                panic!()
+    pub fn read_u32(&mut self, addr: u32) -> Result<u32, Fault> {
+        self.bus_read(addr, Size::Word)
+    }
+
+    // writes like a debugger loading an image, the region permissions do not apply
+    pub fn write_chunk(&mut self, addr: u32, data: &[u8]) -> Result<(), Fault> {
+        for (i, x) in data.iter().enumerate() {
+            let address = addr.wrapping_add(i as u32);
+            if (SCB_START..SCB_START + SCB_SIZE).contains(&address) {
+                self.write_scb(address - SCB_START, Size::Byte, *x as u32)?;
+            } else {
+                self.memmory.write_chunk(address, &[*x])?;
             }
         }
+        Ok(())
     }
 
-    fn write_special_register(&mut self, register: SpecialRegister, value: u32) {
-        let idx: u8 = register.into();
-        match idx {
-            0..=9 => {
-                self.special_registers[idx as usize] = value;
-            }
-            16 => { // PRIMASK register
-                self.special_registers[10] = value;
-            }
-            20 => { // CONTROL register
-                self.special_registers[11] = value;
-            }
-            _ => {
-                println!("no a special register - should never get here");
-                panic!()
-            }
+    pub fn attach_peripheral(&mut self, name: &str, start: u32, size: u32, peripheral: Box<dyn Peripheral>) {
+        self.memmory.attach(name, start, size, peripheral);
+    }
+
+    // the 2 or 4 bytes of the instruction at addr
+    pub fn fetch(&self, addr: u32) -> Result<Vec<u8>, Fault> {
+        let first = self.memmory.fetch(addr, 2)?;
+        match first[1] >> 3 {
+            0b11101..=0b11111 => self.memmory.fetch(addr, 4),
+            _ => Ok(first),
         }
     }
-    
+
+    pub fn print_instruction(&self, addr: u32) {
+        let instruction = match self.fetch(addr) {
+            Ok(x) => x,
+            Err(_) => return,
+        };
+
+        let interpretation = match disassemble(&instruction, addr) {
+            Ok((x, _)) => x,
+            Err(_) => return,
+        };
+
+        println!("{}", interpretation.display(addr));
+    }
+
     fn read_register(&self, register: Register) -> u32 {
         let i: u8 = register.into();
 
         // special case for PC
         if i == 15 {
-            self.registers[i as usize] + 4
+            self.registers[i as usize].wrapping_add(4)
+        } else if i == 13 {
+            match self.look_up_sp() {
+                SpSel::Main => self.sp_main,
+                SpSel::Process => self.sp_process,
+            }
         } else {
             self.registers[i as usize]
         }
@@ -151,26 +579,52 @@ impl Cpu {
     fn write_register(&mut self, register: Register, value: u32) {
         let i: u8 = register.into();
 
-        // special case for PC
-        if i == 15 {
+        if i == 13 {
+            // _R[LookUpSP()] = value<31:2>:'00';
+            match self.look_up_sp() {
+                SpSel::Main => self.sp_main = value & !0b11,
+                SpSel::Process => self.sp_process = value & !0b11,
+            }
+        } else if i == 15 {
+            // special case for PC
             self.should_branch = true; // if PC is written to always branch
             let to_write = value & 0xfffffffe; // set lowest bit to 0 forcing alignment
             self.registers[i as usize] = to_write;
         } else {
             self.registers[i as usize] = value;
         }
-    } 
+    }
+
+    fn look_up_sp(&self) -> SpSel {
+        /*
+        RName LookUpSP()
+            if CONTROL.SPSEL == ‘1’ then
+                if CurrentMode == Mode_Thread then
+                    return RName_SPprocess;
+                else
+                    UNPREDICTABLE;
+            else
+                return RName_SPmain;
+        */
+        // handler mode always runs on the main stack, exception entry clears SPSEL
+        if self.control & CONTROL_SPSEL != 0 && self.mode == Mode::Thread {
+            SpSel::Process
+        } else {
+            SpSel::Main
+        }
+    }
 
     pub fn print_registers_and_flags(&self) {
-        println!("Flags N: {:?} Z: {:?} C: {:?} V:{:?}", self.flags.n, self.flags.z, self.flags.c, self.flags.v);
-        for (i, n) in self.registers.iter().enumerate() {
+        println!("Flags N: {:?} Z: {:?} C: {:?} V:{:?}", self.xpsr.n(), self.xpsr.z(), self.xpsr.c(), self.xpsr.v());
+        for i in 0..16 {
             let reg: Register = (i as u8).try_into().unwrap();
-            let s = *n as i32;
+            let n = self.get_register(reg);
+            let s = n as i32;
             println!("{reg:?}: {n:#x} signed: {s} unsigned: {n}")
         }
     }
 
-    fn execute(&mut self, instruction: &Thumb) { // returns if pc should be incremented or not
+    fn execute(&mut self, instruction: &Thumb) -> Result<(), HardFault> {
         match instruction {
             Thumb::Thumb16(inst16) => {
                 match inst16 {
@@ -178,12 +632,11 @@ impl Cpu {
                         self.add_with_carry_register_imm(*rd, *rn, *imm32, false, true);
                     },
                     Thumb16::BxT1(rm) => {
-                        self.do_bx(*rm);
+                        self.do_bx(*rm)?;
                     },
                     Thumb16::CmpImmT1(rn, imm32) => {
                         let rn_data = self.read_register(*rn);
-                        let result = self.add_with_carry_update_flags(rn_data, !*imm32, true) as i32;
-                        println!("result: {result}");
+                        self.add_with_carry_update_flags(rn_data, !*imm32, true);
                     },
                     Thumb16::BImmT1(cond, imm32) => {
                         self.do_bt1(*cond, *imm32);
@@ -201,31 +654,177 @@ impl Cpu {
                         let result = self.read_register(*rm);
                         self.write_register(*rd, result);
                     },
-                    Thumb16::LdrImmT1(rt, imm32) => {
-                        self.do_ldr_imm(*rt, *imm32);
+                    Thumb16::LdrLiteralT1(rt, imm32) => {
+                        self.do_ldr_literal(*rt, *imm32)?;
                     },
                     Thumb16::Stm(rn, reg_list) => {
-                        self.do_stm(*rn, *reg_list);
+                        self.do_stm(*rn, *reg_list)?;
                     },
                     Thumb16::BT2(imm32) => {
                         let pc = self.read_register(Register::PC);
                         self.write_register(Register::PC, pc.wrapping_add(*imm32));
                     },
-                    Thumb16::Ldm(_, _) => todo!(),
+                    Thumb16::Ldm(rn, reg_list) => {
+                        self.do_ldm(*rn, *reg_list)?;
+                    },
                     Thumb16::Push(reg_list) => {
-                        self.do_push(*reg_list);
+                        self.do_push(*reg_list)?;
+                    },
+                    Thumb16::UdfT1(imm32) => {
+                        return Err(HardFault::Undefined(*imm32));
                     },
-                    Thumb16::UdfT1(_) => todo!(),
                     Thumb16::AddSpImmT1(rd, imm32) => {
-                        self.add_with_carry_register_imm(*rd, Register::MSP, *imm32, false, false);
+                        self.add_with_carry_register_imm(*rd, Register::SP, *imm32, false, false);
                     },
                     Thumb16::SubSpSpImmT1(imm32) => {
-                        self.add_with_carry_register_imm(Register::MSP, Register::MSP, !(*imm32), true, false);
+                        self.add_with_carry_register_imm(Register::SP, Register::SP, !(*imm32), true, false);
+                    },
+                    Thumb16::StrImmT2(rt, imm32) => {
+                        self.write_register_to_memmory_offset(*rt, Register::SP, *imm32, Size::Word)?;
+                    },
+                    Thumb16::Bkpt(_) => unreachable!("bkpt is handled by step"),
+                    Thumb16::LslImmT1(imm32, rm, rd) => {
+                        self.do_shift_imm(*rd, *rm, SRType::Lsl, *imm32);
+                    },
+                    Thumb16::LsrImmT1(imm32, rm, rd) => {
+                        self.do_shift_imm(*rd, *rm, SRType::Lsr, *imm32);
+                    },
+                    Thumb16::AsrImmT1(imm32, rm, rd) => {
+                        self.do_shift_imm(*rd, *rm, SRType::Asr, *imm32);
+                    },
+                    Thumb16::MovsRegT2(rm, rd) => {
+                        let result = self.read_register(*rm);
+                        self.write_register(*rd, result);
+                        self.set_flag_nz(result);
+                    },
+                    Thumb16::SubsRegT1(rm, rn, rd) => {
+                        let rm_data = self.read_register(*rm);
+                        let rn_data = self.read_register(*rn);
+                        let result = self.add_with_carry_update_flags(rn_data, !rm_data, true);
+                        self.write_register(*rd, result);
+                    },
+                    Thumb16::SubsImmT1(imm32, rn, rd) => {
+                        self.add_with_carry_register_imm(*rd, *rn, !*imm32, true, true);
+                    },
+                    Thumb16::AddsImmT2(rdn, imm32) => {
+                        self.add_with_carry_register_imm(*rdn, *rdn, *imm32, false, true);
                     },
-                    Thumb16::STRImmT2(rt, imm32) => {
-                        self.write_register_to_memmory_offset(*rt, Register::MSP, *imm32, true);
+                    Thumb16::SubsImmT2(rdn, imm32) => {
+                        self.add_with_carry_register_imm(*rdn, *rdn, !*imm32, true, true);
+                    },
+                    Thumb16::AddRegT2(rm, rdn) => {
+                        self.add_with_carry_register_register(*rdn, *rm, *rdn, false, false);
+                    },
+                    Thumb16::CmpRegT2(rm, rn) => {
+                        self.do_cmp_r(*rm, *rn);
+                    },
+                    Thumb16::BlxRegT1(rm) => {
+                        self.do_blx(*rm);
+                    },
+                    Thumb16::StrRegT1(rm, rn, rt) => {
+                        let offset = self.read_register(*rm);
+                        self.write_register_to_memmory_offset(*rt, *rn, offset, Size::Word)?;
+                    },
+                    Thumb16::StrhRegT1(rm, rn, rt) => {
+                        let offset = self.read_register(*rm);
+                        self.write_register_to_memmory_offset(*rt, *rn, offset, Size::Halfword)?;
+                    },
+                    Thumb16::StrbRegT1(rm, rn, rt) => {
+                        let offset = self.read_register(*rm);
+                        self.write_register_to_memmory_offset(*rt, *rn, offset, Size::Byte)?;
+                    },
+                    Thumb16::LdrsbRegT1(rm, rn, rt) => {
+                        let offset = self.read_register(*rm);
+                        self.read_memmory_to_register_offset(*rt, *rn, offset, Size::Byte, true)?;
+                    },
+                    Thumb16::LdrRegT1(rm, rn, rt) => {
+                        let offset = self.read_register(*rm);
+                        self.read_memmory_to_register_offset(*rt, *rn, offset, Size::Word, false)?;
+                    },
+                    Thumb16::LdrhRegT1(rm, rn, rt) => {
+                        let offset = self.read_register(*rm);
+                        self.read_memmory_to_register_offset(*rt, *rn, offset, Size::Halfword, false)?;
+                    },
+                    Thumb16::LdrbRegT1(rm, rn, rt) => {
+                        let offset = self.read_register(*rm);
+                        self.read_memmory_to_register_offset(*rt, *rn, offset, Size::Byte, false)?;
+                    },
+                    Thumb16::LdrshRegT1(rm, rn, rt) => {
+                        let offset = self.read_register(*rm);
+                        self.read_memmory_to_register_offset(*rt, *rn, offset, Size::Halfword, true)?;
+                    },
+                    Thumb16::StrImmT1(imm32, rn, rt) => {
+                        self.write_register_to_memmory_offset(*rt, *rn, *imm32, Size::Word)?;
+                    },
+                    Thumb16::LdrImmT1(imm32, rn, rt) => {
+                        self.read_memmory_to_register_offset(*rt, *rn, *imm32, Size::Word, false)?;
+                    },
+                    Thumb16::StrbImmT1(imm32, rn, rt) => {
+                        self.write_register_to_memmory_offset(*rt, *rn, *imm32, Size::Byte)?;
+                    },
+                    Thumb16::LdrbImmT1(imm32, rn, rt) => {
+                        self.read_memmory_to_register_offset(*rt, *rn, *imm32, Size::Byte, false)?;
+                    },
+                    Thumb16::StrhImmT1(imm32, rn, rt) => {
+                        self.write_register_to_memmory_offset(*rt, *rn, *imm32, Size::Halfword)?;
+                    },
+                    Thumb16::LdrhImmT1(imm32, rn, rt) => {
+                        self.read_memmory_to_register_offset(*rt, *rn, *imm32, Size::Halfword, false)?;
+                    },
+                    Thumb16::LdrImmT2(rt, imm32) => {
+                        self.read_memmory_to_register_offset(*rt, Register::SP, *imm32, Size::Word, false)?;
+                    },
+                    Thumb16::AdrT1(rd, imm32) => {
+                        let pc = self.read_register(Register::PC);
+                        self.write_register(*rd, align(pc, 4).wrapping_add(*imm32));
+                    },
+                    Thumb16::AddSpImmT2(imm32) => {
+                        self.add_with_carry_register_imm(Register::SP, Register::SP, *imm32, false, false);
+                    },
+                    Thumb16::SxthT1(rm, rd) => {
+                        let result = self.read_register(*rm) as u16 as i16 as i32 as u32;
+                        self.write_register(*rd, result);
+                    },
+                    Thumb16::SxtbT1(rm, rd) => {
+                        let result = self.read_register(*rm) as u8 as i8 as i32 as u32;
+                        self.write_register(*rd, result);
+                    },
+                    Thumb16::UxthT1(rm, rd) => {
+                        let result = self.read_register(*rm) & 0xffff;
+                        self.write_register(*rd, result);
+                    },
+                    Thumb16::UxtbT1(rm, rd) => {
+                        let result = self.read_register(*rm) & 0xff;
+                        self.write_register(*rd, result);
+                    },
+                    Thumb16::Cps(im) => {
+                        self.do_cps(*im);
+                    },
+                    Thumb16::RevT1(rm, rd) => {
+                        let result = self.read_register(*rm).swap_bytes();
+                        self.write_register(*rd, result);
+                    },
+                    Thumb16::Rev16T1(rm, rd) => {
+                        let rm_data = self.read_register(*rm);
+                        let result = ((rm_data & 0x00ff00ff) << 8) | ((rm_data & 0xff00ff00) >> 8);
+                        self.write_register(*rd, result);
+                    },
+                    Thumb16::RevshT1(rm, rd) => {
+                        let result = (self.read_register(*rm) as u16).swap_bytes() as i16 as i32 as u32;
+                        self.write_register(*rd, result);
+                    },
+                    Thumb16::Pop(reg_list) => {
+                        self.do_pop(*reg_list)?;
+                    },
+                    // hints have no architectural effect on a single core without a debugger
+                    Thumb16::Nop | Thumb16::Yield | Thumb16::Wfe | Thumb16::Sev => {},
+                    // sleeping is not modelled, the next instruction runs and any pending
+                    // exception is taken before it like on wake up
+                    Thumb16::Wfi => self.wfi = true,
+                    Thumb16::Svc(_) => {
+                        let next_instr_addr = self.read_register(Register::PC).wrapping_sub(2);
+                        self.call_supervisor(next_instr_addr)?;
                     },
-                    Thumb16::Bkpt(_) => todo!(),
                 }
             },
             Thumb::Thumb32(inst32) => {
@@ -234,42 +833,153 @@ impl Cpu {
                         self.do_bl(*imm32);
                     },
                     Thumb32::MsrT1(rn, sr) => {
-                        todo!()
+                        self.do_msr(*rn, *sr);
                     },
                     Thumb32::MrsT1(rd, sr) => {
                         self.do_mrs(*rd, *sr);
-                    }
+                    },
+                    // memory accesses complete in program order, barriers have nothing to wait for
+                    Thumb32::DsbT1(_) | Thumb32::DmbT1(_) | Thumb32::IsbT1(_) => {},
+                    Thumb32::UdfT2(imm32) => {
+                        return Err(HardFault::Undefined(*imm32));
+                    },
                 }
             }
         }
+        Ok(())
     }
 
     fn do_data_proc(&mut self, dp_op_code: &DpOpcode, r1: &Register, r2: &Register) {
+        // r1 -> rm (rn for RSB and MUL) r2 -> rdn
+        let (rm, rdn) = (*r1, *r2);
         match dp_op_code {
-            DpOpcode::AND => todo!(),
-            DpOpcode::OR => todo!(),
-            DpOpcode::LSL => todo!(),
-            DpOpcode::LSR => todo!(),
-            DpOpcode::ASR => todo!(),
-            DpOpcode::ADC => todo!(),
-            DpOpcode::SBC => todo!(),
-            DpOpcode::ROR => todo!(),
-            DpOpcode::TST => todo!(),
-            DpOpcode::RSB => todo!(),
+            DpOpcode::AND => {
+                let result = self.read_register(rdn) & self.read_register(rm);
+                self.write_register(rdn, result);
+                self.set_flag_nz(result);
+            },
+            DpOpcode::EOR => {
+                let result = self.read_register(rdn) ^ self.read_register(rm);
+                self.write_register(rdn, result);
+                self.set_flag_nz(result);
+            },
+            DpOpcode::LSL => {
+                self.do_shift_register(rdn, rm, SRType::Lsl);
+            },
+            DpOpcode::LSR => {
+                self.do_shift_register(rdn, rm, SRType::Lsr);
+            },
+            DpOpcode::ASR => {
+                self.do_shift_register(rdn, rm, SRType::Asr);
+            },
+            DpOpcode::ADC => {
+                let c = self.xpsr.c();
+                self.add_with_carry_register_register(rdn, rm, rdn, c, true);
+            },
+            DpOpcode::SBC => {
+                let rn_data = self.read_register(rdn);
+                let rm_data = self.read_register(rm);
+                let result = self.add_with_carry_update_flags(rn_data, !rm_data, self.xpsr.c());
+                self.write_register(rdn, result);
+            },
+            DpOpcode::ROR => {
+                self.do_shift_register(rdn, rm, SRType::Ror);
+            },
+            DpOpcode::TST => {
+                let result = self.read_register(rdn) & self.read_register(rm);
+                self.set_flag_nz(result);
+            },
+            DpOpcode::RSB => {
+                // RSBS <Rd>, <Rn>, #0
+                let rn_data = self.read_register(rm);
+                let result = self.add_with_carry_update_flags(!rn_data, 0, true);
+                self.write_register(rdn, result);
+            },
             DpOpcode::CMP => {
-                // r1 -> rm r2 -> rn
-                self.do_cmp_r(*r1, *r2);
+                self.do_cmp_r(rm, rdn);
+            },
+            DpOpcode::CMN => {
+                let rn_data = self.read_register(rdn);
+                let rm_data = self.read_register(rm);
+                self.add_with_carry_update_flags(rn_data, rm_data, false);
+            },
+            DpOpcode::ORR => {
+                let result = self.read_register(rdn) | self.read_register(rm);
+                self.write_register(rdn, result);
+                self.set_flag_nz(result);
+            },
+            DpOpcode::MUL => {
+                self.do_mul(rdn, rm);
+            },
+            DpOpcode::BIC => {
+                let result = self.read_register(rdn) & !self.read_register(rm);
+                self.write_register(rdn, result);
+                self.set_flag_nz(result);
             },
-            DpOpcode::CMN => todo!(),
-            DpOpcode::ORR => todo!(),
-            DpOpcode::MUL => todo!(),
-            DpOpcode::BIC => todo!(),
             DpOpcode::MVN => {
-                self.do_mvn_register(*r1, *r2);
+                self.do_mvn_register(rdn, rm);
             },
         }
     }
 
+    fn do_shift_register(&mut self, rdn: Register, rm: Register, shift_t: SRType) {
+        /*
+        if ConditionPassed() then
+            EncodingSpecificOperations();
+            shift_n = UInt(R[m]<7:0>);
+            (result, carry) = Shift_C(R[n], shift_t, shift_n, APSR.C);
+            R[d] = result;
+            if setflags then // always true
+                APSR.N = result<31>;
+                APSR.Z = IsZeroBit(result);
+                APSR.C = carry;
+                // APSR.V unchanged
+        */
+        let shift_n = self.read_register(rm) & 0xff;
+        let value = self.read_register(rdn);
+        let (result, carry) = shift_c(value, shift_t, shift_n, self.xpsr.c());
+        self.write_register(rdn, result);
+        self.set_flag_nz(result);
+        self.xpsr.set(Xpsr::C, carry);
+    }
+
+    fn do_shift_imm(&mut self, rd: Register, rm: Register, shift_t: SRType, shift_n: u32) {
+        /*
+        if ConditionPassed() then
+            EncodingSpecificOperations();
+            (result, carry) = Shift_C(R[m], shift_t, shift_n, APSR.C);
+            R[d] = result;
+            if setflags then // always true
+                APSR.N = result<31>;
+                APSR.Z = IsZeroBit(result);
+                APSR.C = carry;
+                // APSR.V unchanged
+        */
+        let value = self.read_register(rm);
+        let (result, carry) = shift_c(value, shift_t, shift_n, self.xpsr.c());
+        self.write_register(rd, result);
+        self.set_flag_nz(result);
+        self.xpsr.set(Xpsr::C, carry);
+    }
+
+    fn do_mul(&mut self, rdm: Register, rn: Register) {
+        /*
+        if ConditionPassed() then
+            EncodingSpecificOperations();
+            operand1 = SInt(R[n]); // or UInt(R[n]) without functionality change
+            operand2 = SInt(R[m]); // or UInt(R[m]) without functionality change
+            result = operand1 * operand2;
+            R[d] = result<31:0>;
+            if setflags then // always true
+                APSR.N = result<31>;
+                APSR.Z = IsZeroBit(result<31:0>);
+                // APSR.C, APSR.V unchanged
+        */
+        let result = self.read_register(rn).wrapping_mul(self.read_register(rdm));
+        self.write_register(rdm, result);
+        self.set_flag_nz(result);
+    }
+
     fn do_mrs(&mut self, rd: Register, sr: SpecialRegister) {
         /*
         R[d] = Zeros(32);
@@ -298,54 +1008,172 @@ impl Cpu {
 
         let mut rd_to_write: u32 = 0;
         let special_idx: u8 = sr.into();
-        match (special_idx >> 3) {
+        match special_idx >> 3 {
             0b00000 => {
                 if (special_idx & 0b1) == 1 {
-                    rd_to_write = (rd_to_write & 0xff00) | (self.special_registers[5] & 0xff);
+                    rd_to_write |= self.xpsr.ipsr();
                 }
                 if (special_idx & 0b10) == 0b10 {
-                    rd_to_write = rd_to_write & !(1 << 24);
+                    rd_to_write &= !(1 << 24);
                 }
                 if (special_idx &0b100) == 0 {
-                    rd_to_write = (rd_to_write & !(0b11111 << 27)) | (self.special_registers[0] & (0b11111 << 27));
+                    rd_to_write |= self.xpsr.0 & Xpsr::APSR;
                 }
             }
             0b00001 => {
-                if self.CurrentModeIsPrivileged() {
-                    match (special_idx & 0b111) {
+                if self.current_mode_is_privileged() {
+                    match special_idx & 0b111 {
                         0b000 => {
-                            rd_to_write = self.special_registers[8];
+                            rd_to_write = self.sp_main;
                         }
                         0b001 => {
-                            rd_to_write = self.special_registers[9];
+                            rd_to_write = self.sp_process;
                         }
-                        _ => panic!()
+                        _ => unreachable!("SYSm is validated by the decoder"),
                     }
                 }
             }
             0b00010 => {
-                match (special_idx & 0b111) {
+                match special_idx & 0b111 {
                     0b000 => {
-                        rd_to_write = if self.CurrentModeIsPrivileged() {
-                            self.special_registers[10] & 0b1
+                        rd_to_write = if self.current_mode_is_privileged() {
+                            self.primask & 0b1
                         } else {
                             0b0
                         };
                     }
                     0b100 => {
-                        rd_to_write = self.special_registers[11] & 0b11;
+                        rd_to_write = self.control & 0b11;
                     }
-                    _ => panic!()
+                    _ => unreachable!("SYSm is validated by the decoder"),
                 }
             }
-            _ => panic!()
+            _ => unreachable!("SYSm is validated by the decoder"),
         }
         self.write_register(rd, rd_to_write);
     }
 
-    fn CurrentModeIsPrivileged(&self) -> bool {
-        // always privileged for now
-        true
+    fn do_msr(&mut self, rn: Register, sr: SpecialRegister) {
+        /*
+        case SYSm<7:3> of
+            when ‘00000’ // xPSR accesses
+                if SYSm<2> == ‘0’ then // Include APSR
+                    APSR<31:27> = R[n]<31:27>;
+            when ‘00001’ // SP access
+                if CurrentModeIsPrivileged() then
+                    case SYSm<2:0> of
+                        when ‘000’
+                            SP_main = R[n]<31:2>:’00’;
+                        when ‘001’
+                            SP_process = R[n]<31:2>:’00’;
+            when ‘00010’ // Priority mask or CONTROL access
+                case SYSm<2:0> of
+                    when ‘000’
+                        if CurrentModeIsPrivileged() then
+                            PRIMASK<0> = R[n]<0>;
+                    when ‘100’
+                        if CurrentModeIsPrivileged() && CurrentMode == Mode_Thread then
+                            CONTROL<0> = R[n]<0>;
+                            CONTROL<1> = R[n]<1>;
+        */
+
+        let rn_data = self.read_register(rn);
+        let special_idx: u8 = sr.into();
+        match special_idx >> 3 {
+            0b00000 => {
+                // IPSR and EPSR ignore writes, there is no Q bit so only NZCV are written
+                if (special_idx & 0b100) == 0 {
+                    self.xpsr.0 = (self.xpsr.0 & !Xpsr::APSR) | (rn_data & Xpsr::APSR);
+                }
+            }
+            0b00001 => {
+                if self.current_mode_is_privileged() {
+                    match special_idx & 0b111 {
+                        0b000 => {
+                            self.sp_main = rn_data & !0b11;
+                        }
+                        0b001 => {
+                            self.sp_process = rn_data & !0b11;
+                        }
+                        _ => unreachable!("SYSm is validated by the decoder"),
+                    }
+                }
+            }
+            0b00010 => {
+                match special_idx & 0b111 {
+                    0b000 => {
+                        if self.current_mode_is_privileged() {
+                            self.primask = rn_data & 0b1;
+                        }
+                    }
+                    0b100 => {
+                        // IPSR is 0 in thread mode
+                        if self.current_mode_is_privileged() && self.mode == Mode::Thread {
+                            self.control = rn_data & 0b11;
+                        }
+                    }
+                    _ => unreachable!("SYSm is validated by the decoder"),
+                }
+            }
+            _ => unreachable!("SYSm is validated by the decoder"),
+        }
+    }
+
+    fn do_cps(&mut self, disable: bool) {
+        /*
+        if CurrentModeIsPrivileged() then
+            if enable then PRIMASK<0> = ‘0’;
+            if disable then PRIMASK<0> = ‘1’;
+        */
+        if self.current_mode_is_privileged() {
+            self.primask = disable as u32;
+        }
+    }
+
+    fn current_mode_is_privileged(&self) -> bool {
+        /*
+        boolean CurrentModeIsPrivileged()
+            return (CurrentMode == Mode_Handler || CONTROL.nPRIV == ‘0’);
+        */
+        self.mode == Mode::Handler || self.control & CONTROL_NPRIV == 0
+    }
+
+    // a data access by the executing instruction, the PPB faults when unprivileged
+    fn read_memmory(&mut self, addr: u32, size: Size) -> Result<u32, Fault> {
+        self.check_privilege(addr, Access::Read)?;
+        self.check_watchpoints(addr, size, Access::Read);
+        self.bus_read(addr, size)
+    }
+
+    fn write_memmory(&mut self, addr: u32, size: Size, value: u32) -> Result<(), Fault> {
+        self.check_privilege(addr, Access::Write)?;
+        self.check_watchpoints(addr, size, Access::Write);
+        self.bus_write(addr, size, value)
+    }
+
+    // Memmory with the SCB in front of it. SCB accesses still go through memmory for the
+    // alignment and region checks of the SCS.
+    fn bus_read(&mut self, addr: u32, size: Size) -> Result<u32, Fault> {
+        let value = self.memmory.read(addr, size)?;
+        if (SCB_START..SCB_START + SCB_SIZE).contains(&addr) {
+            return Ok(self.read_scb(addr - SCB_START, size));
+        }
+        Ok(value)
+    }
+
+    fn bus_write(&mut self, addr: u32, size: Size, value: u32) -> Result<(), Fault> {
+        self.memmory.write(addr, size, value)?;
+        if (SCB_START..SCB_START + SCB_SIZE).contains(&addr) {
+            return self.write_scb(addr - SCB_START, size, value);
+        }
+        Ok(())
+    }
+
+    fn check_privilege(&self, address: u32, access: Access) -> Result<(), Fault> {
+        if !self.current_mode_is_privileged() && (PPB_START..=PPB_END).contains(&address) {
+            return Err(Fault::Permission { address, access });
+        }
+        Ok(())
     }
 
     fn do_mvn_register(&mut self, rd: Register, rm: Register) {
@@ -359,14 +1187,13 @@ impl Cpu {
                 APSR.N = result<31>;
                 APSR.Z = IsZeroBit(result);
                 APSR.C = carry;
-                // APSR.V unchanged§
+                // APSR.V unchanged
         */
 
-        let data_rm = self.read_register(rm);
-        let data_rd = !data_rm;
-        self.flags.z = data_rd == 0;
-        self.flags.c = false; // no carry science no shift
-        self.write_register(rd, data_rd);
+        // without a shift Shift_C returns APSR.C as the carry, so C is unchanged
+        let result = !self.read_register(rm);
+        self.write_register(rd, result);
+        self.set_flag_nz(result);
     }
 
     fn do_bl(&mut self, imm32: u32) {
@@ -382,13 +1209,51 @@ impl Cpu {
         self.add_with_carry_register_imm(Register::PC, Register::PC, imm32, false, false);
     }
 
-    fn do_bx(&mut self, rm: Register) {
-        // this should do stuff with privileges but have not implemented yet
+    fn do_bx(&mut self, rm: Register) -> Result<(), HardFault> {
+        /*
+        if ConditionPassed() then
+            EncodingSpecificOperations();
+            BXWritePC(R[m]);
+        */
         let addr = self.read_register(rm);
-        self.write_register(Register::PC, addr);
+        self.bx_write_pc(addr)
+    }
+
+    fn bx_write_pc(&mut self, address: u32) -> Result<(), HardFault> {
+        /*
+        BXWritePC(bits(32) address)
+            if CurrentMode == Mode_Handler && address<31:28> == ‘1111’ then
+                ExceptionReturn(address<27:0>);
+            else
+                EPSR.T = address<0>; // if EPSR.T == 0, a HardFault is taken on the next instruction
+                BranchTo(address<31:1>:‘0’);
+        */
+        if self.mode == Mode::Handler && address >> 28 == 0xf {
+            return self.exception_return(address);
+        }
+        self.xpsr.set(Xpsr::T, address & 0b1 == 1);
+        self.write_register(Register::PC, address);
+        Ok(())
     }
 
-    fn do_ldr_imm(&mut self, rt: Register, imm32: u32) {
+    fn do_blx(&mut self, rm: Register) {
+        /*
+        if ConditionPassed() then
+            EncodingSpecificOperations();
+            target = R[m];
+            next_instr_addr = PC - 2;
+            LR = next_instr_addr<31:1> : ‘1’;
+            BLXWritePC(target);
+        */
+        let target = self.read_register(rm);
+        let next_instr_addr = self.read_register(Register::PC).wrapping_sub(2);
+        self.write_register(Register::LR, next_instr_addr | 0b1);
+        // BLXWritePC is BXWritePC without the exception return
+        self.xpsr.set(Xpsr::T, target & 0b1 == 1);
+        self.write_register(Register::PC, target);
+    }
+
+    fn do_ldr_literal(&mut self, rt: Register, imm32: u32) -> Result<(), Fault> {
         /*
         if ConditionPassed() then
             EncodingSpecificOperations();
@@ -398,9 +1263,10 @@ impl Cpu {
         */
         let pc = self.read_register(Register::PC);
         let base = align(pc, 4);
-        let addr = base + imm32;
-        let value = self.memmory.read_u32(addr);
+        let addr = base.wrapping_add(imm32);
+        let value = self.read_memmory(addr, Size::Word)?;
         self.write_register(rt, value);
+        Ok(())
     }
 
     fn do_cmp_r(&mut self, rm: Register, rn: Register) {
@@ -469,23 +1335,60 @@ impl Cpu {
         APSR.V = overflow;
         */
 
-        self.flags.c = carry_out;
-        self.flags.v = overflow;
-        self.flags.z = result == 0;
-        self.flags.n = (result as i32) < 0;
+        self.xpsr.set(Xpsr::N, (result as i32) < 0);
+        self.xpsr.set(Xpsr::Z, result == 0);
+        self.xpsr.set(Xpsr::C, carry_out);
+        self.xpsr.set(Xpsr::V, overflow);
         result
     }
 
-    fn write_register_to_memmory(&mut self, rt: Register, addr: u32) {
+    // the low size bytes of register rt to memmory at addr
+    fn write_register_to_memmory(&mut self, rt: Register, addr: u32, size: Size) -> Result<(), Fault> {
         let value = self.read_register(rt);
-        self.memmory.write_u32(addr, value);
+        self.write_memmory(addr, size, value)
     }
 
     // write a register rt to memmory with addr = offset + rn
-    fn write_register_to_memmory_offset(&mut self, rt: Register, rn: Register, offset: u32, add: bool) {
+    fn write_register_to_memmory_offset(&mut self, rt: Register, rn: Register, offset: u32, size: Size) -> Result<(), Fault> {
+        /*
+        offset_addr = if add then (R[n] + offset) else (R[n] - offset);
+        address = if index then offset_addr else R[n];
+        MemU[address,4] = R[t];         // STR
+        MemU[address,2] = R[t]<15:0>;   // STRH
+        MemU[address,1] = R[t]<7:0>;    // STRB
+        */
+        let rn_val = self.read_register(rn);
+        let (addr, _, _) = Self::add_with_carry(rn_val, offset, false);
+        self.write_register_to_memmory(rt, addr, size)
+    }
+
+    fn read_memmory_to_register(&mut self, rt: Register, addr: u32) -> Result<(), Fault> {
+        let value = self.read_memmory(addr, Size::Word)?;
+        self.write_register(rt, value);
+        Ok(())
+    }
+
+    // read size bytes of memmory at addr = offset + rn into register rt, sign extended if signed
+    fn read_memmory_to_register_offset(&mut self, rt: Register, rn: Register, offset: u32, size: Size, signed: bool) -> Result<(), Fault> {
+        /*
+        offset_addr = if add then (R[n] + offset) else (R[n] - offset);
+        address = if index then offset_addr else R[n];
+        R[t] = MemU[address,4];                     // LDR
+        R[t] = ZeroExtend(MemU[address,2], 32);     // LDRH
+        R[t] = SignExtend(MemU[address,2], 32);     // LDRSH
+        R[t] = ZeroExtend(MemU[address,1], 32);     // LDRB
+        R[t] = SignExtend(MemU[address,1], 32);     // LDRSB
+        */
         let rn_val = self.read_register(rn);
         let (addr, _, _) = Self::add_with_carry(rn_val, offset, false);
-        self.write_register_to_memmory(rt, addr);
+        let value = self.read_memmory(addr, size)?;
+        let value = match (size, signed) {
+            (Size::Byte, true) => value as u8 as i8 as i32 as u32,
+            (Size::Halfword, true) => value as u16 as i16 as i32 as u32,
+            _ => value,
+        };
+        self.write_register(rt, value);
+        Ok(())
     }
 
     fn do_movs_imm(&mut self, rd: Register, imm32: u32) {
@@ -507,25 +1410,82 @@ impl Cpu {
     fn do_bt1(&mut self, cond: Cond, imm32: u32) {
         if self.condition_passed(cond) {
             let pc = self.read_register(Register::PC);
-            self.write_register(Register::PC, pc + imm32);
+            self.write_register(Register::PC, pc.wrapping_add(imm32));
         }
     }
 
-    fn do_push(&mut self, register_list: RegisterList) {
+    fn do_push(&mut self, register_list: RegisterList) -> Result<(), Fault> {
         let list = register_list.to_vec();
         let len = list.len() as u32;
-        let sp = self.read_register(Register::MSP);
-        let addr = sp - 4 * len;
+        let sp = self.read_register(Register::SP);
+        let addr = sp.wrapping_sub(4 * len);
 
         for (i, r) in list.iter().enumerate() {
             let value = self.read_register(*r);
-            self.memmory.write_u32(addr + (4 * i as u32), value);
+            self.write_memmory(addr.wrapping_add(4 * i as u32), Size::Word, value)?;
         }
 
-        self.write_register(Register::MSP, sp - 4 * len);
+        self.write_register(Register::SP, addr);
+        Ok(())
+    }
+
+    fn do_pop(&mut self, register_list: RegisterList) -> Result<(), HardFault> {
+        /*
+        if ConditionPassed() then
+            EncodingSpecificOperations();
+            address = SP;
+            for i = 0 to 7
+                if registers<i> == ‘1’ then
+                    R[i] = MemA[address,4]; address = address + 4;
+            if registers<15> == ‘1’ then
+                LoadWritePC(MemA[address,4]);
+            SP = SP + 4*BitCount(registers);
+        */
+        let list = register_list.to_vec();
+        let len = list.len() as u32;
+        let sp = self.read_register(Register::SP);
+
+        let mut new_pc = None;
+        for (i, r) in list.iter().enumerate() {
+            let addr = sp.wrapping_add(4 * i as u32);
+            if *r == Register::PC {
+                new_pc = Some(self.read_memmory(addr, Size::Word)?);
+            } else {
+                self.read_memmory_to_register(*r, addr)?;
+            }
+        }
+
+        self.write_register(Register::SP, sp.wrapping_add(4 * len));
+        // LoadWritePC is BXWritePC, after SP is written so an exception return unstacks above it
+        match new_pc {
+            Some(value) => self.bx_write_pc(value),
+            None => Ok(()),
+        }
+    }
+
+    fn do_ldm(&mut self, rn: Register, register_list: RegisterList) -> Result<(), Fault> {
+        /*
+        if ConditionPassed() then
+            EncodingSpecificOperations();
+            address = R[n];
+            for i = 0 to 7
+                if registers<i> == ‘1’ then
+                    R[i] = MemA[address,4]; address = address + 4;
+            if wback && registers<n> == ‘0’ then R[n] = R[n] + 4*BitCount(registers);
+        */
+        let addr = self.read_register(rn);
+        let list = register_list.to_vec();
+        for (i, r) in list.iter().enumerate() {
+            self.read_memmory_to_register(*r, addr.wrapping_add(4 * i as u32))?;
+        }
+
+        if !list.contains(&rn) {
+            self.write_register(rn, addr.wrapping_add(4 * list.len() as u32));
+        }
+        Ok(())
     }
     
-    fn do_stm(&mut self, rn: Register, register_list: RegisterList) {
+    fn do_stm(&mut self, rn: Register, register_list: RegisterList) -> Result<(), Fault> {
         /*
         if ConditionPassed() then
             EncodingSpecificOperations();
@@ -540,23 +1500,22 @@ impl Cpu {
             if wback then R[n] = R[n] + 4*BitCount(registers);
         */
 
+        // Rn in the list stores its original value, written back after the loop. That is the
+        // defined value when it is the lowest register, and one UNKNOWN allows otherwise.
         let addr = self.read_register(rn);
         let list = register_list.to_vec();
         for (i, r) in list.iter().enumerate() {
-            if *r == rn {
-                deprecated!() // rn is not allowed to be in the list
-            }
-
             let value = self.read_register(*r);
-            self.memmory.write_u32(addr + (4 * i as u32), value);
+            self.write_memmory(addr.wrapping_add(4 * i as u32), Size::Word, value)?;
         }
 
-        self.write_register(rn, addr + (4 * list.len() as u32));
+        self.write_register(rn, addr.wrapping_add(4 * list.len() as u32));
+        Ok(())
     }
 
     fn set_flag_nz(&mut self, result: u32) {
-        self.flags.z = result == 0;
-        self.flags.n = (result as i32) < 0;
+        self.xpsr.set(Xpsr::N, (result as i32) < 0);
+        self.xpsr.set(Xpsr::Z, result == 0);
     }
 
     fn condition_passed(&self, cond: Cond) -> bool {
@@ -581,25 +1540,25 @@ impl Cpu {
         */
         let result = match cond {
             Cond::EQ | Cond::NE => {
-                self.flags.z
+                self.xpsr.z()
             },
             Cond::CS | Cond::CC => {
-                self.flags.c
+                self.xpsr.c()
             },
             Cond::MI | Cond::PL => {
-                self.flags.n
+                self.xpsr.n()
             },
             Cond::VS | Cond::VC => {
-                self.flags.v
+                self.xpsr.v()
             },
             Cond::HI | Cond::LS => {
-                self.flags.c && !self.flags.z
+                self.xpsr.c() && !self.xpsr.z()
             },
             Cond::GE | Cond::LT => {
-                self.flags.n == self.flags.v
+                self.xpsr.n() == self.xpsr.v()
             },
             Cond::GT | Cond::LE => {
-                (self.flags.n == self.flags.v) && !self.flags.z
+                (self.xpsr.n() == self.xpsr.v()) && !self.xpsr.z()
             },
             Cond::None => true
         };
@@ -620,10 +1579,96 @@ fn align(x: u32, y: u32) -> u32 {
     y * (x / y)
 }
 
+// shift types of the data processing instructions, RRX does not exist in ARMv6-M
+#[derive(Debug, Clone, Copy)]
+enum SRType {
+    Lsl,
+    Lsr,
+    Asr,
+    Ror,
+}
+
+fn shift_c(value: u32, shift_t: SRType, amount: u32, carry_in: bool) -> (u32, bool) {
+    /*
+    (bits(N), bit) Shift_C(bits(N) value, SRType type, integer amount, bit carry_in)
+        assert !(type == SRType_RRX && amount != 1);
+        if amount == 0 then
+            (result, carry_out) = (value, carry_in);
+        else
+            case type of
+                when SRType_LSL
+                    (result, carry_out) = LSL_C(value, amount);
+                when SRType_LSR
+                    (result, carry_out) = LSR_C(value, amount);
+                when SRType_ASR
+                    (result, carry_out) = ASR_C(value, amount);
+                when SRType_ROR
+                    (result, carry_out) = ROR_C(value, amount);
+        return (result, carry_out);
+    */
+    if amount == 0 {
+        return (value, carry_in);
+    }
+    match shift_t {
+        SRType::Lsl => lsl_c(value, amount),
+        SRType::Lsr => lsr_c(value, amount),
+        SRType::Asr => asr_c(value, amount),
+        SRType::Ror => ror_c(value, amount),
+    }
+}
+
+fn lsl_c(x: u32, shift: u32) -> (u32, bool) {
+    /*
+    extended_x = x : Zeros(shift);
+    result = extended_x<N-1:0>;
+    carry_out = extended_x<N>;
+    */
+    if shift > 32 {
+        return (0, false);
+    }
+    let extended_x = (x as u64) << shift;
+    (extended_x as u32, (extended_x >> 32) & 1 == 1)
+}
+
+fn lsr_c(x: u32, shift: u32) -> (u32, bool) {
+    /*
+    extended_x = ZeroExtend(x, shift+N);
+    result = extended_x<shift+N-1:shift>;
+    carry_out = extended_x<shift-1>;
+    */
+    if shift > 32 {
+        return (0, false);
+    }
+    let extended_x = x as u64;
+    ((extended_x >> shift) as u32, (extended_x >> (shift - 1)) & 1 == 1)
+}
+
+fn asr_c(x: u32, shift: u32) -> (u32, bool) {
+    /*
+    extended_x = SignExtend(x, shift+N);
+    result = extended_x<shift+N-1:shift>;
+    carry_out = extended_x<shift-1>;
+    */
+    // past 32 every bit is a copy of the sign
+    let shift = shift.min(32);
+    let extended_x = x as i32 as i64;
+    ((extended_x >> shift) as u32, (extended_x >> (shift - 1)) & 1 == 1)
+}
+
+fn ror_c(x: u32, shift: u32) -> (u32, bool) {
+    /*
+    m = shift MOD N;
+    result = LSR(x,m) OR LSL(x,N-m);
+    carry_out = result<N-1>;
+    */
+    let result = x.rotate_right(shift % 32);
+    (result, result >> 31 == 1)
+}
+
 
 #[test]
 fn demo_test() {
-    let mut chunk: &[u8] = &[
+    let chunk: &[u8] = &[
         0x09, 0x20, // mov r0, #9
         //0x0a, 0x20, // mov r0, #10
         //0x0e, 0x20, // mov r0, #14
@@ -639,7 +1684,558 @@ fn demo_test() {
     ];
     let program = Program::build(chunk, 0x104, 0x20000000);
     let mut cpu = build();
-    cpu.load_program(&program);
+    cpu.load_program(&program).unwrap();
 
-    cpu.start(0x104);
-}
\ No newline at end of file
+    assert_eq!(cpu.start(0x104), StopReason::Breakpoint(0));
+    // 9 is not higher than 10 so r0 is incremented
+    assert_eq!(cpu.get_register(Register::R0), 10);
+    assert_eq!(cpu.get_register(Register::PC), 0x10e);
+}
+
+#[test]
+fn test_run_limit() {
+    let chunk: &[u8] = &[
+        0x40, 0x1c, // adds	r0, r0, #1
+        0xfd, 0xe7, // b	0x100
+    ];
+    let program = Program::build(chunk, 0x100, 0x20000000);
+    let mut cpu = build();
+    cpu.load_program(&program).unwrap();
+    cpu.set_register(Register::PC, 0x100);
+
+    assert_eq!(cpu.run(Some(5)), StopReason::InstructionLimit);
+    assert_eq!(cpu.get_register(Register::R0), 3);
+    assert_eq!(cpu.get_register(Register::PC), 0x102);
+}
+
+#[test]
+fn test_run_options() {
+    let chunk: &[u8] = &[
+        0x30, 0xbf, // wfi
+        0xfe, 0xe7, // b	0x102
+    ];
+    let program = Program::build(chunk, 0x100, 0x20000000);
+    let mut cpu = build();
+    cpu.load_program(&program).unwrap();
+    cpu.set_register(Register::PC, 0x100);
+
+    let options = RunOptions { max_cycles: Some(3), ..RunOptions::default() };
+    assert_eq!(cpu.run_with(&options), StopReason::CycleLimit);
+    assert_eq!(cpu.get_cycles(), 3);
+    let options = RunOptions { timeout: Some(Duration::ZERO), ..RunOptions::default() };
+    assert_eq!(cpu.run_with(&options), StopReason::Timeout);
+
+    let options = RunOptions { stop_on_self_branch: true, stop_on_wfi: true, ..RunOptions::default() };
+    cpu.set_register(Register::PC, 0x100);
+    assert_eq!(cpu.run_with(&options), StopReason::WaitForInterrupt(0x100));
+    assert_eq!(cpu.get_register(Register::PC), 0x102);
+    assert_eq!(cpu.run_with(&options), StopReason::SelfBranch(0x102));
+    assert_eq!(cpu.get_register(Register::PC), 0x102);
+
+    // SysTick could still interrupt the loop
+    cpu.write_memmory(SYSTICK_START, Size::Word, 0b11).unwrap();
+    let options = RunOptions { max_instructions: Some(10), ..options };
+    assert_eq!(cpu.run_with(&options), StopReason::InstructionLimit);
+}
+
+#[test]
+fn test_load_segments() {
+    use crate::{Segment, symbols::SymbolTable};
+    use elf::abi;
+
+    let text: &[u8] = &[0x40, 0x1c, 0x70, 0x47];
+    let data: &[u8] = &[0x78, 0x56, 0x34, 0x12];
+    let program = Program {
+        text,
+        start_addr: 0x100,
+        start_stack: Some(0x20001000),
+        symbols: SymbolTable::default(),
+        segments: vec![
+            Segment { paddr: 0x100, vaddr: 0x100, data: text, mem_size: 4, flags: abi::PF_R | abi::PF_X },
+            // .data stored after .text and run from RAM
+            Segment { paddr: 0x104, vaddr: 0x20000000, data, mem_size: 4, flags: abi::PF_R | abi::PF_W },
+            // .bss
+            Segment { paddr: 0x20000004, vaddr: 0x20000004, data: &[], mem_size: 8, flags: abi::PF_R | abi::PF_W },
+        ],
+    };
+    let mut cpu = build();
+    cpu.memmory.write_u32(0x20000008, 0xffffffff).unwrap();
+    cpu.load_program(&program).unwrap();
+
+    assert_eq!(cpu.read_u32(0x100), Ok(0x47701c40));
+    assert_eq!(cpu.read_u32(0x104), Ok(0x12345678));
+    assert_eq!(cpu.read_u32(0x20000000), Ok(0x12345678));
+    assert_eq!(cpu.read_u32(0x20000008), Ok(0));
+}
+
+#[test]
+fn test_reset() {
+    let chunk: &[u8] = &[
+        0x00, 0x10, 0x00, 0x20, // initial MSP 0x20001000
+        0x11, 0x01, 0x00, 0x00, // reset handler 0x110 with the thumb bit
+    ];
+    let code: &[u8] = &[
+        0x05, 0x20, // movs	r0, #5
+        0x00, 0xbe, // bkpt	#0
+    ];
+    let mut cpu = build();
+    // no _stack_start, everything comes from the vector table at VTOR
+    cpu.load_program(&Program::build(chunk, 0x100, 0)).unwrap();
+    cpu.load_program(&Program::build(code, 0x110, 0)).unwrap();
+    cpu.set_vtor(0x100);
+    cpu.reset().unwrap();
+
+    assert_eq!(cpu.get_register(Register::SP), 0x20001000);
+    assert_eq!(cpu.get_register(Register::PC), 0x110);
+    assert_eq!(cpu.get_register(Register::LR), 0xffffffff);
+    assert_eq!(cpu.get_xpsr(), 1 << 24);
+
+    assert_eq!(cpu.run(None), StopReason::Breakpoint(0));
+    assert_eq!(cpu.get_register(Register::R0), 5);
+}
+
+#[test]
+fn test_bus_fault() {
+    let chunk: &[u8] = &[
+        0x01, 0x68, // ldr	r1, [r0]
+        // HardFault
+        0x01, 0x68, // ldr	r1, [r0]
+    ];
+    let mut cpu = build();
+    cpu.load_program(&Program::build(chunk, 0x100, 0x20001000)).unwrap();
+    cpu.memmory.write_chunk(0xc, &u32::to_le_bytes(0x103)).unwrap();
+    cpu.set_register(Register::R0, 0x60000000);
+
+    // faulting again in the HardFault handler locks up
+    let fault = Fault::Unmapped { address: 0x60000000, access: Access::Read };
+    assert_eq!(cpu.start(0x100), StopReason::Lockup(HardFault::Bus(fault)));
+    assert_eq!(cpu.get_register(Register::PC), 0x102);
+    // the faulting instruction is not completed and returns to itself
+    assert_eq!(cpu.read_u32(0x20000fe0 + 0x18), Ok(0x100));
+
+    // addresses wrap around instead of overflowing
+    cpu.write_register(Register::SP, 0);
+    let fault = Fault::Unmapped { address: 0xfffffffc, access: Access::Write };
+    assert_eq!(cpu.do_push(RegisterList(0b1)), Err(fault));
+    assert_eq!(cpu.read_register(Register::SP), 0);
+}
+
+#[test]
+fn test_top_of_memory() {
+    let memmory = Memmory::build(vec![Region::new("Top", 0xfffff000, 0x1000, Permissions::from_attributes("rwx"))]);
+    let mut cpu = build_with_memmory(memmory);
+    cpu.memmory.write_chunk(0xfffff000, &[0x70, 0x47]).unwrap(); // bx	lr
+    cpu.memmory.write_chunk(0xfffffffc, &[
+        0x7a, 0x46, // mov	r2, pc
+        0x80, 0x47, // blx	r0
+    ]).unwrap();
+    cpu.write_register(Register::SP, 0xfffff800);
+    cpu.write_register(Register::R0, 0xfffff001);
+
+    // PC reads and the return address wrap around to 0
+    cpu.write_register(Register::PC, 0xfffffffc);
+    cpu.step().unwrap();
+    assert_eq!(cpu.read_register(Register::R2), 0);
+    cpu.step().unwrap();
+    assert_eq!(cpu.read_register(Register::LR), 1);
+    cpu.write_register(Register::PC, 0xfffffffe);
+    assert_eq!(cpu.step_over(&RunOptions::default()), StopReason::StepComplete);
+    assert_eq!(cpu.get_register(Register::PC), 0);
+
+    // so does the increment of PC
+    cpu.memmory.write_chunk(0xfffffffe, &[0x00, 0xbf]).unwrap(); // nop
+    cpu.write_register(Register::PC, 0xfffffffe);
+    cpu.step().unwrap();
+    assert_eq!(cpu.get_register(Register::PC), 0);
+}
+
+#[test]
+fn test_peripheral() {
+    use std::{cell::RefCell, rc::Rc};
+
+    // a data register logging writes and a counter of ticks
+    struct Device {
+        writes: Rc<RefCell<Vec<(u32, Size, u32)>>>,
+        ticks: u32,
+    }
+
+    impl Peripheral for Device {
+        fn read(&mut self, offset: u32, _size: Size) -> u32 {
+            match offset {
+                4 => self.ticks,
+                _ => 0,
+            }
+        }
+
+        fn write(&mut self, offset: u32, size: Size, value: u32) {
+            self.writes.borrow_mut().push((offset, size, value));
+        }
+
+        fn tick(&mut self, cycles: u32) {
+            self.ticks += cycles;
+        }
+    }
+
+    let chunk: &[u8] = &[
+        0x01, 0x60, // str	r1, [r0]
+        0x42, 0x68, // ldr	r2, [r0, #4]
+        0x00, 0xbe, // bkpt	#0
+    ];
+    let writes = Rc::new(RefCell::new(vec![]));
+    let mut cpu = build();
+    cpu.load_program(&Program::build(chunk, 0x100, 0x20001000)).unwrap();
+    cpu.attach_peripheral("USART2", 0x40004400, 0x400, Box::new(Device { writes: writes.clone(), ticks: 0 }));
+    cpu.set_register(Register::R0, 0x40004400);
+    cpu.set_register(Register::R1, 0x41);
+
+    assert_eq!(cpu.start(0x100), StopReason::Breakpoint(0));
+    assert_eq!(*writes.borrow(), vec![(0, Size::Word, 0x41)]);
+    // ticked once by the store
+    assert_eq!(cpu.get_register(Register::R2), 1);
+    // outside the device the default Peripheral region is plain memory again
+    assert_eq!(cpu.read_u32(0x40004800), Ok(0));
+}
+
+#[test]
+fn test_byte_halfword() {
+    let chunk: &[u8] = &[
+        0x41, 0x70, // strb	r1, [r0, #1]
+        0x41, 0x80, // strh	r1, [r0, #2]
+        0xc2, 0x56, // ldrsb	r2, [r0, r3]
+        0x44, 0x5f, // ldrsh	r4, [r0, r5]
+        0x46, 0x78, // ldrb	r6, [r0, #1]
+        0x47, 0x88, // ldrh	r7, [r0, #2]
+        0x00, 0xbe, // bkpt	#0
+    ];
+    let mut cpu = build();
+    cpu.load_program(&Program::build(chunk, 0x100, 0x20001000)).unwrap();
+    cpu.set_register(Register::R0, 0x20000000);
+    cpu.set_register(Register::R1, 0x12348081);
+    cpu.set_register(Register::R3, 1);
+    cpu.set_register(Register::R5, 2);
+
+    assert_eq!(cpu.start(0x100), StopReason::Breakpoint(0));
+    assert_eq!(cpu.read_u32(0x20000000), Ok(0x80818100));
+    assert_eq!(cpu.get_register(Register::R2), 0xffffff81);
+    assert_eq!(cpu.get_register(Register::R4), 0xffff8081);
+    assert_eq!(cpu.get_register(Register::R6), 0x81);
+    assert_eq!(cpu.get_register(Register::R7), 0x8081);
+
+    // halfwords must be halfword aligned, the bkpt is the HardFault handler
+    cpu.memmory.write_chunk(0xc, &u32::to_le_bytes(0x10d)).unwrap();
+    cpu.set_register(Register::R0, 0x20000001);
+    assert_eq!(cpu.start(0x10a), StopReason::Breakpoint(0));
+    assert!(cpu.is_exception_active(Exception::HardFault));
+    assert_eq!(cpu.read_u32(0x20000fe0 + 0x18), Ok(0x10a));
+}
+
+#[test]
+fn test_stm_base_in_list() {
+    let chunk: &[u8] = &[
+        0x06, 0xc1, // stm	r1!, {r1, r2}
+        0x06, 0xc2, // stm	r2!, {r1, r2}
+        0x00, 0xbe, // bkpt	#0
+    ];
+    let mut cpu = build();
+    cpu.load_program(&Program::build(chunk, 0x100, 0x20001000)).unwrap();
+    cpu.set_register(Register::R1, 0x20000000);
+    cpu.set_register(Register::R2, 0x20000100);
+
+    assert_eq!(cpu.start(0x100), StopReason::Breakpoint(0));
+    // the lowest register stores the original base
+    assert_eq!(cpu.read_u32(0x20000000), Ok(0x20000000));
+    assert_eq!(cpu.read_u32(0x20000004), Ok(0x20000100));
+    assert_eq!(cpu.get_register(Register::R1), 0x20000008);
+    // R1 below the base stores its value as written back by the first STM
+    assert_eq!(cpu.read_u32(0x20000100), Ok(0x20000008));
+    // the base when it is not the lowest register stores an UNKNOWN value, the original base
+    assert_eq!(cpu.read_u32(0x20000104), Ok(0x20000100));
+    assert_eq!(cpu.get_register(Register::R2), 0x20000108);
+}
+
+#[test]
+fn test_data_proc() {
+    let chunk: &[u8] = &[
+        0x41, 0x00, // lsls	r1, r0, #1
+        0x02, 0x08, // lsrs	r2, r0, #32
+        0x03, 0x11, // asrs	r3, r0, #4
+        0x21, 0x24, // movs	r4, #33
+        0xa0, 0x40, // lsls	r0, r4
+        0x00, 0xbe, // bkpt	#0
+        0xf5, 0x41, // rors	r5, r6
+        0x75, 0x41, // adcs	r5, r6
+        0xb5, 0x41, // sbcs	r5, r6
+        0x00, 0xbe, // bkpt	#0
+        0x08, 0x42, // tst	r0, r1
+        0x5a, 0x42, // rsbs	r2, r3, #0
+        0xc8, 0x42, // cmn	r0, r1
+        0x08, 0x43, // orrs	r0, r1
+        0x48, 0x43, // muls	r0, r1, r0
+        0x88, 0x43, // bics	r0, r1
+        0x48, 0x40, // eors	r0, r1
+        0x08, 0x40, // ands	r0, r1
+        0xc8, 0x43, // mvns	r0, r1
+        0x00, 0xbe, // bkpt	#0
+    ];
+    let mut cpu = build();
+    cpu.load_program(&Program::build(chunk, 0x100, 0x20001000)).unwrap();
+
+    // shifts carry out the last bit shifted out
+    cpu.set_register(Register::R0, 0x80000001);
+    assert_eq!(cpu.start(0x100), StopReason::Breakpoint(0));
+    assert_eq!(cpu.get_register(Register::R1), 2);
+    assert_eq!(cpu.get_register(Register::R2), 0);
+    assert_eq!(cpu.get_register(Register::R3), 0xf8000000);
+    // a register shift by more than 32 clears the result and the carry
+    assert_eq!(cpu.get_register(Register::R0), 0);
+    assert_eq!(cpu.get_apsr(), 0x40000000);
+
+    cpu.set_register(Register::R5, 1);
+    cpu.set_register(Register::R6, 1);
+    assert_eq!(cpu.start(0x10c), StopReason::Breakpoint(0));
+    // ror sets C to bit 31, adc adds it and sbc subtracts without a borrow
+    assert_eq!(cpu.get_register(Register::R5), 0x80000000);
+    assert_eq!(cpu.get_apsr(), 0xa0000000);
+
+    cpu.set_register(Register::R0, 0xf0);
+    cpu.set_register(Register::R1, 0x3c);
+    cpu.set_register(Register::R3, 1);
+    assert_eq!(cpu.start(0x114), StopReason::Breakpoint(0));
+    assert_eq!(cpu.get_register(Register::R2), 0xffffffff);
+    assert_eq!(cpu.get_register(Register::R0), 0xffffffc3);
+}
+
+#[test]
+fn test_xpsr() {
+    let chunk: &[u8] = &[
+        0x00, 0x21, // movs	r1, #0
+        0x89, 0x42, // cmp	r1, r1
+        0xc9, 0x43, // mvns	r1, r1
+        0xef, 0xf3, 0x00, 0x80, // mrs	r0, apsr
+        0x89, 0x42, // cmp	r1, r1
+        0xef, 0xf3, 0x03, 0x82, // mrs	r2, xpsr
+        0xef, 0xf3, 0x05, 0x83, // mrs	r3, ipsr
+        0x00, 0xbe, // bkpt	#0
+        0x00, 0xbd, // pop	{pc}
+    ];
+    let mut cpu = build();
+    cpu.load_program(&Program::build(chunk, 0x100, 0x20001000)).unwrap();
+
+    assert_eq!(cpu.start(0x100), StopReason::Breakpoint(0));
+    // mvns sets N and Z and keeps the carry of the cmp
+    assert_eq!(cpu.get_register(Register::R0), 0xa0000000);
+    // the T bit reads as zero
+    assert_eq!(cpu.get_register(Register::R2), 0x60000000);
+    assert_eq!(cpu.get_register(Register::R3), 0);
+    assert_eq!(cpu.get_xpsr(), 0x61000000);
+
+    // interworking branches set T from bit 0 of the address
+    cpu.memmory.write_u32(0x20000ffc, 0x100).unwrap();
+    cpu.set_register(Register::SP, 0x20000ffc);
+    cpu.set_register(Register::PC, 0x116);
+    cpu.step().unwrap();
+    assert_eq!(cpu.get_register(Register::PC), 0x100);
+    assert_eq!(cpu.get_xpsr(), 0x60000000);
+}
+
+#[test]
+fn test_msr_cps() {
+    let chunk: &[u8] = &[
+        0x72, 0xb6, // cpsid	i
+        0xef, 0xf3, 0x10, 0x80, // mrs	r0, PRIMASK
+        0x62, 0xb6, // cpsie	i
+        0xef, 0xf3, 0x10, 0x81, // mrs	r1, PRIMASK
+        0x82, 0xf3, 0x00, 0x88, // msr	APSR, r2
+        0x83, 0xf3, 0x09, 0x88, // msr	PSP, r3
+        0xef, 0xf3, 0x09, 0x84, // mrs	r4, PSP
+        0x83, 0xf3, 0x08, 0x88, // msr	MSP, r3
+        0x85, 0xf3, 0x10, 0x88, // msr	PRIMASK, r5
+        0x00, 0xbe, // bkpt	#0
+    ];
+    let mut cpu = build();
+    cpu.load_program(&Program::build(chunk, 0x100, 0x20001000)).unwrap();
+    cpu.set_register(Register::R2, 0x9fffffff);
+    cpu.set_register(Register::R3, 0x20000803);
+    cpu.set_register(Register::R5, 1);
+
+    assert_eq!(cpu.start(0x100), StopReason::Breakpoint(0));
+    assert_eq!(cpu.get_register(Register::R0), 1);
+    assert_eq!(cpu.get_register(Register::R1), 0);
+    // only the flags are written and the T bit is kept
+    assert_eq!(cpu.get_xpsr(), 0x91000000);
+    // stack pointers are word aligned
+    assert_eq!(cpu.get_register(Register::R4), 0x20000800);
+    assert_eq!(cpu.get_register(Register::SP), 0x20000800);
+    // PRIMASK masks every exception with configurable priority
+    assert_eq!(cpu.get_execution_priority(), 0);
+}
+
+#[test]
+fn test_banked_sp() {
+    let chunk: &[u8] = &[
+        0x80, 0xf3, 0x09, 0x88, // msr	PSP, r0
+        0x02, 0x21, // movs	r1, #2
+        0x81, 0xf3, 0x14, 0x88, // msr	CONTROL, r1
+        0xbf, 0xf3, 0x6f, 0x8f, // isb	sy
+        0x02, 0xb4, // push	{r1}
+        0xef, 0xf3, 0x08, 0x82, // mrs	r2, MSP
+        0xef, 0xf3, 0x09, 0x83, // mrs	r3, PSP
+        0x6c, 0x46, // mov	r4, sp
+        0xef, 0xf3, 0x14, 0x85, // mrs	r5, CONTROL
+        0x00, 0xbe, // bkpt	#0
+    ];
+    let mut cpu = build();
+    cpu.load_program(&Program::build(chunk, 0x100, 0x20001000)).unwrap();
+    cpu.set_register(Register::R0, 0x20000800);
+
+    assert_eq!(cpu.start(0x100), StopReason::Breakpoint(0));
+    // with SPSEL set thread mode pushes to the process stack
+    assert_eq!(cpu.read_u32(0x200007fc), Ok(2));
+    assert_eq!(cpu.get_register(Register::R2), 0x20001000);
+    assert_eq!(cpu.get_register(Register::R3), 0x200007fc);
+    assert_eq!(cpu.get_register(Register::R4), 0x200007fc);
+    assert_eq!(cpu.get_register(Register::R5), 2);
+    assert_eq!(cpu.get_register(Register::SP), 0x200007fc);
+    assert_eq!(cpu.get_special_register(SpecialRegister::MSP), 0x20001000);
+}
+
+#[test]
+fn test_unprivileged() {
+    let chunk: &[u8] = &[
+        0x01, 0x21, // movs	r1, #1
+        0x81, 0xf3, 0x14, 0x88, // msr	CONTROL, r1
+        0xbf, 0xf3, 0x6f, 0x8f, // isb	sy
+        0x72, 0xb6, // cpsid	i
+        0xef, 0xf3, 0x10, 0x80, // mrs	r0, PRIMASK
+        0x00, 0x21, // movs	r1, #0
+        0x81, 0xf3, 0x14, 0x88, // msr	CONTROL, r1
+        0xef, 0xf3, 0x14, 0x82, // mrs	r2, CONTROL
+        0x23, 0x68, // ldr	r3, [r4]
+        0x00, 0xbe, // bkpt	#0
+    ];
+    let mut cpu = build();
+    cpu.load_program(&Program::build(chunk, 0x100, 0x20001000)).unwrap();
+    // the bkpt is the HardFault handler
+    cpu.memmory.write_chunk(0xc, &u32::to_le_bytes(0x11d)).unwrap();
+    cpu.set_register(Register::R0, 0xff);
+    cpu.set_register(Register::R4, 0xe000ed00);
+
+    // the SCS can not be read from unprivileged thread mode
+    assert_eq!(cpu.start(0x100), StopReason::Breakpoint(0));
+    assert_eq!(cpu.get_mode(), Mode::Handler);
+    assert_eq!(cpu.read_u32(0x20000fe0 + 0x18), Ok(0x11a));
+    // cpsid is ignored
+    assert_eq!(cpu.get_register(Register::R0), 0);
+    assert_eq!(cpu.get_special_register(SpecialRegister::PRIMASK), 0);
+    // unprivileged code can not make itself privileged again
+    assert_eq!(cpu.get_register(Register::R2), 1);
+}
+
+#[test]
+fn test_interrupts() {
+    let chunk: &[u8] = &[
+        0x01, 0x60, // str	r1, [r0]
+        0x1a, 0x60, // str	r2, [r3]
+        0x01, 0x34, // adds	r4, #1
+        0xfd, 0xe7, // b	0x104
+        // IRQ0
+        0x01, 0x35, // adds	r5, #1
+        0x70, 0x47, // bx	lr
+        // IRQ1
+        0x01, 0x36, // adds	r6, #1
+        0x70, 0x47, // bx	lr
+    ];
+    let mut cpu = build();
+    cpu.load_program(&Program::build(chunk, 0x100, 0x20001000)).unwrap();
+    cpu.memmory.write_chunk(0x40, &[0x09, 0x01, 0x00, 0x00, 0x0d, 0x01, 0x00, 0x00]).unwrap();
+    cpu.set_register(Register::PC, 0x100);
+    // enable IRQ0 at priority 2 and IRQ1 at priority 1
+    cpu.set_register(Register::R0, 0xe000e100);
+    cpu.set_register(Register::R1, 0b11);
+    cpu.set_register(Register::R2, 0x4080);
+    cpu.set_register(Register::R3, 0xe000e400);
+    cpu.step().unwrap();
+    cpu.step().unwrap();
+    assert_eq!(cpu.read_u32(0xe000e100), Ok(0b11));
+
+    // the higher priority IRQ1 is taken first
+    cpu.raise_irq(0);
+    cpu.raise_irq(1);
+    cpu.step().unwrap();
+    assert_eq!(cpu.get_register(Register::R6), 1);
+    assert_eq!(cpu.get_xpsr() & 0x3f, 17);
+    assert_eq!(cpu.get_register(Register::LR), 0xfffffff9);
+    assert_eq!(cpu.get_register(Register::SP), 0x20000fe0);
+    assert_eq!(cpu.read_u32(0x20000fe0 + 0x18), Ok(0x104));
+
+    // IRQ0 is tail-chained on the same frame
+    cpu.step().unwrap();
+    assert_eq!(cpu.get_register(Register::PC), 0x108);
+    assert_eq!(cpu.get_xpsr() & 0x3f, 16);
+    assert_eq!(cpu.get_register(Register::LR), 0xfffffff9);
+    assert_eq!(cpu.get_register(Register::SP), 0x20000fe0);
+    assert!(!cpu.is_exception_active(Exception::Irq(1)));
+    cpu.step().unwrap();
+    assert_eq!(cpu.get_register(Register::R5), 1);
+
+    // IRQ1 preempts IRQ0 and returns to it
+    cpu.raise_irq(1);
+    cpu.step().unwrap();
+    assert_eq!(cpu.get_register(Register::R6), 2);
+    assert_eq!(cpu.get_register(Register::LR), 0xfffffff1);
+    assert_eq!(cpu.get_register(Register::SP), 0x20000fc0);
+    cpu.step().unwrap();
+    assert_eq!(cpu.get_register(Register::PC), 0x10a);
+    assert_eq!(cpu.get_mode(), Mode::Handler);
+    cpu.step().unwrap();
+    assert_eq!(cpu.get_register(Register::PC), 0x104);
+    assert_eq!(cpu.get_mode(), Mode::Thread);
+    assert_eq!(cpu.get_register(Register::SP), 0x20001000);
+
+    // PRIMASK keeps the interrupt pending
+    cpu.primask = 1;
+    cpu.raise_irq(0);
+    cpu.step().unwrap();
+    assert_eq!(cpu.get_register(Register::PC), 0x106);
+    assert!(cpu.is_exception_pending(Exception::Irq(0)));
+
+    // there is no IRQ32 to pend
+    cpu.raise_irq(32);
+    cpu.raise_irq(u32::MAX);
+    assert_eq!(cpu.nvic.borrow().highest_pending().map(|(number, _)| number), Some(16));
+}
+
+#[test]
+fn test_systick_exception() {
+    let chunk: &[u8] = &[
+        0x41, 0x60, // str	r1, [r0, #4]
+        0x03, 0x21, // movs	r1, #3
+        0x01, 0x60, // str	r1, [r0]
+        0xfe, 0xe7, // b	0x106
+        // SysTick
+        0x01, 0x35, // adds	r5, #1
+        0x70, 0x47, // bx	lr
+    ];
+    let mut cpu = build();
+    cpu.load_program(&Program::build(chunk, 0x100, 0x20001000)).unwrap();
+    cpu.memmory.write_chunk(0x3c, &u32::to_le_bytes(0x109)).unwrap();
+    cpu.set_register(Register::PC, 0x100);
+    // reload every 10 cycles with the interrupt enabled
+    cpu.set_register(Register::R0, 0xe000e010);
+    cpu.set_register(Register::R1, 9);
+
+    assert_eq!(cpu.run(Some(3)), StopReason::InstructionLimit);
+    assert_eq!(cpu.read_u32(0xe000e018), Ok(9));
+    // one cycle reloads, 9 count down and the last of them pends SysTick
+    assert_eq!(cpu.run(Some(9)), StopReason::InstructionLimit);
+    assert!(cpu.is_exception_pending(Exception::SysTick));
+    assert_eq!(cpu.run(Some(1)), StopReason::InstructionLimit);
+    assert_eq!(cpu.get_register(Register::R5), 1);
+    assert_eq!(cpu.get_register(Register::PC), 0x10a);
+
+    // COUNTFLAG is set and cleared by reading it
+    assert_eq!(cpu.read_u32(0xe000e010), Ok(0x10007));
+    assert_eq!(cpu.read_u32(0xe000e010), Ok(0x7));
+    assert_eq!(cpu.run(Some(100)), StopReason::InstructionLimit);
+    assert_eq!(cpu.get_register(Register::R5), 11);
+}
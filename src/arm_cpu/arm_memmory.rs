@@ -1,33 +1,420 @@
+use std::collections::BTreeMap;
+
+use super::peripheral::Peripheral;
+use crate::Error;
+
+// regions are backed by pages allocated on first write, unwritten memory reads as zero
+const PAGE_SIZE: usize = 0x1000;
+
+// width of a single access in bytes
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Size {
+    Byte = 1,
+    Halfword = 2,
+    Word = 4,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Access {
+    Read,
+    Write,
+    Execute,
+}
+
+// an access the memory system refused, taken as a HardFault by the processor
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Fault {
+    // no region is mapped at address
+    Unmapped { address: u32, access: Access },
+    // the region at address does not allow the access
+    Permission { address: u32, access: Access },
+    // A3.2.1 ARMv6-M has no unaligned accesses, halfwords and words must be naturally aligned
+    Unaligned { address: u32, access: Access },
+}
+
+impl std::fmt::Display for Fault {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Fault::Unmapped { address, access } => write!(f, "bus fault: {access:?} of unmapped address {address:#x}"),
+            Fault::Permission { address, access } => write!(f, "bus fault: {access:?} not permitted at {address:#x}"),
+            Fault::Unaligned { address, access } => write!(f, "unaligned {access:?} at {address:#x}"),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Permissions {
+    pub read: bool,
+    pub write: bool,
+    pub execute: bool,
+}
+
+impl Permissions {
+    // linker script attributes like `rx` or `rwx`
+    pub fn from_attributes(attributes: &str) -> Permissions {
+        Permissions {
+            read: attributes.contains(['r', 'R']),
+            write: attributes.contains(['w', 'W']),
+            execute: attributes.contains(['x', 'X']),
+        }
+    }
+
+    fn allows(&self, access: Access) -> bool {
+        match access {
+            Access::Read => self.read,
+            Access::Write => self.write,
+            Access::Execute => self.execute,
+        }
+    }
+}
+
+enum Backing {
+    Pages(BTreeMap<u32, Box<[u8; PAGE_SIZE]>>),
+    Peripheral(Box<dyn Peripheral>),
+}
+
+impl std::fmt::Debug for Backing {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Backing::Pages(pages) => write!(f, "Pages({})", pages.len()),
+            Backing::Peripheral(_) => write!(f, "Peripheral"),
+        }
+    }
+}
 
+#[derive(Debug)]
+pub struct Region {
+    pub name: String,
+    pub start: u32,
+    pub size: u32,
+    pub permissions: Permissions,
+    backing: Backing,
+}
+
+impl Region {
+    pub fn new(name: &str, start: u32, size: u32, permissions: Permissions) -> Region {
+        Region { name: name.to_string(), start, size, permissions, backing: Backing::Pages(BTreeMap::new()) }
+    }
+
+    // a region handled by a peripheral, never executable
+    pub fn peripheral(name: &str, start: u32, size: u32, peripheral: Box<dyn Peripheral>) -> Region {
+        let permissions = Permissions::from_attributes("rw");
+        Region { name: name.to_string(), start, size, permissions, backing: Backing::Peripheral(peripheral) }
+    }
+
+    fn contains(&self, address: u32) -> bool {
+        address.wrapping_sub(self.start) < self.size
+    }
+
+    fn read_byte(&self, address: u32) -> u8 {
+        let offset = (address - self.start) as usize;
+        match &self.backing {
+            Backing::Pages(pages) => match pages.get(&((offset / PAGE_SIZE) as u32)) {
+                Some(page) => page[offset % PAGE_SIZE],
+                None => 0,
+            },
+            // fetches are refused before getting here, see `Region::peripheral`
+            Backing::Peripheral(_) => 0,
+        }
+    }
+
+    fn write_byte(&mut self, address: u32, value: u8) {
+        let offset = (address - self.start) as usize;
+        match &mut self.backing {
+            Backing::Pages(pages) => {
+                let page = pages.entry((offset / PAGE_SIZE) as u32).or_insert_with(|| Box::new([0; PAGE_SIZE]));
+                page[offset % PAGE_SIZE] = value;
+            }
+            Backing::Peripheral(peripheral) => peripheral.write(offset as u32, Size::Byte, value as u32),
+        }
+    }
+
+    fn read(&mut self, address: u32, size: Size) -> u32 {
+        match &mut self.backing {
+            Backing::Pages(_) => (0..size as u32)
+                .rev()
+                .fold(0, |value, i| (value << 8) | self.read_byte(address + i) as u32),
+            Backing::Peripheral(peripheral) => peripheral.read(address - self.start, size),
+        }
+    }
+
+    fn write(&mut self, address: u32, size: Size, value: u32) {
+        match &mut self.backing {
+            Backing::Pages(_) => {
+                for i in 0..size as u32 {
+                    self.write_byte(address + i, (value >> (8 * i)) as u8);
+                }
+            }
+            Backing::Peripheral(peripheral) => peripheral.write(address - self.start, size, value),
+        }
+    }
+}
+
+// The memory map, a set of named regions. Accesses outside of them are bus faults.
 #[derive(Debug)]
 pub struct Memmory {
-    pub data: Vec<u8>,
+    regions: Vec<Region>,
+}
+
+// B3.1 the ARMv6-M system address map, Code, SRAM and Peripheral in full and the System
+// Control Space
+impl Default for Memmory {
+    fn default() -> Self {
+        Memmory::build(vec![
+            Region::new("Code", 0x00000000, 0x20000000, Permissions::from_attributes("rx")),
+            Region::new("SRAM", 0x20000000, 0x20000000, Permissions::from_attributes("rwx")),
+            Region::new("Peripheral", 0x40000000, 0x20000000, Permissions::from_attributes("rw")),
+            scs_region(),
+        ])
+    }
+}
+
+fn scs_region() -> Region {
+    Region::new("SCS", 0xe000e000, 0x1000, Permissions::from_attributes("rw"))
 }
 
 impl Memmory {
-    pub fn write_chunk(&mut self, start_addr: u32, data: &[u8]) {
-        for (i, n) in data.iter().enumerate() {
-            self.data[i + start_addr as usize] = *n;
+    pub fn build(regions: Vec<Region>) -> Memmory {
+        Memmory { regions }
+    }
+
+    // The MEMORY regions of a linker script like app/memory.x, the Peripheral region and the
+    // System Control Space are added to them. Regions without attributes allow everything,
+    // as for the linker.
+    pub fn from_memory_x(script: &str) -> Result<Memmory, Error> {
+        // drop /* */ comments
+        let mut text = String::new();
+        let mut rest = script;
+        while let Some(start) = rest.find("/*") {
+            text.push_str(&rest[..start]);
+            rest = match rest[start..].find("*/") {
+                Some(end) => &rest[start + end + 2..],
+                None => return Err(Error::UnableToParseMemoryMap),
+            };
+        }
+        text.push_str(rest);
+
+        let block = match text.find("MEMORY") {
+            Some(x) => &text[x..],
+            None => return Err(Error::UnableToParseMemoryMap),
+        };
+        let (open, close) = match (block.find('{'), block.find('}')) {
+            (Some(open), Some(close)) if open < close => (open, close),
+            _ => return Err(Error::UnableToParseMemoryMap),
+        };
+
+        let mut regions = vec![];
+        for line in block[open + 1..close].lines().map(str::trim).filter(|x| !x.is_empty()) {
+            // NAME (attributes) : ORIGIN = 0x00000000, LENGTH = 256K
+            let (name, fields) = line.split_once(':').ok_or(Error::UnableToParseMemoryMap)?;
+            let (name, attributes) = match name.split_once('(') {
+                Some((name, attributes)) => (name.trim(), attributes.trim_end().trim_end_matches(')')),
+                None => (name.trim(), "rwx"),
+            };
+
+            let mut origin = None;
+            let mut length = None;
+            for field in fields.split(',') {
+                let (key, value) = field.split_once('=').ok_or(Error::UnableToParseMemoryMap)?;
+                match key.trim() {
+                    "ORIGIN" | "org" | "o" => origin = Some(parse_size(value.trim())?),
+                    "LENGTH" | "len" | "l" => length = Some(parse_size(value.trim())?),
+                    _ => return Err(Error::UnableToParseMemoryMap),
+                }
+            }
+            match (origin, length) {
+                (Some(origin), Some(length)) => {
+                    regions.push(Region::new(name, origin, length, Permissions::from_attributes(attributes)))
+                }
+                _ => return Err(Error::UnableToParseMemoryMap),
+            }
         }
+
+        regions.push(Region::new("Peripheral", 0x40000000, 0x20000000, Permissions::from_attributes("rw")));
+        regions.push(scs_region());
+        Ok(Memmory::build(regions))
+    }
+
+    pub fn regions(&self) -> &[Region] {
+        &self.regions
     }
 
-    pub fn read_4B(&self, addr: u32) -> [u8; 4] {
-        let mut ret: [u8; 4] = [0; 4];
-        let i = addr as usize;
-        for (j, x) in self.data[i..i + 4].to_owned().iter().enumerate() {
-            ret[j] = *x;
+    // Attaches a peripheral over [start, start + size), it takes precedence over regions
+    // already mapped there.
+    pub fn attach(&mut self, name: &str, start: u32, size: u32, peripheral: Box<dyn Peripheral>) {
+        self.regions.insert(0, Region::peripheral(name, start, size, peripheral));
+    }
+
+    pub fn tick(&mut self, cycles: u32) {
+        for region in self.regions.iter_mut() {
+            if let Backing::Peripheral(peripheral) = &mut region.backing {
+                peripheral.tick(cycles);
+            }
         }
-        ret
     }
 
-    pub fn read_u32(&self, addr: u32) -> u32 {
-        let chunk = self.read_4B(addr);
-        let value = u32::from_le_bytes(chunk);
-        value
+    // whether a peripheral can still pend an exception as time passes
+    pub fn can_interrupt(&self) -> bool {
+        self.regions.iter().any(|region| matches!(&region.backing, Backing::Peripheral(x) if x.can_interrupt()))
     }
 
-    pub fn write_u32(&mut self, addr: u32, value: u32) {
-        let chunk = value.to_le_bytes();
-        self.write_chunk(addr, &chunk);
-    } 
-}
\ No newline at end of file
+    // resets the peripherals, memory keeps its contents
+    pub fn reset(&mut self) {
+        for region in self.regions.iter_mut() {
+            if let Backing::Peripheral(peripheral) = &mut region.backing {
+                peripheral.reset();
+            }
+        }
+    }
+
+    // the region all of [address, address + size) is in
+    fn region(&mut self, address: u32, size: u32, access: Access) -> Result<&mut Region, Fault> {
+        let index = match self.regions.iter().position(|x| x.contains(address)) {
+            Some(x) => x,
+            None => return Err(Fault::Unmapped { address, access }),
+        };
+        let region = &mut self.regions[index];
+        if !region.permissions.allows(access) {
+            return Err(Fault::Permission { address, access });
+        }
+        if let Some(outside) = (1..size).map(|i| address.wrapping_add(i)).find(|x| !region.contains(*x)) {
+            return Err(Fault::Unmapped { address: outside, access });
+        }
+        Ok(region)
+    }
+
+    // a single access of size, zero extended
+    pub fn read(&mut self, address: u32, size: Size) -> Result<u32, Fault> {
+        if !address.is_multiple_of(size as u32) {
+            return Err(Fault::Unaligned { address, access: Access::Read });
+        }
+        let region = self.region(address, size as u32, Access::Read)?;
+        Ok(region.read(address, size))
+    }
+
+    // a single access of size, value is truncated to it
+    pub fn write(&mut self, address: u32, size: Size, value: u32) -> Result<(), Fault> {
+        if !address.is_multiple_of(size as u32) {
+            return Err(Fault::Unaligned { address, access: Access::Write });
+        }
+        let region = self.region(address, size as u32, Access::Write)?;
+        region.write(address, size, value);
+        Ok(())
+    }
+
+    // loads an image, the region permissions do not apply
+    pub fn write_chunk(&mut self, start_addr: u32, data: &[u8]) -> Result<(), Fault> {
+        // nothing is written when any byte is unmapped
+        for i in 0..data.len() as u32 {
+            let address = start_addr.wrapping_add(i);
+            if !self.regions.iter().any(|x| x.contains(address)) {
+                return Err(Fault::Unmapped { address, access: Access::Write });
+            }
+        }
+
+        for (i, n) in data.iter().enumerate() {
+            let address = start_addr.wrapping_add(i as u32);
+            if let Some(region) = self.regions.iter_mut().find(|x| x.contains(address)) {
+                region.write_byte(address, *n);
+            }
+        }
+        Ok(())
+    }
+
+    // instruction fetch, needs execute permission
+    pub fn fetch(&self, addr: u32, len: usize) -> Result<Vec<u8>, Fault> {
+        (0..len as u32)
+            .map(|i| {
+                let address = addr.wrapping_add(i);
+                match self.regions.iter().find(|x| x.contains(address)) {
+                    Some(x) if x.permissions.execute => Ok(x.read_byte(address)),
+                    Some(_) => Err(Fault::Permission { address, access: Access::Execute }),
+                    None => Err(Fault::Unmapped { address, access: Access::Execute }),
+                }
+            })
+            .collect()
+    }
+
+    pub fn read_u8(&mut self, addr: u32) -> Result<u8, Fault> {
+        self.read(addr, Size::Byte).map(|x| x as u8)
+    }
+
+    pub fn read_u16(&mut self, addr: u32) -> Result<u16, Fault> {
+        self.read(addr, Size::Halfword).map(|x| x as u16)
+    }
+
+    pub fn read_u32(&mut self, addr: u32) -> Result<u32, Fault> {
+        self.read(addr, Size::Word)
+    }
+
+    pub fn write_u8(&mut self, addr: u32, value: u8) -> Result<(), Fault> {
+        self.write(addr, Size::Byte, value as u32)
+    }
+
+    pub fn write_u16(&mut self, addr: u32, value: u16) -> Result<(), Fault> {
+        self.write(addr, Size::Halfword, value as u32)
+    }
+
+    pub fn write_u32(&mut self, addr: u32, value: u32) -> Result<(), Fault> {
+        self.write(addr, Size::Word, value)
+    }
+}
+
+// 0x20000000, 4096, 256K or 1M
+fn parse_size(value: &str) -> Result<u32, Error> {
+    let (digits, scale) = match value.strip_suffix(['K', 'k']) {
+        Some(x) => (x, 1024),
+        None => match value.strip_suffix(['M', 'm']) {
+            Some(x) => (x, 1024 * 1024),
+            None => (value, 1),
+        },
+    };
+    let parsed = match digits.strip_prefix("0x").or_else(|| digits.strip_prefix("0X")) {
+        Some(hex) => u32::from_str_radix(hex, 16),
+        None => digits.parse(),
+    };
+    parsed.ok().and_then(|x| x.checked_mul(scale)).ok_or(Error::UnableToParseMemoryMap)
+}
+
+#[test]
+fn test_memory_x() {
+    let memmory = Memmory::from_memory_x(include_str!("../../app/memory.x")).unwrap();
+    let regions: Vec<(&str, u32, u32)> = memmory.regions().iter().map(|x| (x.name.as_str(), x.start, x.size)).collect();
+    assert_eq!(
+        regions,
+        vec![
+            ("FLASH", 0x00000000, 256 * 1024),
+            ("RAM", 0x20000000, 64 * 1024),
+            ("Peripheral", 0x40000000, 0x20000000),
+            ("SCS", 0xe000e000, 0x1000),
+        ]
+    );
+
+    let memmory = Memmory::from_memory_x("MEMORY {\n FLASH (rx) : ORIGIN = 0x08000000, LENGTH = 0x10000\n}").unwrap();
+    assert_eq!(memmory.regions()[0].permissions, Permissions::from_attributes("rx"));
+    assert!(Memmory::from_memory_x("MEMORY { FLASH : ORIGIN = 0x0 }").is_err());
+}
+
+#[test]
+fn test_faults() {
+    let mut memmory = Memmory::from_memory_x(include_str!("../../app/memory.x")).unwrap();
+
+    memmory.write_u32(0x2000fffc, 0x12345678).unwrap();
+    assert_eq!(memmory.read_u32(0x2000fffc), Ok(0x12345678));
+    // past the end of RAM
+    assert_eq!(memmory.read_u32(0x20010000), Err(Fault::Unmapped { address: 0x20010000, access: Access::Read }));
+    // unaligned, nothing is written
+    assert_eq!(memmory.write_u32(0x2000fffe, 0), Err(Fault::Unaligned { address: 0x2000fffe, access: Access::Write }));
+    assert_eq!(memmory.read_u16(0x2000fffd), Err(Fault::Unaligned { address: 0x2000fffd, access: Access::Read }));
+    assert_eq!(memmory.read_u32(0x2000fffc), Ok(0x12345678));
+    // little endian
+    memmory.write_u8(0x2000fffd, 0xab).unwrap();
+    memmory.write_u16(0x2000fffe, 0xcdef).unwrap();
+    assert_eq!(memmory.read_u32(0x2000fffc), Ok(0xcdefab78));
+    assert_eq!(memmory.read_u16(0x2000fffc), Ok(0xab78));
+    assert_eq!(memmory.read_u8(0x2000ffff), Ok(0xcd));
+    // peripherals are not executable
+    assert_eq!(memmory.fetch(0x40000000, 2), Err(Fault::Permission { address: 0x40000000, access: Access::Execute }));
+    // the default map has the code region read only
+    assert_eq!(Memmory::default().write_u32(0x100, 0), Err(Fault::Permission { address: 0x100, access: Access::Write }));
+}
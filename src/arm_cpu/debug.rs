@@ -0,0 +1,314 @@
+// Breakpoints and watchpoints for debuggers, the BPU and DWT comparators of the hardware
+// without their limits on number and size. Breakpoints stop run before the instruction at
+// their address, watchpoints stop after the instruction that accessed their range. Stepping
+// over and out of calls builds on them.
+use std::fmt;
+
+use super::{
+    arm_memmory::{Access, Size},
+    Cpu, RunOptions, StopReason,
+};
+use crate::{
+    ast::{Register, Thumb, Thumb16, Thumb32},
+    instructions::Instructions,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WatchKind {
+    Read,
+    Write,
+    // reads and writes
+    Access,
+}
+
+impl WatchKind {
+    fn matches(&self, access: Access) -> bool {
+        matches!(
+            (self, access),
+            (WatchKind::Read, Access::Read) | (WatchKind::Write, Access::Write) | (WatchKind::Access, Access::Read | Access::Write)
+        )
+    }
+}
+
+impl fmt::Display for WatchKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WatchKind::Read => write!(f, "read"),
+            WatchKind::Write => write!(f, "write"),
+            WatchKind::Access => write!(f, "access"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Watchpoint {
+    pub start: u32,
+    pub length: u32,
+    pub kind: WatchKind,
+}
+
+impl Watchpoint {
+    fn overlaps(&self, address: u32, size: u32) -> bool {
+        let start = self.start as u64;
+        let address = address as u64;
+        address < start + self.length as u64 && start < address + size as u64
+    }
+}
+
+impl Cpu {
+    pub fn add_breakpoint(&mut self, address: u32) {
+        self.breakpoints.insert(address & !0b1);
+    }
+
+    // false when there was no breakpoint at address
+    pub fn remove_breakpoint(&mut self, address: u32) -> bool {
+        self.breakpoints.remove(&(address & !0b1))
+    }
+
+    pub fn has_breakpoint(&self, address: u32) -> bool {
+        self.breakpoints.contains(&(address & !0b1))
+    }
+
+    pub fn add_watchpoint(&mut self, start: u32, length: u32, kind: WatchKind) {
+        self.watchpoints.push(Watchpoint { start, length, kind });
+    }
+
+    // false when no watchpoint was set like that
+    pub fn remove_watchpoint(&mut self, start: u32, length: u32, kind: WatchKind) -> bool {
+        let watchpoint = Watchpoint { start, length, kind };
+        match self.watchpoints.iter().position(|x| *x == watchpoint) {
+            Some(i) => {
+                self.watchpoints.remove(i);
+                true
+            }
+            None => false,
+        }
+    }
+
+    // Runs until address is reached, after at least one instruction so it can be where
+    // execution already is.
+    pub fn run_until(&mut self, address: u32, options: &RunOptions) -> StopReason {
+        let address = address & !0b1;
+        self.run_tracing(options, |_| {}, |cpu| {
+            (cpu.registers[15] == address).then_some(StopReason::AddressReached(address))
+        })
+    }
+
+    // Steps a single instruction, running a BL or BLX call until it returns or options stop it.
+    pub fn step_over(&mut self, options: &RunOptions) -> StopReason {
+        let pc = self.registers[15];
+        let (instruction, size) = match self.decode_at(pc) {
+            Some(x) => x,
+            // the step reports why it could not be decoded
+            None => return self.step().err().unwrap_or(StopReason::StepComplete),
+        };
+        if !is_call(&instruction) {
+            return self.step().err().unwrap_or(StopReason::StepComplete);
+        }
+        // back at the return address with the frame of the call gone, not in a recursive call
+        // or an exception handler
+        let return_address = pc.wrapping_add(size);
+        let sp = self.read_register(Register::SP);
+        let ipsr = self.xpsr.ipsr();
+        self.run_tracing(options, |_| {}, |cpu| {
+            let returned = cpu.registers[15] == return_address && cpu.xpsr.ipsr() == ipsr && cpu.read_register(Register::SP) >= sp;
+            returned.then_some(StopReason::StepComplete)
+        })
+    }
+
+    // Runs until the current function returns or options stop it, counting the calls and
+    // returns made on the way.
+    pub fn step_out(&mut self, options: &RunOptions) -> StopReason {
+        let ipsr = self.xpsr.ipsr();
+        let mut next = self.next_instruction(ipsr);
+        let mut depth = 0;
+        self.run_tracing(options, |_| {}, |cpu| {
+            let executed = std::mem::replace(&mut next, cpu.next_instruction(ipsr));
+            match executed {
+                Some(ref x) if is_call(x) => depth += 1,
+                Some(ref x) if is_return(x) => {
+                    if depth == 0 {
+                        return Some(StopReason::StepComplete);
+                    }
+                    depth -= 1;
+                }
+                _ => {}
+            }
+            None
+        })
+    }
+
+    // The instruction the next step executes, unless it takes an exception or runs at another
+    // exception number than ipsr.
+    fn next_instruction(&self, ipsr: u32) -> Option<Thumb> {
+        match self.pending_exception() {
+            None if self.xpsr.ipsr() == ipsr => self.decode_at(self.registers[15]).map(|(x, _)| x),
+            _ => None,
+        }
+    }
+
+    // the instruction at address and its size
+    fn decode_at(&self, address: u32) -> Option<(Thumb, u32)> {
+        let data = self.fetch(address).ok()?;
+        let decoded = Instructions::new(&data, address).next()?.ok()?;
+        Some((decoded.instruction, decoded.size))
+    }
+
+    // records the first data access of the instruction to hit a watchpoint
+    pub(super) fn check_watchpoints(&mut self, address: u32, size: Size, access: Access) {
+        if self.watchpoint_hit.is_some() {
+            return;
+        }
+        let hit = self.watchpoints.iter().find(|x| x.kind.matches(access) && x.overlaps(address, size as u32));
+        if let Some(watchpoint) = hit {
+            // the address within the watched range
+            self.watchpoint_hit = Some((watchpoint.kind, address.max(watchpoint.start)));
+        }
+    }
+}
+
+fn is_call(instruction: &Thumb) -> bool {
+    matches!(instruction, Thumb::Thumb32(Thumb32::BlT1(_)) | Thumb::Thumb16(Thumb16::BlxRegT1(_)))
+}
+
+// BX, usually BX LR, and POP of PC
+fn is_return(instruction: &Thumb) -> bool {
+    match instruction {
+        Thumb::Thumb16(Thumb16::BxT1(_)) => true,
+        Thumb::Thumb16(Thumb16::Pop(list)) => list.0 & (1 << 15) != 0,
+        _ => false,
+    }
+}
+
+#[test]
+fn test_breakpoints_and_watchpoints() {
+    use super::{build, StopReason};
+    use crate::{ast::Register, Program};
+
+    let chunk: &[u8] = &[
+        0x01, 0x20, // movs	r0, #1
+        0x08, 0x60, // str	r0, [r1]
+        0x4a, 0x88, // ldrh	r2, [r1, #2]
+        0x00, 0xbf, // nop
+        0x00, 0xbe, // bkpt	#0
+    ];
+    let mut cpu = build();
+    cpu.load_program(&Program::build(chunk, 0x100, 0x20001000)).unwrap();
+    cpu.set_register(Register::PC, 0x100);
+    cpu.set_register(Register::R1, 0x20000000);
+
+    cpu.add_breakpoint(0x109);
+    cpu.add_watchpoint(0x20000003, 1, WatchKind::Write);
+    cpu.add_watchpoint(0x20000002, 2, WatchKind::Read);
+    // stops after the store, with the address in the watched range
+    assert_eq!(cpu.run(None), StopReason::Watchpoint(WatchKind::Write, 0x20000003));
+    assert_eq!(cpu.get_register(Register::PC), 0x104);
+    assert_eq!(cpu.run(None), StopReason::Watchpoint(WatchKind::Read, 0x20000002));
+    assert_eq!(cpu.get_register(Register::R2), 0);
+    assert!(cpu.remove_watchpoint(0x20000002, 2, WatchKind::Read));
+    assert!(!cpu.remove_watchpoint(0x20000002, 2, WatchKind::Read));
+
+    // stops before the instruction, and runs it when resumed from there
+    assert_eq!(cpu.run(None), StopReason::AddressBreakpoint(0x108));
+    assert_eq!(cpu.get_register(Register::PC), 0x108);
+    assert!(cpu.has_breakpoint(0x108));
+    assert_eq!(cpu.run(None), StopReason::Breakpoint(0));
+    assert!(cpu.remove_breakpoint(0x108));
+}
+
+#[test]
+fn test_stepping() {
+    use super::build;
+    use crate::Program;
+
+    let chunk: &[u8] = &[
+        0x00, 0xf0, 0x04, 0xf8, // bl	0x10c <func>
+        0x01, 0x21, // movs	r1, #1
+        0x00, 0xbe, // bkpt	#0
+        0x00, 0xbf, // nop
+        0x00, 0xbf, // nop
+        // func
+        0x00, 0xb5, // push	{lr}
+        0x00, 0xf0, 0x02, 0xf8, // bl	0x116 <leaf>
+        0x00, 0xbd, // pop	{pc}
+        0x00, 0xbf, // nop
+        // leaf
+        0x01, 0x30, // adds	r0, #1
+        0x70, 0x47, // bx	lr
+    ];
+    let mut cpu = build();
+    cpu.load_program(&Program::build(chunk, 0x100, 0x20001000)).unwrap();
+    let options = RunOptions::default();
+
+    // over the whole call
+    cpu.set_register(Register::PC, 0x100);
+    assert_eq!(cpu.step_over(&options), StopReason::StepComplete);
+    assert_eq!(cpu.get_register(Register::PC), 0x104);
+    assert_eq!(cpu.get_register(Register::R0), 1);
+    // not a call, a single step
+    assert_eq!(cpu.step_over(&options), StopReason::StepComplete);
+    assert_eq!(cpu.get_register(Register::PC), 0x106);
+
+    // into func and leaf, then out of both
+    cpu.set_register(Register::PC, 0x100);
+    cpu.step().unwrap();
+    cpu.step().unwrap();
+    cpu.step().unwrap();
+    assert_eq!(cpu.get_register(Register::PC), 0x116);
+    assert_eq!(cpu.step_out(&options), StopReason::StepComplete);
+    assert_eq!(cpu.get_register(Register::PC), 0x112);
+    assert_eq!(cpu.step_out(&options), StopReason::StepComplete);
+    assert_eq!(cpu.get_register(Register::PC), 0x104);
+    assert_eq!(cpu.get_register(Register::R0), 2);
+
+    // a call from func is stepped out of as a whole
+    cpu.set_register(Register::PC, 0x100);
+    cpu.step().unwrap();
+    assert_eq!(cpu.step_out(&options), StopReason::StepComplete);
+    assert_eq!(cpu.get_register(Register::PC), 0x104);
+
+    // breakpoints still stop
+    cpu.set_register(Register::PC, 0x100);
+    cpu.add_breakpoint(0x118);
+    assert_eq!(cpu.step_over(&options), StopReason::AddressBreakpoint(0x118));
+    cpu.remove_breakpoint(0x118);
+
+    // the limits of the options still stop
+    let limited = RunOptions { max_instructions: Some(2), ..RunOptions::default() };
+    cpu.set_register(Register::PC, 0x100);
+    assert_eq!(cpu.step_over(&limited), StopReason::InstructionLimit);
+    assert_eq!(cpu.get_register(Register::PC), 0x10e);
+    assert_eq!(cpu.step_out(&limited), StopReason::InstructionLimit);
+    assert_eq!(cpu.get_register(Register::PC), 0x118);
+
+    cpu.set_register(Register::PC, 0x100);
+    assert_eq!(cpu.run_until(0x112, &options), StopReason::AddressReached(0x112));
+    assert_eq!(cpu.run_until(0x112, &limited), StopReason::InstructionLimit);
+    assert_eq!(cpu.get_register(Register::PC), 0x106);
+    // already there, the next time it is reached
+    assert_eq!(cpu.run_until(0x106, &options), StopReason::Breakpoint(0));
+}
+
+#[test]
+fn test_breakpoint_on_handler() {
+    use super::build;
+    use crate::Program;
+
+    let chunk: &[u8] = &[
+        0x00, 0xbf, // nop
+        0x00, 0xbe, // bkpt	#0
+        // PendSV
+        0x01, 0xbe, // bkpt	#1
+    ];
+    let mut cpu = build();
+    cpu.load_program(&Program::build(chunk, 0x100, 0x20001000)).unwrap();
+    cpu.memmory.write_chunk(0x38, &u32::to_le_bytes(0x105)).unwrap();
+    cpu.set_register(Register::PC, 0x100);
+    cpu.write_memmory(0xe000ed04, Size::Word, 1 << 28).unwrap();
+
+    // taken before the nop, stopping on the entry to the handler
+    cpu.add_breakpoint(0x104);
+    assert_eq!(cpu.run(None), StopReason::AddressBreakpoint(0x104));
+    assert_eq!(cpu.get_register(Register::PC), 0x104);
+    assert_eq!(cpu.run(None), StopReason::Breakpoint(1));
+}
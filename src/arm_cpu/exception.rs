@@ -0,0 +1,429 @@
+// The ARMv6-M exception model, B1.5. Exceptions are entered by stacking an 8 word frame and
+// fetching the handler from the vector table at VTOR, and left by branching to EXC_RETURN.
+use crate::ast::Register;
+
+use super::{arm_memmory::Fault, Cpu, Mode, Xpsr, CONTROL_SPSEL};
+
+// exception numbers above 16 are external interrupts, the IPSR holds 6 bits
+pub const EXCEPTION_COUNT: usize = 48;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Exception {
+    Reset,
+    NMI,
+    HardFault,
+    SVCall,
+    PendSV,
+    SysTick,
+    // external interrupt 0 to 31
+    Irq(u32),
+}
+
+impl Exception {
+    pub fn number(&self) -> u32 {
+        match self {
+            Exception::Reset => 1,
+            Exception::NMI => 2,
+            Exception::HardFault => 3,
+            Exception::SVCall => 11,
+            Exception::PendSV => 14,
+            Exception::SysTick => 15,
+            Exception::Irq(n) => 16 + n,
+        }
+    }
+
+    pub fn from_number(number: u32) -> Option<Exception> {
+        match number {
+            1 => Some(Exception::Reset),
+            2 => Some(Exception::NMI),
+            3 => Some(Exception::HardFault),
+            11 => Some(Exception::SVCall),
+            14 => Some(Exception::PendSV),
+            15 => Some(Exception::SysTick),
+            16..=47 => Some(Exception::Irq(number - 16)),
+            _ => None,
+        }
+    }
+}
+
+// Why a HardFault was taken. ARMv6-M has no configurable faults, every fault escalates to
+// HardFault and a fault that can not be handled locks the processor up.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HardFault {
+    // a memory access faulted
+    Bus(Fault),
+    // UDF with its immediate
+    Undefined(u32),
+    // an instruction was executed with EPSR.T cleared
+    InvalidState,
+    // a branch to an EXC_RETURN value that does not return from the active exception
+    InvalidReturn(u32),
+    // SVC at a priority that masks SVCall
+    Svc,
+}
+
+impl From<Fault> for HardFault {
+    fn from(fault: Fault) -> HardFault {
+        HardFault::Bus(fault)
+    }
+}
+
+impl std::fmt::Display for HardFault {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            HardFault::Bus(fault) => write!(f, "{fault}"),
+            HardFault::Undefined(imm32) => write!(f, "undefined instruction #{imm32}"),
+            HardFault::InvalidState => write!(f, "executed with the T bit cleared"),
+            HardFault::InvalidReturn(exc_return) => write!(f, "invalid exception return {exc_return:#x}"),
+            HardFault::Svc => write!(f, "SVC with SVCall masked"),
+        }
+    }
+}
+
+impl Cpu {
+    // The priority below which exceptions are masked, an exception only preempts with a
+    // lower priority number. PRIMASK boosts it to 0 masking everything configurable.
+    pub fn get_execution_priority(&self) -> i32 {
+        /*
+        integer ExecutionPriority()
+            highestpri = 4; // priority of Thread mode with no active exceptions
+                            // the value is PriorityMax + 1 = 4
+            boostedpri = 4; // value is PriorityMax + 1
+            for (i = 2; i < 48; i = i+1) // IPSR values of the exceptions
+                if ExceptionActive[i] == ‘1’ then
+                    if ExceptionPriority(i) < highestpri then
+                        highestpri = ExceptionPriority(i);
+            if PRIMASK<0> == ‘1’ then
+                boostedpri = 0;
+            if boostedpri < highestpri then
+                priority = boostedpri;
+            else
+                priority = highestpri;
+            return priority;
+        */
+        let highestpri = (2..EXCEPTION_COUNT as u32)
+            .filter(|x| self.exception_active[*x as usize])
+            .map(|x| self.exception_priority(x))
+            .fold(4, i32::min);
+        let boostedpri = if self.primask & 0b1 == 1 { 0 } else { 4 };
+        boostedpri.min(highestpri)
+    }
+
+    fn exception_priority(&self, number: u32) -> i32 {
+        self.nvic.borrow().exception_priority(number)
+    }
+
+    pub fn is_exception_active(&self, exception: Exception) -> bool {
+        self.exception_active[exception.number() as usize]
+    }
+
+    pub fn is_exception_pending(&self, exception: Exception) -> bool {
+        self.nvic.borrow().is_pending(exception)
+    }
+
+    // the pending exception to take now, if any has a high enough priority to preempt
+    pub(super) fn pending_exception(&self) -> Option<Exception> {
+        let (number, priority) = self.nvic.borrow().highest_pending()?;
+        if priority < self.get_execution_priority() {
+            Exception::from_number(number)
+        } else {
+            None
+        }
+    }
+
+    // Takes a HardFault for the instruction at return_address. A fault at the priority of
+    // HardFault or NMI, or while stacking for HardFault, locks up.
+    pub(super) fn take_hard_fault(&mut self, cause: HardFault, return_address: u32) -> Result<(), HardFault> {
+        if self.get_execution_priority() < 0 {
+            return Err(cause);
+        }
+        self.exception_entry(Exception::HardFault, return_address)
+    }
+
+    pub(super) fn call_supervisor(&mut self, return_address: u32) -> Result<(), HardFault> {
+        // SVC must be taken straight away, when it can not preempt it escalates to HardFault
+        if self.exception_priority(Exception::SVCall.number()) >= self.get_execution_priority() {
+            return Err(HardFault::Svc);
+        }
+        self.exception_entry(Exception::SVCall, return_address)
+    }
+
+    pub(super) fn exception_entry(&mut self, exception: Exception, return_address: u32) -> Result<(), HardFault> {
+        /*
+        ExceptionEntry()
+            // NOTE: PushStack() can abandon memory accesses if a fault occurs during the stacking
+            // sequence.
+            PushStack();
+            ExceptionTaken(ExceptionNumber);
+        */
+        self.push_stack(return_address)?;
+        self.exception_taken(exception)
+    }
+
+    fn push_stack(&mut self, return_address: u32) -> Result<(), HardFault> {
+        /*
+        PushStack()
+            if CONTROL.SPSEL == ‘1’ AND CurrentMode == Mode_Thread then
+                frameptralign = SP_process<2>;
+                SP_process = (SP_process - 0x20) AND NOT(ZeroExtend(‘100’,32));
+                frameptr = SP_process;
+            else
+                frameptralign = SP_main<2>;
+                SP_main = (SP_main - 0x20) AND NOT(ZeroExtend(‘100’,32));
+                frameptr = SP_main;
+            // only the stack locations, not the store order, are architected
+            MemA[frameptr,4] = R[0];
+            MemA[frameptr+0x4,4] = R[1];
+            MemA[frameptr+0x8,4] = R[2];
+            MemA[frameptr+0xC,4] = R[3];
+            MemA[frameptr+0x10,4] = R[12];
+            MemA[frameptr+0x14,4] = LR;
+            MemA[frameptr+0x18,4] = ReturnAddress();
+            MemA[frameptr+0x1C,4] = (XPSR<31:10>:frameptralign:XPSR<8:0>);
+            if CurrentMode==Mode_Handler then
+                LR = 0xFFFFFFF1<31:0>;
+            else
+                if CONTROL.SPSEL == ‘0’ then
+                    LR = 0xFFFFFFF9<31:0>;
+                else
+                    LR = 0xFFFFFFFD<31:0>;
+        */
+        let process = self.control & CONTROL_SPSEL != 0 && self.mode == Mode::Thread;
+        let sp = if process { self.sp_process } else { self.sp_main };
+        let frameptralign = (sp >> 2) & 0b1;
+        let frameptr = sp.wrapping_sub(0x20) & !0b100;
+
+        let frame = [
+            self.registers[0],
+            self.registers[1],
+            self.registers[2],
+            self.registers[3],
+            self.registers[12],
+            self.registers[14],
+            return_address,
+            (self.xpsr.0 & !(1 << 9)) | (frameptralign << 9),
+        ];
+        for (i, value) in frame.iter().enumerate() {
+            self.memmory.write_u32(frameptr.wrapping_add(4 * i as u32), *value)?;
+        }
+        if process {
+            self.sp_process = frameptr;
+        } else {
+            self.sp_main = frameptr;
+        }
+
+        let exc_return = match (self.mode, process) {
+            (Mode::Handler, _) => 0xfffffff1,
+            (Mode::Thread, false) => 0xfffffff9,
+            (Mode::Thread, true) => 0xfffffffd,
+        };
+        self.write_register(Register::LR, exc_return);
+        Ok(())
+    }
+
+    fn exception_taken(&mut self, exception: Exception) -> Result<(), HardFault> {
+        /*
+        ExceptionTaken(integer ExceptionNumber)
+            bit tbit;
+            bits(32) tmp;
+            for i = 0 to 3
+                R[i] = bits(32) UNKNOWN;
+            R[12] = bits(32) UNKNOWN;
+            bits(32) vectortable = VTOR<31:7>:‘0000000’;
+            tmp = MemA[vectortable+4*ExceptionNumber,4];
+            BranchTo(tmp AND 0xFFFFFFFE<31:0>);
+            tbit = tmp<0>;
+            CurrentMode = Mode_Handler;
+            APSR = bits(32) UNKNOWN; // Flags UNPREDICTABLE due to other activations
+            IPSR<5:0> = ExceptionNumber<5:0>; // ExceptionNumber set in IPSR
+            EPSR.T = tbit; // T-bit set from vector
+            EPSR.IT<7:0> = 0x0; // IT/ICI bits cleared
+            // PRIMASK, FAULTMASK, BASEPRI unchanged on exception entry
+            CONTROL.FPCA = ‘0’; // Mark Floating-point inactive
+            CONTROL.SPSEL = ‘0’; // current Stack is Main, CONTROL.nPRIV unchanged
+            // CONTROL.nPRIV unchanged
+            ExceptionActive[ExceptionNumber] = ‘1’; // exception is now active
+            SCS_UpdateStatusRegs(); // update SCS registers as appropriate
+            ClearExclusiveLocal();
+            SetEventRegister(); // see WFE instruction for more details
+            InstructionSynchronizationBarrier();
+        */
+        let number = exception.number();
+        self.nvic.borrow_mut().clear_pending(exception);
+        let vectortable = self.vtor & !0x7f;
+        let tmp = self.memmory.read_u32(vectortable.wrapping_add(4 * number))?;
+        self.write_register(Register::PC, tmp);
+
+        self.mode = Mode::Handler;
+        self.xpsr.set_ipsr(number);
+        self.xpsr.set(Xpsr::T, tmp & 0b1 == 1);
+        self.control &= !CONTROL_SPSEL;
+        self.exception_active[number as usize] = true;
+        Ok(())
+    }
+
+    pub(super) fn exception_return(&mut self, exc_return: u32) -> Result<(), HardFault> {
+        /*
+        ExceptionReturn(bits(28) EXC_RETURN)
+            assert CurrentMode == Mode_Handler;
+            if !IsOnes(EXC_RETURN<27:4>) then UNPREDICTABLE;
+            integer ReturningExceptionNumber = UInt(IPSR<5:0>);
+            integer NestedActivation; // used for Handler => Thread check when value == 1
+            NestedActivation = ExceptionActiveBitCount(); // Number of active exceptions
+            if ExceptionActive[ReturningExceptionNumber] == ‘0’ then UNPREDICTABLE;
+            case EXC_RETURN<3:0> of
+                when ‘0001’ // return to Handler
+                    if NestedActivation == 1 then UNPREDICTABLE;
+                    frameptr = SP_main;
+                    CurrentMode = Mode_Handler;
+                    CONTROL.SPSEL = ‘0’;
+                when ‘1001’ // returning to Thread using Main stack
+                    if NestedActivation != 1 then UNPREDICTABLE;
+                    frameptr = SP_main;
+                    CurrentMode = Mode_Thread;
+                    CONTROL.SPSEL = ‘0’;
+                when ‘1101’ // returning to Thread using Process stack
+                    if NestedActivation != 1 then UNPREDICTABLE;
+                    frameptr = SP_process;
+                    CurrentMode = Mode_Thread;
+                    CONTROL.SPSEL = ‘1’;
+                otherwise
+                    UNPREDICTABLE;
+            DeActivate(ReturningExceptionNumber);
+            PopStack(frameptr, EXC_RETURN);
+            if CurrentMode==Mode_Handler AND IPSR<5:0> == ‘000000’ then UNPREDICTABLE;
+            if CurrentMode==Mode_Thread AND IPSR<5:0> != ‘000000’ then UNPREDICTABLE;
+            ClearExclusiveLocal();
+            SetEventRegister(); // see WFE instruction for more details
+            InstructionSynchronizationBarrier();
+            if CurrentMode==Mode_Thread AND NestedActivation == 0 AND SCR.SLEEPONEXIT == ‘1’ then
+                SleepOnExit(); // IMPLEMENTATION DEFINED
+        */
+        // the UNPREDICTABLE cases fault like the Cortex-M0 does
+        let returning = self.xpsr.ipsr() as usize;
+        let nested_activation = self.exception_active.iter().filter(|x| **x).count();
+        if exc_return & 0x0ffffff0 != 0x0ffffff0 || !self.exception_active[returning] {
+            return Err(HardFault::InvalidReturn(exc_return));
+        }
+        let (frameptr, mode, spsel) = match exc_return & 0xf {
+            0b0001 if nested_activation != 1 => (self.sp_main, Mode::Handler, false),
+            0b1001 if nested_activation == 1 => (self.sp_main, Mode::Thread, false),
+            0b1101 if nested_activation == 1 => (self.sp_process, Mode::Thread, true),
+            _ => return Err(HardFault::InvalidReturn(exc_return)),
+        };
+        self.exception_active[returning] = false;
+
+        // Tail-chaining, a pending exception that would preempt the code returned to is taken
+        // straight away on the frame still stacked and with the same EXC_RETURN.
+        if let Some(exception) = self.pending_exception() {
+            self.write_register(Register::LR, exc_return);
+            return self.exception_taken(exception);
+        }
+
+        self.mode = mode;
+        if spsel {
+            self.control |= CONTROL_SPSEL;
+        } else {
+            self.control &= !CONTROL_SPSEL;
+        }
+        self.pop_stack(frameptr, exc_return)?;
+
+        if (self.mode == Mode::Handler) == (self.xpsr.ipsr() == 0) {
+            return Err(HardFault::InvalidReturn(exc_return));
+        }
+        Ok(())
+    }
+
+    fn pop_stack(&mut self, frameptr: u32, exc_return: u32) -> Result<(), HardFault> {
+        /*
+        PopStack(bits(32) frameptr, bits(28) EXC_RETURN)
+            // only stack locations, not the load order, are architected
+            R[0] = MemA[frameptr,4];
+            R[1] = MemA[frameptr+0x4,4];
+            R[2] = MemA[frameptr+0x8,4];
+            R[3] = MemA[frameptr+0xC,4];
+            R[12] = MemA[frameptr+0x10,4];
+            LR = MemA[frameptr+0x14,4];
+            PC = MemA[frameptr+0x18,4]; // UNPREDICTABLE if the new PC not halfword aligned
+            psr = MemA[frameptr+0x1C,4];
+            case EXC_RETURN<3:0> of
+                when ‘0001’ // returning to Handler
+                    SP_main = (SP_main + 0x20) OR ZeroExtend(psr<9>:‘00’,32);
+                when ‘1001’ // returning to Thread using Main stack
+                    SP_main = (SP_main + 0x20) OR ZeroExtend(psr<9>:‘00’,32);
+                when ‘1101’ // returning to Thread using Process stack
+                    SP_process = (SP_process + 0x20) OR ZeroExtend(psr<9>:‘00’,32);
+            APSR<31:28> = psr<31:28>;
+            IPSR<5:0> = psr<5:0>;
+            EPSR.T = psr<24>;
+        */
+        let mut frame = [0; 8];
+        for (i, value) in frame.iter_mut().enumerate() {
+            *value = self.memmory.read_u32(frameptr.wrapping_add(4 * i as u32))?;
+        }
+        let [r0, r1, r2, r3, r12, lr, pc, psr] = frame;
+        self.registers[0] = r0;
+        self.registers[1] = r1;
+        self.registers[2] = r2;
+        self.registers[3] = r3;
+        self.registers[12] = r12;
+        self.write_register(Register::LR, lr);
+        self.write_register(Register::PC, pc);
+
+        let sp = frameptr.wrapping_add(0x20) | (((psr >> 9) & 0b1) << 2);
+        if exc_return & 0xf == 0b1101 {
+            self.sp_process = sp;
+        } else {
+            self.sp_main = sp;
+        }
+        self.xpsr.0 = (self.xpsr.0 & !Xpsr::APSR) | (psr & Xpsr::APSR);
+        self.xpsr.set_ipsr(psr);
+        self.xpsr.set(Xpsr::T, psr & Xpsr::T != 0);
+        Ok(())
+    }
+}
+
+#[test]
+fn test_exception_entry_return() {
+    use super::{build, StopReason};
+    use crate::Program;
+
+    let chunk: &[u8] = &[
+        0x05, 0xdf, // svc	5
+        0x01, 0x30, // adds	r0, #1
+        0x01, 0xde, // udf	#1
+        // SVCall
+        0x07, 0x24, // movs	r4, #7
+        0x70, 0x47, // bx	lr
+        // HardFault
+        0x00, 0xbe, // bkpt	#0
+    ];
+    let mut cpu = build();
+    cpu.load_program(&Program::build(chunk, 0x100, 0x20001000)).unwrap();
+    cpu.memmory.write_chunk(0xc, &u32::to_le_bytes(0x10b)).unwrap();
+    cpu.memmory.write_chunk(0x2c, &u32::to_le_bytes(0x107)).unwrap();
+    // thread mode on a process stack that is not 8 byte aligned
+    cpu.control = CONTROL_SPSEL;
+    cpu.sp_process = 0x20000804;
+    cpu.set_register(Register::R0, 1);
+
+    assert_eq!(cpu.start(0x100), StopReason::Breakpoint(0));
+    // the SVC handler ran and returned to the instruction after the svc
+    assert_eq!(cpu.get_register(Register::R4), 7);
+    assert_eq!(cpu.get_register(Register::R0), 2);
+    assert!(!cpu.is_exception_active(Exception::SVCall));
+
+    // udf took a HardFault stacked on the aligned process stack
+    assert!(cpu.is_exception_active(Exception::HardFault));
+    assert_eq!(cpu.get_mode(), Mode::Handler);
+    assert_eq!(cpu.xpsr.ipsr(), 3);
+    assert_eq!(cpu.get_register(Register::LR), 0xfffffffd);
+    assert_eq!(cpu.sp_process, 0x200007e0);
+    assert_eq!(cpu.read_u32(0x200007e0), Ok(2));
+    assert_eq!(cpu.read_u32(0x200007f8), Ok(0x104));
+    // the stacked xPSR records the alignment in bit 9
+    assert_eq!(cpu.read_u32(0x200007fc), Ok(Xpsr::T | (1 << 9)));
+    // handlers run on the main stack
+    assert_eq!(cpu.control & CONTROL_SPSEL, 0);
+    assert_eq!(cpu.get_register(Register::SP), 0x20001000);
+}
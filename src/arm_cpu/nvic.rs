@@ -0,0 +1,162 @@
+// B3.4 the Nested Vectored Interrupt Controller. The enable, pending and priority state of the
+// exceptions is shared between the processor taking them and the memory mapped registers.
+use std::{cell::RefCell, rc::Rc};
+
+use super::{
+    arm_memmory::Size,
+    exception::{Exception, EXCEPTION_COUNT},
+    peripheral::Peripheral,
+};
+
+pub const NVIC_START: u32 = 0xe000e100;
+// ISER to the last IPR
+pub const NVIC_SIZE: u32 = 0x320;
+
+const ISER: u32 = 0x000;
+const ICER: u32 = 0x080;
+const ISPR: u32 = 0x100;
+const ICPR: u32 = 0x180;
+const IPR_START: u32 = 0x300;
+const IPR_END: u32 = 0x31c;
+
+// external interrupts start at exception 16
+const IRQ_OFFSET: usize = 16;
+
+#[derive(Debug)]
+pub struct Nvic {
+    // one bit per external interrupt
+    enabled: u32,
+    // by exception number
+    pending: [bool; EXCEPTION_COUNT],
+    // by exception number as written, only bits [7:6] are implemented
+    priority: [u8; EXCEPTION_COUNT],
+}
+
+impl Nvic {
+    pub fn new() -> Nvic {
+        Nvic { enabled: 0, pending: [false; EXCEPTION_COUNT], priority: [0; EXCEPTION_COUNT] }
+    }
+
+    pub fn set_pending(&mut self, exception: Exception) {
+        self.pending[exception.number() as usize] = true;
+    }
+
+    pub fn clear_pending(&mut self, exception: Exception) {
+        self.pending[exception.number() as usize] = false;
+    }
+
+    pub fn is_pending(&self, exception: Exception) -> bool {
+        self.pending[exception.number() as usize]
+    }
+
+    // the configurable priority of an exception, 0 to 3
+    pub fn priority(&self, number: u32) -> i32 {
+        (self.priority[number as usize] >> 6) as i32
+    }
+
+    pub fn set_priority(&mut self, number: u32, priority: u8) {
+        self.priority[number as usize] = priority & 0xc0;
+    }
+
+    // The pending exception taken next and its priority. Of the same priority the lowest
+    // exception number wins, external interrupts must also be enabled.
+    pub fn highest_pending(&self) -> Option<(u32, i32)> {
+        (1..EXCEPTION_COUNT)
+            .filter(|x| self.pending[*x])
+            .filter(|x| *x < IRQ_OFFSET || self.enabled & (1 << (x - IRQ_OFFSET)) != 0)
+            .map(|x| (x as u32, self.exception_priority(x as u32)))
+            .min_by_key(|(number, priority)| (*priority, *number))
+    }
+
+    // the fixed priorities of Reset, NMI and HardFault, the rest are configurable
+    pub fn exception_priority(&self, number: u32) -> i32 {
+        match number {
+            1 => -3,
+            2 => -2,
+            3 => -1,
+            _ => self.priority(number),
+        }
+    }
+
+    pub fn pending_irqs(&self) -> u32 {
+        (0..32).filter(|x| self.pending[IRQ_OFFSET + x]).fold(0, |a, x| a | (1 << x))
+    }
+}
+
+impl Default for Nvic {
+    fn default() -> Nvic {
+        Nvic::new()
+    }
+}
+
+// the NVIC registers at 0xE000E100
+pub struct NvicRegisters(pub Rc<RefCell<Nvic>>);
+
+impl Peripheral for NvicRegisters {
+    fn read(&mut self, offset: u32, size: Size) -> u32 {
+        let nvic = self.0.borrow();
+        let word = match offset & !0b11 {
+            ISER | ICER => nvic.enabled,
+            ISPR | ICPR => nvic.pending_irqs(),
+            IPR_START..=IPR_END => {
+                let irq = (offset & !0b11) - IPR_START;
+                (0..4).fold(0, |a, i| a | (nvic.priority[IRQ_OFFSET + (irq + i) as usize] as u32) << (8 * i))
+            }
+            _ => 0,
+        };
+        (word >> (8 * (offset & 0b11))) & size_mask(size)
+    }
+
+    fn write(&mut self, offset: u32, size: Size, value: u32) {
+        let mut nvic = self.0.borrow_mut();
+        let shift = 8 * (offset & 0b11);
+        let mask = size_mask(size) << shift;
+        let value = (value << shift) & mask;
+        match offset & !0b11 {
+            // writing 1 sets or clears, writing 0 has no effect
+            ISER => nvic.enabled |= value,
+            ICER => nvic.enabled &= !value,
+            ISPR | ICPR => {
+                let pending = (offset & !0b11) == ISPR;
+                for irq in (0..32).filter(|x| value & (1 << x) != 0) {
+                    nvic.pending[IRQ_OFFSET + irq] = pending;
+                }
+            }
+            IPR_START..=IPR_END => {
+                let irq = (offset & !0b11) - IPR_START;
+                for i in (0..4).filter(|x| mask & (0xff << (8 * x)) != 0) {
+                    nvic.set_priority(IRQ_OFFSET as u32 + irq + i, (value >> (8 * i)) as u8);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+pub fn size_mask(size: Size) -> u32 {
+    match size {
+        Size::Word => 0xffffffff,
+        _ => (1 << (8 * size as u32)) - 1,
+    }
+}
+
+#[test]
+fn test_nvic_registers() {
+    let nvic = Rc::new(RefCell::new(Nvic::new()));
+    let mut registers = NvicRegisters(nvic.clone());
+
+    registers.write(ISER, Size::Word, 0b1010);
+    registers.write(ICER, Size::Word, 0b1000);
+    assert_eq!(registers.read(ICER, Size::Word), 0b10);
+
+    registers.write(ISPR, Size::Word, 0b0110);
+    registers.write(ICPR, Size::Word, 0b0100);
+    assert_eq!(registers.read(ISPR, Size::Word), 0b10);
+    assert!(nvic.borrow().is_pending(Exception::Irq(1)));
+
+    // IPR1 holds IRQ 4 to 7, only the top 2 bits of each byte are kept
+    registers.write(IPR_START + 4, Size::Word, 0xffc04000);
+    registers.write(IPR_START + 5, Size::Byte, 0x80);
+    assert_eq!(registers.read(IPR_START + 4, Size::Word), 0xc0c08000);
+    assert_eq!(nvic.borrow().priority(Exception::Irq(5).number()), 2);
+}
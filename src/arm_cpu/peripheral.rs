@@ -0,0 +1,23 @@
+use super::arm_memmory::Size;
+
+// A memory mapped device attached to an address range with `Cpu::attach_peripheral`.
+//
+// Offsets are from the start of the range. Every load and store of the processor is passed on
+// as a single access of its size, so reads and writes can have side effects like clearing a
+// status flag.
+pub trait Peripheral {
+    fn read(&mut self, offset: u32, size: Size) -> u32;
+
+    fn write(&mut self, offset: u32, size: Size, value: u32);
+
+    // called after every instruction with the cycles it took
+    fn tick(&mut self, _cycles: u32) {}
+
+    // whether ticking can still pend an exception, like an enabled timer interrupt
+    fn can_interrupt(&self) -> bool {
+        false
+    }
+
+    // called when the processor resets
+    fn reset(&mut self) {}
+}
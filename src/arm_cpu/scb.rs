@@ -0,0 +1,235 @@
+// B3.2 the System Control Block at 0xE000ED00. Unlike the NVIC and SysTick its registers are
+// views of the processor itself, the active exception, the vector table and reset, so accesses
+// are handled by the Cpu rather than a peripheral in memmory.
+use super::{
+    arm_memmory::{Fault, Size},
+    exception::Exception,
+    nvic::size_mask,
+    Cpu,
+};
+
+pub const SCB_START: u32 = 0xe000ed00;
+// CPUID to SHPR3
+pub const SCB_SIZE: u32 = 0x24;
+
+const CPUID: u32 = 0x00;
+const ICSR: u32 = 0x04;
+const VTOR: u32 = 0x08;
+const AIRCR: u32 = 0x0c;
+const SCR: u32 = 0x10;
+const CCR: u32 = 0x14;
+const SHPR2: u32 = 0x1c;
+const SHPR3: u32 = 0x20;
+
+// ICSR bits
+const NMIPENDSET: u32 = 1 << 31;
+const PENDSVSET: u32 = 1 << 28;
+const PENDSVCLR: u32 = 1 << 27;
+const PENDSTSET: u32 = 1 << 26;
+const PENDSTCLR: u32 = 1 << 25;
+const ISRPENDING: u32 = 1 << 22;
+const VECTPENDING_SHIFT: u32 = 12;
+
+// AIRCR, writes are ignored without the key, reads return its complement
+const VECTKEY: u32 = 0x05fa;
+const VECTKEYSTAT: u32 = 0xfa05 << 16;
+const SYSRESETREQ: u32 = 1 << 2;
+
+// SCR, SEVONPEND, SLEEPDEEP and SLEEPONEXIT
+const SCR_MASK: u32 = 0b10110;
+// CCR is read only, STKALIGN and UNALIGN_TRP are always set in ARMv6-M
+const CCR_VALUE: u32 = (1 << 9) | (1 << 3);
+// VTOR.TBLOFF, the table is 128 byte aligned
+const TBLOFF_MASK: u32 = 0xffffff80;
+
+// the system handlers with a configurable priority
+const SVCALL: u32 = 11;
+const PENDSV: u32 = 14;
+const SYSTICK: u32 = 15;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Core {
+    CortexM0,
+    CortexM0Plus,
+}
+
+impl Core {
+    // implementer ARM, architecture ARMv6-M, the part number and revision
+    pub fn cpuid(&self) -> u32 {
+        match self {
+            // r0p0
+            Core::CortexM0 => 0x410cc200,
+            // r0p1
+            Core::CortexM0Plus => 0x410cc601,
+        }
+    }
+
+    // VTOR is optional and the Cortex-M0 does not implement it, there the register reads as
+    // zero and vector table relocation is left to memory remapping
+    fn has_vtor(&self) -> bool {
+        *self == Core::CortexM0Plus
+    }
+}
+
+impl Cpu {
+    pub fn set_core(&mut self, core: Core) {
+        self.core = core;
+    }
+
+    pub fn get_core(&self) -> Core {
+        self.core
+    }
+
+    pub(super) fn read_scb(&self, offset: u32, size: Size) -> u32 {
+        let word = match offset & !0b11 {
+            CPUID => self.core.cpuid(),
+            ICSR => self.icsr(),
+            VTOR if self.core.has_vtor() => self.vtor,
+            AIRCR => VECTKEYSTAT,
+            SCR => self.scr,
+            CCR => CCR_VALUE,
+            SHPR2 | SHPR3 => {
+                let first = 8 + (offset & !0b11) - SHPR2;
+                let nvic = self.nvic.borrow();
+                (0..4).fold(0, |a, i| a | ((nvic.priority(first + i) as u32) << 6) << (8 * i))
+            }
+            _ => 0,
+        };
+        (word >> (8 * (offset & 0b11))) & size_mask(size)
+    }
+
+    pub(super) fn write_scb(&mut self, offset: u32, size: Size, value: u32) -> Result<(), Fault> {
+        let shift = 8 * (offset & 0b11);
+        let mask = size_mask(size) << shift;
+        let value = (value << shift) & mask;
+        match offset & !0b11 {
+            ICSR => {
+                // writing 0 has no effect, setting and clearing together leaves it cleared
+                let mut nvic = self.nvic.borrow_mut();
+                if value & NMIPENDSET != 0 {
+                    nvic.set_pending(Exception::NMI);
+                }
+                if value & PENDSVSET != 0 {
+                    nvic.set_pending(Exception::PendSV);
+                }
+                if value & PENDSVCLR != 0 {
+                    nvic.clear_pending(Exception::PendSV);
+                }
+                if value & PENDSTSET != 0 {
+                    nvic.set_pending(Exception::SysTick);
+                }
+                if value & PENDSTCLR != 0 {
+                    nvic.clear_pending(Exception::SysTick);
+                }
+            }
+            VTOR if self.core.has_vtor() => self.vtor = (self.vtor & !mask) | (value & TBLOFF_MASK),
+            // a system reset request resets the processor straight away
+            AIRCR if value >> 16 == VECTKEY && value & SYSRESETREQ != 0 => return self.reset(),
+            SCR => self.scr = (self.scr & !mask) | (value & SCR_MASK),
+            SHPR2 | SHPR3 => {
+                let first = 8 + (offset & !0b11) - SHPR2;
+                let mut nvic = self.nvic.borrow_mut();
+                for i in (0..4).filter(|x| mask & (0xff << (8 * x)) != 0) {
+                    if matches!(first + i, SVCALL | PENDSV | SYSTICK) {
+                        nvic.set_priority(first + i, (value >> (8 * i)) as u8);
+                    }
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    // the active exception, the one that would be taken next and what is pending
+    fn icsr(&self) -> u32 {
+        let nvic = self.nvic.borrow();
+        let vectpending = nvic.highest_pending().map_or(0, |(number, _)| number);
+        let mut icsr = self.xpsr.ipsr() | (vectpending << VECTPENDING_SHIFT);
+        if nvic.pending_irqs() != 0 {
+            icsr |= ISRPENDING;
+        }
+        if nvic.is_pending(Exception::NMI) {
+            icsr |= NMIPENDSET;
+        }
+        if nvic.is_pending(Exception::PendSV) {
+            icsr |= PENDSVSET;
+        }
+        if nvic.is_pending(Exception::SysTick) {
+            icsr |= PENDSTSET;
+        }
+        icsr
+    }
+}
+
+#[test]
+fn test_scb_registers() {
+    let mut cpu = super::build();
+
+    assert_eq!(cpu.read_u32(SCB_START + CPUID), Ok(0x410cc601));
+    assert_eq!(cpu.read_u32(SCB_START + CCR), Ok(0x208));
+    assert_eq!(cpu.read_u32(SCB_START + AIRCR), Ok(0xfa050000));
+
+    cpu.write_memmory(SCB_START + VTOR, Size::Word, 0x200000ff).unwrap();
+    assert_eq!(cpu.get_vtor(), 0x20000080);
+    // no VTOR on the Cortex-M0
+    cpu.set_core(Core::CortexM0);
+    assert_eq!(cpu.read_u32(SCB_START + CPUID), Ok(0x410cc200));
+    assert_eq!(cpu.read_u32(SCB_START + VTOR), Ok(0));
+    cpu.write_memmory(SCB_START + VTOR, Size::Word, 0).unwrap();
+    assert_eq!(cpu.get_vtor(), 0x20000080);
+
+    // of the same priority the lower exception number is pending first
+    cpu.write_memmory(SCB_START + ICSR, Size::Word, PENDSVSET | PENDSTSET).unwrap();
+    assert_eq!(cpu.read_u32(SCB_START + ICSR), Ok(PENDSVSET | PENDSTSET | (14 << 12)));
+    // SysTick at priority 1, PendSV at 3
+    cpu.write_memmory(SCB_START + SHPR3, Size::Word, 0x40ffffff).unwrap();
+    assert_eq!(cpu.read_u32(SCB_START + SHPR3), Ok(0x40c00000));
+    assert_eq!(cpu.read_u32(SCB_START + ICSR), Ok(PENDSVSET | PENDSTSET | (15 << 12)));
+    cpu.write_memmory(SCB_START + SHPR2 + 3, Size::Byte, 0x80).unwrap();
+    assert_eq!(cpu.read_u32(SCB_START + SHPR2), Ok(0x80000000));
+    assert_eq!(cpu.nvic.borrow().priority(SVCALL), 2);
+
+    cpu.write_memmory(SCB_START + ICSR, Size::Word, PENDSVCLR | PENDSTCLR | NMIPENDSET).unwrap();
+    assert!(!cpu.is_exception_pending(Exception::PendSV));
+    assert!(!cpu.is_exception_pending(Exception::SysTick));
+    cpu.raise_irq(3);
+    assert_eq!(cpu.read_u32(SCB_START + ICSR), Ok(NMIPENDSET | ISRPENDING | (2 << 12)));
+
+    cpu.write_memmory(SCB_START + SCR, Size::Word, 0xffffffff).unwrap();
+    assert_eq!(cpu.read_u32(SCB_START + SCR), Ok(0b10110));
+}
+
+#[test]
+fn test_system_reset_request() {
+    use super::{build, StopReason};
+    use crate::{ast::Register, Program};
+
+    let vectors: &[u8] = &[
+        0x00, 0x10, 0x00, 0x20, // initial MSP 0x20001000
+        0x01, 0x01, 0x00, 0x00, // reset handler 0x100 with the thumb bit
+    ];
+    let chunk: &[u8] = &[
+        0x01, 0x34, // adds	r4, #1
+        0x05, 0x2c, // cmp	r4, #5
+        0x03, 0xd1, // bne	0x10e
+        0x02, 0x48, // ldr	r0, [pc, #8]
+        0x02, 0x49, // ldr	r1, [pc, #8]
+        0x01, 0x60, // str	r1, [r0]
+        0x01, 0xbe, // bkpt	#1
+        0x00, 0xbe, // bkpt	#0
+        0x0c, 0xed, 0x00, 0xe0, // AIRCR
+        0x04, 0x00, 0xfa, 0x05, // VECTKEY and SYSRESETREQ
+    ];
+    let mut cpu = build();
+    cpu.load_program(&Program::build(vectors, 0x0, 0)).unwrap();
+    cpu.load_program(&Program::build(chunk, 0x100, 0)).unwrap();
+    cpu.reset().unwrap();
+    cpu.set_register(Register::R4, 4);
+    cpu.write_memmory(SCB_START + SCR, Size::Word, 0b100).unwrap();
+
+    // the store resets, R4 is cleared and the reset handler runs again
+    assert_eq!(cpu.run(None), StopReason::Breakpoint(0));
+    assert_eq!(cpu.get_register(Register::R4), 1);
+    assert_eq!(cpu.get_register(Register::PC), 0x10e);
+    assert_eq!(cpu.read_u32(SCB_START + SCR), Ok(0));
+}
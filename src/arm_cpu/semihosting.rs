@@ -0,0 +1,347 @@
+// ARM semihosting. BKPT 0xAB hands the operation in R0 with the parameter in R1 to the host,
+// the debugger normally, which performs it and resumes after the BKPT with the result in R0.
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, Read, Write},
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
+
+use super::{
+    arm_memmory::{Fault, Size},
+    Cpu, StopReason,
+};
+
+pub const SEMIHOSTING_BKPT: u32 = 0xab;
+
+const SYS_OPEN: u32 = 0x01;
+const SYS_CLOSE: u32 = 0x02;
+const SYS_WRITEC: u32 = 0x03;
+const SYS_WRITE0: u32 = 0x04;
+const SYS_WRITE: u32 = 0x05;
+const SYS_READ: u32 = 0x06;
+const SYS_CLOCK: u32 = 0x10;
+const SYS_TIME: u32 = 0x11;
+const SYS_EXIT: u32 = 0x18;
+const SYS_EXIT_EXTENDED: u32 = 0x20;
+
+// the reason code of SYS_EXIT for a program that finished, anything else is an error
+const ADP_STOPPED_APPLICATION_EXIT: u32 = 0x20026;
+
+// the failed result of an operation
+const ERROR: u32 = u32::MAX;
+
+// the most SYS_WRITE and SYS_READ transfer at once, the rest is left to the next call. SYS_WRITE0
+// writes no more of a string, in case it is missing its NUL.
+const MAX_TRANSFER: u32 = 0x1000;
+
+// what a handle returned by SYS_OPEN refers to
+#[derive(Debug)]
+enum Handle {
+    // ":tt" opened for reading, writing and appending
+    Stdin,
+    Stdout,
+    Stderr,
+    File(File),
+}
+
+pub struct Semihosting {
+    enabled: bool,
+    // where the program's standard output and SYS_WRITEC and SYS_WRITE0 go
+    console: Box<dyn Write>,
+    // handle n is files[n - 1], 0 is never a valid handle
+    files: Vec<Option<Handle>>,
+    // SYS_CLOCK counts from here
+    start: Instant,
+}
+
+impl Semihosting {
+    pub fn new() -> Semihosting {
+        Semihosting { enabled: true, console: Box::new(io::stdout()), files: Vec::new(), start: Instant::now() }
+    }
+
+    fn open(&mut self, handle: Handle) -> u32 {
+        let index = match self.files.iter().position(|x| x.is_none()) {
+            Some(x) => x,
+            None => {
+                self.files.push(None);
+                self.files.len() - 1
+            }
+        };
+        self.files[index] = Some(handle);
+        index as u32 + 1
+    }
+
+    fn handle(&mut self, handle: u32) -> Option<&mut Handle> {
+        self.files.get_mut((handle as usize).checked_sub(1)?)?.as_mut()
+    }
+
+    fn write(&mut self, handle: u32, data: &[u8]) -> io::Result<()> {
+        match self.handle(handle) {
+            Some(Handle::Stdout) => self.write_console(data),
+            Some(Handle::Stderr) => io::stderr().write_all(data),
+            Some(Handle::File(file)) => file.write_all(data),
+            _ => Err(io::ErrorKind::InvalidInput.into()),
+        }
+    }
+
+    fn read(&mut self, handle: u32, buffer: &mut [u8]) -> io::Result<usize> {
+        match self.handle(handle) {
+            Some(Handle::Stdin) => io::stdin().read(buffer),
+            Some(Handle::File(file)) => file.read(buffer),
+            _ => Err(io::ErrorKind::InvalidInput.into()),
+        }
+    }
+
+    fn write_console(&mut self, data: &[u8]) -> io::Result<()> {
+        self.console.write_all(data)?;
+        self.console.flush()
+    }
+}
+
+impl Default for Semihosting {
+    fn default() -> Semihosting {
+        Semihosting::new()
+    }
+}
+
+impl std::fmt::Debug for Semihosting {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Semihosting").field("enabled", &self.enabled).field("files", &self.files).finish()
+    }
+}
+
+// the fopen() modes of SYS_OPEN, r, r+, w, w+, a and a+ each with and without b
+fn open_options(mode: u32) -> Option<OpenOptions> {
+    let mut options = OpenOptions::new();
+    match mode >> 1 {
+        0 => options.read(true),
+        1 => options.read(true).write(true),
+        2 => options.write(true).create(true).truncate(true),
+        3 => options.read(true).write(true).create(true).truncate(true),
+        4 => options.append(true).create(true),
+        5 => options.read(true).append(true).create(true),
+        _ => return None,
+    };
+    Some(options)
+}
+
+impl Cpu {
+    // BKPT 0xAB is a breakpoint like any other while disabled
+    pub fn set_semihosting(&mut self, enabled: bool) {
+        self.semihosting.enabled = enabled;
+    }
+
+    pub fn semihosting_enabled(&self) -> bool {
+        self.semihosting.enabled
+    }
+
+    // where the program's standard output goes, stdout by default
+    pub fn set_console(&mut self, console: Box<dyn Write>) {
+        self.semihosting.console = console;
+    }
+
+    // Performs the operation in R0, leaving its result there. SYS_EXIT stops execution with
+    // the exit status of the program.
+    pub(super) fn semihosting_call(&mut self) -> Result<(), StopReason> {
+        let operation = self.registers[0];
+        let parameter = self.registers[1];
+        match operation {
+            // 32 bit targets pass the reason code itself, the exit status is 0 or 1 like QEMU
+            SYS_EXIT => return Err(StopReason::Exit((parameter != ADP_STOPPED_APPLICATION_EXIT) as u32)),
+            SYS_EXIT_EXTENDED => {
+                let status = match self.read_parameters::<2>(parameter) {
+                    Ok([ADP_STOPPED_APPLICATION_EXIT, subcode]) => subcode,
+                    _ => 1,
+                };
+                return Err(StopReason::Exit(status));
+            }
+            _ => {}
+        }
+        // a parameter block that can not be read fails the operation
+        self.registers[0] = self.semihosting_operation(operation, parameter).unwrap_or(ERROR);
+        Ok(())
+    }
+
+    fn semihosting_operation(&mut self, operation: u32, parameter: u32) -> Result<u32, Fault> {
+        let result = match operation {
+            SYS_OPEN => {
+                let [name, mode, length] = self.read_parameters(parameter)?;
+                if length > MAX_TRANSFER {
+                    return Ok(ERROR);
+                }
+                let name = String::from_utf8_lossy(&self.read_bytes(name, length)?).into_owned();
+                let handle = match (name.as_str(), mode) {
+                    // the console, by mode as stdin, stdout and stderr
+                    (":tt", 0..=3) => Some(Handle::Stdin),
+                    (":tt", 4..=7) => Some(Handle::Stdout),
+                    (":tt", 8..=11) => Some(Handle::Stderr),
+                    _ => open_options(mode).and_then(|x| x.open(&name).ok()).map(Handle::File),
+                };
+                handle.map_or(ERROR, |x| self.semihosting.open(x))
+            }
+            SYS_CLOSE => {
+                let [handle] = self.read_parameters(parameter)?;
+                match self.semihosting.handle(handle) {
+                    Some(_) => {
+                        self.semihosting.files[handle as usize - 1] = None;
+                        0
+                    }
+                    None => ERROR,
+                }
+            }
+            SYS_WRITEC => {
+                let c = self.memmory.read(parameter, Size::Byte)? as u8;
+                // console output can not fail the program
+                let _ = self.semihosting.write_console(&[c]);
+                0
+            }
+            SYS_WRITE0 => {
+                let mut data = Vec::new();
+                for i in 0..MAX_TRANSFER {
+                    match self.memmory.read(parameter.wrapping_add(i), Size::Byte)? as u8 {
+                        0 => break,
+                        c => data.push(c),
+                    }
+                }
+                let _ = self.semihosting.write_console(&data);
+                0
+            }
+            // both return the number of bytes not written or read
+            SYS_WRITE => {
+                let [handle, buffer, length] = self.read_parameters(parameter)?;
+                let data = self.read_bytes(buffer, length.min(MAX_TRANSFER))?;
+                match self.semihosting.write(handle, &data) {
+                    Ok(()) => length - data.len() as u32,
+                    Err(_) => length,
+                }
+            }
+            SYS_READ => {
+                let [handle, buffer, length] = self.read_parameters(parameter)?;
+                let mut data = vec![0; length.min(MAX_TRANSFER) as usize];
+                let read = self.semihosting.read(handle, &mut data).unwrap_or(0);
+                for (i, c) in data[..read].iter().enumerate() {
+                    self.memmory.write(buffer.wrapping_add(i as u32), Size::Byte, *c as u32)?;
+                }
+                length - read as u32
+            }
+            // centiseconds since execution started
+            SYS_CLOCK => (self.semihosting.start.elapsed().as_millis() / 10) as u32,
+            // seconds since the epoch
+            SYS_TIME => SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |x| x.as_secs() as u32),
+            _ => ERROR,
+        };
+        Ok(result)
+    }
+
+    // the N words of the parameter block at address
+    fn read_parameters<const N: usize>(&mut self, address: u32) -> Result<[u32; N], Fault> {
+        let mut parameters = [0; N];
+        for (i, parameter) in parameters.iter_mut().enumerate() {
+            *parameter = self.memmory.read_u32(address.wrapping_add(4 * i as u32))?;
+        }
+        Ok(parameters)
+    }
+
+    fn read_bytes(&mut self, address: u32, length: u32) -> Result<Vec<u8>, Fault> {
+        (0..length).map(|i| Ok(self.memmory.read(address.wrapping_add(i), Size::Byte)? as u8)).collect()
+    }
+}
+
+#[cfg(test)]
+struct Capture(std::rc::Rc<std::cell::RefCell<Vec<u8>>>);
+
+#[cfg(test)]
+impl Write for Capture {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().extend_from_slice(data);
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// runs the bkpt at 0x100 with the operation and parameter, R0 after it
+#[cfg(test)]
+fn call(cpu: &mut Cpu, operation: u32, parameter: u32) -> Result<u32, StopReason> {
+    use crate::ast::Register;
+
+    cpu.set_register(Register::PC, 0x100);
+    cpu.set_register(Register::R0, operation);
+    cpu.set_register(Register::R1, parameter);
+    cpu.step().map(|_| cpu.get_register(Register::R0))
+}
+
+// a call with its parameter block at 0x20000100
+#[cfg(test)]
+fn call_with(cpu: &mut Cpu, operation: u32, parameters: &[u32]) -> Result<u32, StopReason> {
+    for (i, x) in parameters.iter().enumerate() {
+        cpu.memmory.write_u32(0x20000100 + 4 * i as u32, *x).unwrap();
+    }
+    call(cpu, operation, 0x20000100)
+}
+
+#[test]
+fn test_semihosting() {
+    use std::{cell::RefCell, rc::Rc};
+
+    use super::build;
+    use crate::{ast::Register, Program};
+
+    let chunk: &[u8] = &[
+        0xab, 0xbe, // bkpt	#171
+    ];
+    let mut cpu = build();
+    cpu.load_program(&Program::build(chunk, 0x100, 0x20001000)).unwrap();
+    let console = Rc::new(RefCell::new(Vec::new()));
+    cpu.set_console(Box::new(Capture(console.clone())));
+
+    cpu.memmory.write_chunk(0x20000000, b"hi\n\0").unwrap();
+    assert_eq!(call(&mut cpu, SYS_WRITE0, 0x20000000), Ok(0));
+    // execution resumes after the bkpt, which took a cycle
+    assert_eq!(cpu.get_register(Register::PC), 0x102);
+    assert_eq!(cpu.get_cycles(), 1);
+    assert_eq!(call(&mut cpu, SYS_WRITEC, 0x20000001), Ok(0));
+
+    // stdout is ":tt" opened for writing
+    cpu.memmory.write_chunk(0x20000010, b":tt\0").unwrap();
+    let stdout = call_with(&mut cpu, SYS_OPEN, &[0x20000010, 4, 3]).unwrap();
+    assert_eq!(call_with(&mut cpu, SYS_WRITE, &[stdout, 0x20000000, 2]), Ok(0));
+    assert_eq!(console.borrow().as_slice(), b"hi\nihi");
+
+    // a host file written and read back
+    let path = std::env::temp_dir().join(format!("disarm-semihosting-{}", std::process::id()));
+    let name = path.to_str().unwrap().as_bytes();
+    cpu.memmory.write_chunk(0x20000200, name).unwrap();
+    let file = call_with(&mut cpu, SYS_OPEN, &[0x20000200, 4, name.len() as u32]).unwrap();
+    assert_ne!(file, ERROR);
+    assert_eq!(call_with(&mut cpu, SYS_WRITE, &[file, 0x20000000, 2]), Ok(0));
+    assert_eq!(call_with(&mut cpu, SYS_CLOSE, &[file]), Ok(0));
+    assert_eq!(call_with(&mut cpu, SYS_CLOSE, &[file]), Ok(ERROR));
+
+    let file = call_with(&mut cpu, SYS_OPEN, &[0x20000200, 1, name.len() as u32]).unwrap();
+    // 2 of the 5 bytes asked for are read
+    assert_eq!(call_with(&mut cpu, SYS_READ, &[file, 0x20000300, 5]), Ok(3));
+    assert_eq!(cpu.read_u32(0x20000300), Ok(u32::from_le_bytes(*b"hi\0\0")));
+    // huge lengths are transferred a chunk at a time
+    assert_eq!(call_with(&mut cpu, SYS_READ, &[file, 0x20000300, u32::MAX]), Ok(u32::MAX));
+    assert_eq!(call_with(&mut cpu, SYS_WRITE, &[stdout, 0x20000000, u32::MAX]), Ok(u32::MAX - MAX_TRANSFER));
+    // and a string without a NUL is cut short
+    cpu.memmory.write_chunk(0x20000400, &[b'x'; 2 * MAX_TRANSFER as usize]).unwrap();
+    let written = console.borrow().len();
+    assert_eq!(call(&mut cpu, SYS_WRITE0, 0x20000400), Ok(0));
+    assert_eq!(console.borrow().len() - written, MAX_TRANSFER as usize);
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(call_with(&mut cpu, SYS_OPEN, &[0x20000200, 0, name.len() as u32]), Ok(ERROR));
+
+    assert!(call(&mut cpu, SYS_TIME, 0).unwrap() > 1_600_000_000);
+    assert!(call(&mut cpu, SYS_CLOCK, 0).unwrap() < 100);
+
+    assert_eq!(call(&mut cpu, SYS_EXIT, ADP_STOPPED_APPLICATION_EXIT), Err(StopReason::Exit(0)));
+    assert_eq!(call(&mut cpu, SYS_EXIT, 0x20023), Err(StopReason::Exit(1)));
+    assert_eq!(call_with(&mut cpu, SYS_EXIT_EXTENDED, &[ADP_STOPPED_APPLICATION_EXIT, 3]), Err(StopReason::Exit(3)));
+
+    cpu.set_semihosting(false);
+    assert_eq!(call(&mut cpu, SYS_WRITE0, 0x20000000), Err(StopReason::Breakpoint(0xab)));
+}
@@ -0,0 +1,154 @@
+// B3.3 the SysTick timer. A 24 bit counter decremented every processor cycle, reloaded from
+// SYST_RVR after reaching zero, that pends the SysTick exception when TICKINT is set.
+use std::{cell::RefCell, rc::Rc};
+
+use super::{
+    arm_memmory::Size,
+    exception::Exception,
+    nvic::{size_mask, Nvic},
+    peripheral::Peripheral,
+};
+
+pub const SYSTICK_START: u32 = 0xe000e010;
+pub const SYSTICK_SIZE: u32 = 0x10;
+
+const CSR: u32 = 0x0;
+const RVR: u32 = 0x4;
+const CVR: u32 = 0x8;
+const CALIB: u32 = 0xc;
+
+// SYST_CSR bits
+const ENABLE: u32 = 1 << 0;
+const TICKINT: u32 = 1 << 1;
+// the processor clock, the only source modelled
+const CLKSOURCE: u32 = 1 << 2;
+const COUNTFLAG: u32 = 1 << 16;
+
+const COUNTER_MASK: u32 = 0x00ffffff;
+// NOREF, there is no reference clock, and SKEW as TENMS is not known
+const CALIB_VALUE: u32 = 0xc0000000;
+
+pub struct SysTick {
+    csr: u32,
+    rvr: u32,
+    cvr: u32,
+    nvic: Rc<RefCell<Nvic>>,
+}
+
+impl SysTick {
+    pub fn new(nvic: Rc<RefCell<Nvic>>) -> SysTick {
+        SysTick { csr: CLKSOURCE, rvr: 0, cvr: 0, nvic }
+    }
+
+    fn count(&mut self) {
+        if self.cvr == 0 {
+            self.cvr = self.rvr;
+            return;
+        }
+        // counting from 1 to 0 sets COUNTFLAG and fires the exception
+        self.cvr -= 1;
+        if self.cvr == 0 {
+            self.csr |= COUNTFLAG;
+            if self.csr & TICKINT != 0 {
+                self.nvic.borrow_mut().set_pending(Exception::SysTick);
+            }
+        }
+    }
+}
+
+impl Peripheral for SysTick {
+    fn read(&mut self, offset: u32, size: Size) -> u32 {
+        let shift = 8 * (offset & 0b11);
+        let mask = size_mask(size) << shift;
+        let word = match offset & !0b11 {
+            CSR => {
+                // reading COUNTFLAG clears it
+                let csr = self.csr;
+                self.csr &= !(COUNTFLAG & mask);
+                csr
+            }
+            RVR => self.rvr,
+            CVR => self.cvr,
+            CALIB => CALIB_VALUE,
+            _ => 0,
+        };
+        (word & mask) >> shift
+    }
+
+    fn write(&mut self, offset: u32, size: Size, value: u32) {
+        let shift = 8 * (offset & 0b11);
+        let mask = size_mask(size) << shift;
+        let value = (value << shift) & mask;
+        match offset & !0b11 {
+            CSR => {
+                let csr = (self.csr & !mask) | value;
+                self.csr = (self.csr & COUNTFLAG) | (csr & (ENABLE | TICKINT)) | CLKSOURCE;
+            }
+            RVR => self.rvr = ((self.rvr & !mask) | value) & COUNTER_MASK,
+            // any write clears the counter and COUNTFLAG, the next cycle reloads it
+            CVR => {
+                self.cvr = 0;
+                self.csr &= !COUNTFLAG;
+            }
+            _ => {}
+        }
+    }
+
+    fn tick(&mut self, cycles: u32) {
+        if self.csr & ENABLE == 0 {
+            return;
+        }
+        for _ in 0..cycles {
+            self.count();
+        }
+    }
+
+    fn can_interrupt(&self) -> bool {
+        self.csr & (ENABLE | TICKINT) == ENABLE | TICKINT
+    }
+
+    fn reset(&mut self) {
+        self.csr = CLKSOURCE;
+        self.rvr = 0;
+        self.cvr = 0;
+    }
+}
+
+#[test]
+fn test_systick() {
+    let nvic = Rc::new(RefCell::new(Nvic::new()));
+    let mut systick = SysTick::new(nvic.clone());
+
+    systick.write(RVR, Size::Word, 0xff000002);
+    assert_eq!(systick.read(RVR, Size::Word), 2);
+    systick.write(CSR, Size::Word, ENABLE);
+    // the first cycle loads the reload value
+    systick.tick(1);
+    assert_eq!(systick.read(CVR, Size::Word), 2);
+    systick.tick(2);
+    assert_eq!(systick.read(CVR, Size::Word), 0);
+    assert_eq!(systick.read(CSR, Size::Word), COUNTFLAG | CLKSOURCE | ENABLE);
+    assert_eq!(systick.read(CSR, Size::Word), CLKSOURCE | ENABLE);
+    // without TICKINT nothing is pended
+    assert!(!nvic.borrow().is_pending(Exception::SysTick));
+
+    systick.write(CSR, Size::Word, ENABLE | TICKINT);
+    systick.tick(3);
+    assert!(nvic.borrow().is_pending(Exception::SysTick));
+
+    // writing CVR clears it
+    systick.tick(1);
+    systick.write(CVR, Size::Word, 5);
+    assert_eq!(systick.read(CVR, Size::Word), 0);
+    assert_eq!(systick.read(CALIB, Size::Word), CALIB_VALUE);
+
+    // byte and halfword accesses are of their part of the register
+    systick.write(RVR + 1, Size::Byte, 0x12);
+    assert_eq!(systick.read(RVR, Size::Halfword), 0x1202);
+    assert_eq!(systick.read(RVR + 1, Size::Byte), 0x12);
+    assert_eq!(systick.read(CALIB + 3, Size::Byte), 0xc0);
+    systick.write(CSR + 1, Size::Byte, 0);
+    assert_eq!(systick.read(CSR, Size::Word), CLKSOURCE | ENABLE | TICKINT);
+    systick.write(CSR, Size::Byte, 0);
+    assert_eq!(systick.read(CSR, Size::Word), CLKSOURCE);
+}
@@ -24,10 +24,9 @@ pub enum Cond {
 // A5.2.2 Data processing
 #[derive(Debug, PartialEq, Eq, TryFromPrimitive, IntoPrimitive)]
 #[repr(u8)]
-
 pub enum DpOpcode {
     AND,
-    OR,
+    EOR,
     LSL,
     LSR,
     ASR,
@@ -79,7 +78,7 @@ pub enum Register {
     R10,
     R11,
     R12,
-    MSP,
+    SP,
     LR,
     PC,
 }
@@ -100,14 +99,13 @@ pub enum SpecialRegister {
     CONTROL = 20,
 }
 
-type Imm3 = u8;
-type Imm8 = u8;
 type Imm32 = u32;
-type Imm32S = i32;
 type Rn = Register;
 type Rd = Register;
 type Rm = Register;
 type Rt = Register;
+type Rdn = Register;
+type BarrierOption = u8;
 
 #[derive(Debug, PartialEq, Eq, Copy, Clone)]
 pub struct RegisterList(pub u16);
@@ -122,7 +120,7 @@ pub enum Thumb16 {
     AddsRegT1(Rm, Rn, Rd),
     DataProc(DpOpcode, Register, Register),
     MovT1(Rm, Rd),
-    LdrImmT1(Rt, Imm32),
+    LdrLiteralT1(Rt, Imm32),
     Stm(Rn, RegisterList),
     BT2(Imm32),
     Ldm(Rn, RegisterList),
@@ -130,8 +128,51 @@ pub enum Thumb16 {
     UdfT1(Imm32),
     AddSpImmT1(Rd, Imm32),
     SubSpSpImmT1(Imm32),
-    STRImmT2(Rt, Imm32),
+    StrImmT2(Rt, Imm32),
     Bkpt(Imm32),
+    LslImmT1(Imm32, Rm, Rd),
+    LsrImmT1(Imm32, Rm, Rd),
+    AsrImmT1(Imm32, Rm, Rd),
+    MovsRegT2(Rm, Rd),
+    SubsRegT1(Rm, Rn, Rd),
+    SubsImmT1(Imm32, Rn, Rd),
+    AddsImmT2(Rdn, Imm32),
+    SubsImmT2(Rdn, Imm32),
+    AddRegT2(Rm, Rdn),
+    CmpRegT2(Rm, Rn),
+    BlxRegT1(Rm),
+    StrRegT1(Rm, Rn, Rt),
+    StrhRegT1(Rm, Rn, Rt),
+    StrbRegT1(Rm, Rn, Rt),
+    LdrsbRegT1(Rm, Rn, Rt),
+    LdrRegT1(Rm, Rn, Rt),
+    LdrhRegT1(Rm, Rn, Rt),
+    LdrbRegT1(Rm, Rn, Rt),
+    LdrshRegT1(Rm, Rn, Rt),
+    StrImmT1(Imm32, Rn, Rt),
+    LdrImmT1(Imm32, Rn, Rt),
+    StrbImmT1(Imm32, Rn, Rt),
+    LdrbImmT1(Imm32, Rn, Rt),
+    StrhImmT1(Imm32, Rn, Rt),
+    LdrhImmT1(Imm32, Rn, Rt),
+    LdrImmT2(Rt, Imm32),
+    AdrT1(Rd, Imm32),
+    AddSpImmT2(Imm32),
+    SxthT1(Rm, Rd),
+    SxtbT1(Rm, Rd),
+    UxthT1(Rm, Rd),
+    UxtbT1(Rm, Rd),
+    Cps(bool),
+    RevT1(Rm, Rd),
+    Rev16T1(Rm, Rd),
+    RevshT1(Rm, Rd),
+    Pop(RegisterList),
+    Nop,
+    Yield,
+    Wfe,
+    Wfi,
+    Sev,
+    Svc(Imm32),
 }
 
 #[derive(Debug, PartialEq, Eq)]
@@ -139,6 +180,10 @@ pub enum Thumb32 {
     BlT1(Imm32),
     MsrT1(Rn, SpecialRegister),
     MrsT1(Rd, SpecialRegister),
+    DsbT1(BarrierOption),
+    DmbT1(BarrierOption),
+    IsbT1(BarrierOption),
+    UdfT2(Imm32),
 }
 
 #[derive(Debug, PartialEq, Eq)]
@@ -0,0 +1,380 @@
+// Textual disassembly in the UAL syntax `objdump -d` prints.
+use std::fmt::{self, Display, Formatter};
+
+use crate::{
+    ast::{Cond, DpOpcode, Register, RegisterList, SpecialRegister, Thumb, Thumb16, Thumb32},
+    instructions::Decoded,
+    symbols::SymbolTable,
+};
+
+// objdump starts the `@ ...` comment after the operands padded to this width
+const OPERAND_WIDTH: usize = 23;
+
+// objdump pads the raw bytes column to this width
+pub(crate) const BYTES_WIDTH: usize = 13;
+
+impl Display for Register {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Register::SP => write!(f, "sp"),
+            Register::LR => write!(f, "lr"),
+            Register::PC => write!(f, "pc"),
+            r => write!(f, "r{}", u8::from(*r)),
+        }
+    }
+}
+
+impl Display for RegisterList {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let list: Vec<String> = self.to_vec().iter().map(|r| r.to_string()).collect();
+        write!(f, "{{{}}}", list.join(", "))
+    }
+}
+
+impl Display for SpecialRegister {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            SpecialRegister::APSR => "apsr",
+            SpecialRegister::IAPSR => "iapsr",
+            SpecialRegister::EAPSR => "eapsr",
+            SpecialRegister::XPSR => "xpsr",
+            SpecialRegister::IPSR => "ipsr",
+            SpecialRegister::EPSR => "epsr",
+            SpecialRegister::IEPSR => "iepsr",
+            SpecialRegister::MSP => "msp",
+            SpecialRegister::PSP => "psp",
+            SpecialRegister::PRIMASK => "primask",
+            SpecialRegister::CONTROL => "control",
+        };
+        write!(f, "{name}")
+    }
+}
+
+impl Display for Cond {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Cond::EQ => "eq",
+            Cond::NE => "ne",
+            Cond::CS => "hs",
+            Cond::CC => "lo",
+            Cond::MI => "mi",
+            Cond::PL => "pl",
+            Cond::VS => "vs",
+            Cond::VC => "vc",
+            Cond::HI => "hi",
+            Cond::LS => "ls",
+            Cond::GE => "ge",
+            Cond::LT => "lt",
+            Cond::GT => "gt",
+            Cond::LE => "le",
+            Cond::None => "",
+        };
+        write!(f, "{name}")
+    }
+}
+
+// Formats an instruction at a known address, so branch targets and literal pool loads can be
+// resolved the way objdump does (`bhi	0x10e                   @ imm = #4`). With a symbol
+// table the targets are labelled too (`bhi	0x10e <cond_function+0xa> @ imm = #4`).
+pub struct DisplayThumb<'a> {
+    thumb: &'a Thumb,
+    address: Option<u32>,
+    symbols: Option<&'a SymbolTable>,
+}
+
+impl Thumb {
+    pub fn display(&self, address: u32) -> DisplayThumb<'_> {
+        DisplayThumb { thumb: self, address: Some(address), symbols: None }
+    }
+
+    pub fn display_with_symbols<'a>(&'a self, address: u32, symbols: &'a SymbolTable) -> DisplayThumb<'a> {
+        DisplayThumb { thumb: self, address: Some(address), symbols: Some(symbols) }
+    }
+}
+
+// Without an address branch targets are printed as the offset from the instruction (`bhi	#4`).
+impl Display for Thumb {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        DisplayThumb { thumb: self, address: None, symbols: None }.fmt(f)
+    }
+}
+
+impl Display for Thumb16 {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let (mnemonic, operands) = format16(self);
+        write_instruction(f, &mnemonic, &operands, None)
+    }
+}
+
+impl Display for Thumb32 {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let (mnemonic, operands) = format32(self);
+        write_instruction(f, &mnemonic, &operands, None)
+    }
+}
+
+impl Display for DisplayThumb<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let (mnemonic, operands) = match self.thumb {
+            Thumb::Thumb16(x) => format16(x),
+            Thumb::Thumb32(x) => format32(x),
+        };
+
+        let address = match self.address {
+            Some(x) => x,
+            None => return write_instruction(f, &mnemonic, &operands, None),
+        };
+
+        let target = |x: u32| match self.symbols {
+            Some(symbols) => symbols.format_address(x),
+            None => format!("{x:#x}"),
+        };
+
+        // the PC reads as the address of the instruction plus 4
+        let pc = address.wrapping_add(4);
+        match self.thumb {
+            Thumb::Thumb16(Thumb16::BImmT1(_, imm32))
+            | Thumb::Thumb16(Thumb16::BT2(imm32))
+            | Thumb::Thumb32(Thumb32::BlT1(imm32)) => {
+                let comment = format!("imm = #{}", *imm32 as i32);
+                write_instruction(f, &mnemonic, &target(pc.wrapping_add(*imm32)), Some(&comment))
+            }
+            Thumb::Thumb16(Thumb16::LdrLiteralT1(_, imm32)) => {
+                let literal = (pc & !0b11).wrapping_add(*imm32);
+                write_instruction(f, &mnemonic, &operands, Some(&target(literal)))
+            }
+            _ => write_instruction(f, &mnemonic, &operands, None),
+        }
+    }
+}
+
+// A decoded instruction with branch targets labelled from a symbol table.
+pub struct DisplayDecoded<'a> {
+    decoded: &'a Decoded<'a>,
+    symbols: Option<&'a SymbolTable>,
+}
+
+impl<'a> Decoded<'a> {
+    pub fn display_with_symbols(&'a self, symbols: &'a SymbolTable) -> DisplayDecoded<'a> {
+        DisplayDecoded { decoded: self, symbols: Some(symbols) }
+    }
+}
+
+// One line of objdump output, `     100: 1c40         	adds	r0, r0, #1`.
+impl Display for Decoded<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        DisplayDecoded { decoded: self, symbols: None }.fmt(f)
+    }
+}
+
+impl Display for DisplayDecoded<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let decoded = self.decoded;
+        let instruction = match self.symbols {
+            Some(symbols) => decoded.instruction.display_with_symbols(decoded.address, symbols),
+            None => decoded.instruction.display(decoded.address),
+        };
+        let halfwords: Vec<String> = decoded
+            .bytes
+            .chunks(2)
+            .map(|x| format!("{:04x}", u16::from_le_bytes([x[0], x[1]])))
+            .collect();
+        write!(
+            f,
+            "{:>8x}: {:<width$}\t{}",
+            decoded.address,
+            halfwords.join(" "),
+            instruction,
+            width = BYTES_WIDTH
+        )
+    }
+}
+
+fn write_instruction(f: &mut Formatter<'_>, mnemonic: &str, operands: &str, comment: Option<&str>) -> fmt::Result {
+    match (operands.is_empty(), comment) {
+        (true, _) => write!(f, "{mnemonic}"),
+        (false, None) => write!(f, "{mnemonic}\t{operands}"),
+        (false, Some(c)) => write!(f, "{mnemonic}\t{operands:<width$} @ {c}", width = OPERAND_WIDTH),
+    }
+}
+
+// [rn, #imm] with a zero offset printed as [rn]
+fn memory_imm(rn: &Register, imm32: &u32) -> String {
+    if *imm32 == 0 {
+        format!("[{rn}]")
+    } else {
+        format!("[{rn}, #{imm32}]")
+    }
+}
+
+fn format16(instr: &Thumb16) -> (String, String) {
+    let (mnemonic, operands) = match instr {
+        Thumb16::AddsImmT1(imm32, rn, rd) => ("adds", format!("{rd}, {rn}, #{imm32}")),
+        Thumb16::BxT1(rm) => ("bx", format!("{rm}")),
+        Thumb16::CmpImmT1(rn, imm32) => ("cmp", format!("{rn}, #{imm32}")),
+        Thumb16::BImmT1(cond, imm32) => return (format!("b{cond}"), format!("#{}", *imm32 as i32)),
+        Thumb16::MovsImmT1(rd, imm32) => ("movs", format!("{rd}, #{imm32}")),
+        Thumb16::AddsRegT1(rm, rn, rd) => ("adds", format!("{rd}, {rn}, {rm}")),
+        Thumb16::DataProc(dp_op_code, reg_3, reg_0) => {
+            let mnemonic = match dp_op_code {
+                DpOpcode::AND => "ands",
+                DpOpcode::EOR => "eors",
+                DpOpcode::LSL => "lsls",
+                DpOpcode::LSR => "lsrs",
+                DpOpcode::ASR => "asrs",
+                DpOpcode::ADC => "adcs",
+                DpOpcode::SBC => "sbcs",
+                DpOpcode::ROR => "rors",
+                DpOpcode::TST => "tst",
+                DpOpcode::RSB => "rsbs",
+                DpOpcode::CMP => "cmp",
+                DpOpcode::CMN => "cmn",
+                DpOpcode::ORR => "orrs",
+                DpOpcode::MUL => "muls",
+                DpOpcode::BIC => "bics",
+                DpOpcode::MVN => "mvns",
+            };
+            let operands = match dp_op_code {
+                DpOpcode::RSB => format!("{reg_0}, {reg_3}, #0"),
+                DpOpcode::MUL => format!("{reg_0}, {reg_3}, {reg_0}"),
+                _ => format!("{reg_0}, {reg_3}"),
+            };
+            (mnemonic, operands)
+        }
+        Thumb16::MovT1(rm, rd) => ("mov", format!("{rd}, {rm}")),
+        Thumb16::LdrLiteralT1(rt, imm32) => ("ldr", format!("{rt}, [pc, #{imm32}]")),
+        Thumb16::Stm(rn, registers) => ("stm", format!("{rn}!, {registers}")),
+        Thumb16::BT2(imm32) => ("b", format!("#{}", *imm32 as i32)),
+        Thumb16::Ldm(rn, registers) => {
+            // no writeback when the base register is loaded
+            if registers.to_vec().contains(rn) {
+                ("ldm", format!("{rn}, {registers}"))
+            } else {
+                ("ldm", format!("{rn}!, {registers}"))
+            }
+        }
+        Thumb16::Push(registers) => ("push", format!("{registers}")),
+        // udf #254 is what llvm emits for `trap`
+        Thumb16::UdfT1(254) => ("trap", String::new()),
+        Thumb16::UdfT1(imm32) => ("udf", format!("#{imm32}")),
+        Thumb16::AddSpImmT1(rd, imm32) => ("add", format!("{rd}, sp, #{imm32}")),
+        Thumb16::SubSpSpImmT1(imm32) => ("sub", format!("sp, #{imm32}")),
+        Thumb16::StrImmT2(rt, imm32) => ("str", format!("{rt}, {}", memory_imm(&Register::SP, imm32))),
+        Thumb16::Bkpt(imm32) => ("bkpt", format!("#{imm32}")),
+        Thumb16::LslImmT1(imm32, rm, rd) => ("lsls", format!("{rd}, {rm}, #{imm32}")),
+        Thumb16::LsrImmT1(imm32, rm, rd) => ("lsrs", format!("{rd}, {rm}, #{imm32}")),
+        Thumb16::AsrImmT1(imm32, rm, rd) => ("asrs", format!("{rd}, {rm}, #{imm32}")),
+        Thumb16::MovsRegT2(rm, rd) => ("movs", format!("{rd}, {rm}")),
+        Thumb16::SubsRegT1(rm, rn, rd) => ("subs", format!("{rd}, {rn}, {rm}")),
+        Thumb16::SubsImmT1(imm32, rn, rd) => ("subs", format!("{rd}, {rn}, #{imm32}")),
+        Thumb16::AddsImmT2(rdn, imm32) => ("adds", format!("{rdn}, #{imm32}")),
+        Thumb16::SubsImmT2(rdn, imm32) => ("subs", format!("{rdn}, #{imm32}")),
+        Thumb16::AddRegT2(rm, rdn) => ("add", format!("{rdn}, {rm}")),
+        Thumb16::CmpRegT2(rm, rn) => ("cmp", format!("{rn}, {rm}")),
+        Thumb16::BlxRegT1(rm) => ("blx", format!("{rm}")),
+        Thumb16::StrRegT1(rm, rn, rt) => ("str", format!("{rt}, [{rn}, {rm}]")),
+        Thumb16::StrhRegT1(rm, rn, rt) => ("strh", format!("{rt}, [{rn}, {rm}]")),
+        Thumb16::StrbRegT1(rm, rn, rt) => ("strb", format!("{rt}, [{rn}, {rm}]")),
+        Thumb16::LdrsbRegT1(rm, rn, rt) => ("ldrsb", format!("{rt}, [{rn}, {rm}]")),
+        Thumb16::LdrRegT1(rm, rn, rt) => ("ldr", format!("{rt}, [{rn}, {rm}]")),
+        Thumb16::LdrhRegT1(rm, rn, rt) => ("ldrh", format!("{rt}, [{rn}, {rm}]")),
+        Thumb16::LdrbRegT1(rm, rn, rt) => ("ldrb", format!("{rt}, [{rn}, {rm}]")),
+        Thumb16::LdrshRegT1(rm, rn, rt) => ("ldrsh", format!("{rt}, [{rn}, {rm}]")),
+        Thumb16::StrImmT1(imm32, rn, rt) => ("str", format!("{rt}, {}", memory_imm(rn, imm32))),
+        Thumb16::LdrImmT1(imm32, rn, rt) => ("ldr", format!("{rt}, {}", memory_imm(rn, imm32))),
+        Thumb16::StrbImmT1(imm32, rn, rt) => ("strb", format!("{rt}, {}", memory_imm(rn, imm32))),
+        Thumb16::LdrbImmT1(imm32, rn, rt) => ("ldrb", format!("{rt}, {}", memory_imm(rn, imm32))),
+        Thumb16::StrhImmT1(imm32, rn, rt) => ("strh", format!("{rt}, {}", memory_imm(rn, imm32))),
+        Thumb16::LdrhImmT1(imm32, rn, rt) => ("ldrh", format!("{rt}, {}", memory_imm(rn, imm32))),
+        Thumb16::LdrImmT2(rt, imm32) => ("ldr", format!("{rt}, {}", memory_imm(&Register::SP, imm32))),
+        Thumb16::AdrT1(rd, imm32) => ("adr", format!("{rd}, #{imm32}")),
+        Thumb16::AddSpImmT2(imm32) => ("add", format!("sp, #{imm32}")),
+        Thumb16::SxthT1(rm, rd) => ("sxth", format!("{rd}, {rm}")),
+        Thumb16::SxtbT1(rm, rd) => ("sxtb", format!("{rd}, {rm}")),
+        Thumb16::UxthT1(rm, rd) => ("uxth", format!("{rd}, {rm}")),
+        Thumb16::UxtbT1(rm, rd) => ("uxtb", format!("{rd}, {rm}")),
+        // objdump separates the interrupt flag with a space, not a tab
+        Thumb16::Cps(true) => ("cpsid i", String::new()),
+        Thumb16::Cps(false) => ("cpsie i", String::new()),
+        Thumb16::RevT1(rm, rd) => ("rev", format!("{rd}, {rm}")),
+        Thumb16::Rev16T1(rm, rd) => ("rev16", format!("{rd}, {rm}")),
+        Thumb16::RevshT1(rm, rd) => ("revsh", format!("{rd}, {rm}")),
+        Thumb16::Pop(registers) => ("pop", format!("{registers}")),
+        Thumb16::Nop => ("nop", String::new()),
+        Thumb16::Yield => ("yield", String::new()),
+        Thumb16::Wfe => ("wfe", String::new()),
+        Thumb16::Wfi => ("wfi", String::new()),
+        Thumb16::Sev => ("sev", String::new()),
+        Thumb16::Svc(imm32) => ("svc", format!("#{imm32}")),
+    };
+    (mnemonic.to_string(), operands)
+}
+
+fn format32(instr: &Thumb32) -> (String, String) {
+    // only the SY option is defined in ARMv6-M, the rest are printed as a plain immediate
+    let barrier = |option: &u8| match option {
+        0b1111 => "sy".to_string(),
+        x => format!("#{x:#x}"),
+    };
+
+    let (mnemonic, operands) = match instr {
+        Thumb32::BlT1(imm32) => ("bl", format!("#{}", *imm32 as i32)),
+        Thumb32::MsrT1(rn, sr) => ("msr", format!("{sr}, {rn}")),
+        Thumb32::MrsT1(rd, sr) => ("mrs", format!("{rd}, {sr}")),
+        Thumb32::DsbT1(option) => ("dsb", barrier(option)),
+        Thumb32::DmbT1(option) => ("dmb", barrier(option)),
+        Thumb32::IsbT1(option) => ("isb", barrier(option)),
+        Thumb32::UdfT2(imm32) => ("udf.w", format!("#{imm32}")),
+    };
+    (mnemonic.to_string(), operands)
+}
+
+#[test]
+fn test_display_inc_asm() {
+    use crate::instructions::Instructions;
+
+    // symbols are not known here, drop the `<symbol+offset>` annotations from the reference
+    fn strip_symbols(line: &str) -> String {
+        let cut = |x: &str| match x.find(" <") {
+            Some(i) => x[..i].trim_end().to_string(),
+            None => x.trim_end().to_string(),
+        };
+        match line.split_once(" @ ") {
+            Some((instruction, comment)) => format!("{} @ {}", cut(instruction), cut(comment)),
+            None => cut(line),
+        }
+    }
+
+    for (address, chunk, text) in crate::inc_asm() {
+        if text.starts_with('.') {
+            continue;
+        }
+        let decoded = Instructions::new(&chunk, address).next().unwrap().unwrap();
+        let display = decoded.instruction.display(address).to_string();
+        assert_eq!(strip_symbols(&display), strip_symbols(text), "{address:x}: {text}");
+    }
+}
+
+#[test]
+fn test_display_objdump_line() {
+    use crate::instructions::Instructions;
+
+    let chunk: &[u8] = &[
+        0x40, 0x1c, // adds	r0, r0, #1
+        0x02, 0xd8, // bhi	0x10a @ imm = #4
+        0x00, 0xf0, 0x4e, 0xfb, // bl	0x7a4 @ imm = #1692
+        0x09, 0x48, // ldr	r0, [pc, #36] @ 0x130
+        0xb0, 0xb5, // push	{r4, r5, r7, lr}
+    ];
+    let lines: Vec<String> = Instructions::new(chunk, 0x100).map(|x| x.unwrap().to_string()).collect();
+
+    assert_eq!(lines[0], "     100: 1c40         \tadds\tr0, r0, #1");
+    assert_eq!(lines[1], "     102: d802         \tbhi\t0x10a                   @ imm = #4");
+    assert_eq!(lines[2], "     104: f000 fb4e    \tbl\t0x7a4                   @ imm = #1692");
+    assert_eq!(lines[3], "     108: 4809         \tldr\tr0, [pc, #36]           @ 0x130");
+    assert_eq!(lines[4], "     10a: b5b0         \tpush\t{r4, r5, r7, lr}");
+
+    // without an address branches are relative to the instruction
+    assert_eq!(Thumb::Thumb16(Thumb16::BImmT1(Cond::HI, 4)).to_string(), "bhi\t#4");
+    assert_eq!(Thumb::Thumb16(Thumb16::BT2(-10i32 as u32)).to_string(), "b\t#-10");
+}
@@ -0,0 +1,647 @@
+// A GDB remote serial protocol stub, so gdb can debug the emulator with `target extended-remote`
+// as it does the hardware through OpenOCD. One connection is served at a time, the processor
+// is halted whenever gdb is not waiting for it to stop.
+use std::io::{self, BufRead, BufReader, ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream};
+
+use crate::arm_cpu::{Cpu, RunOptions, StopReason, WatchKind};
+use crate::ast::{Register, SpecialRegister};
+
+// instructions run between checks for a ctrl-c from gdb
+const INTERRUPT_POLL: u64 = 10_000;
+
+// the largest packet gdb is told it can send, and replies are kept to
+const PACKET_SIZE: u32 = 0x4000;
+
+// signal numbers of the stop replies
+const SIGINT: u8 = 2;
+const SIGILL: u8 = 4;
+const SIGTRAP: u8 = 5;
+const SIGSEGV: u8 = 11;
+
+// The registers in the order of the target description, their numbers in the p and P
+// packets. The core registers, then the system ones MRS and MSR reach.
+const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <architecture>arm</architecture>
+  <feature name="org.gnu.gdb.arm.m-profile">
+    <reg name="r0" bitsize="32"/>
+    <reg name="r1" bitsize="32"/>
+    <reg name="r2" bitsize="32"/>
+    <reg name="r3" bitsize="32"/>
+    <reg name="r4" bitsize="32"/>
+    <reg name="r5" bitsize="32"/>
+    <reg name="r6" bitsize="32"/>
+    <reg name="r7" bitsize="32"/>
+    <reg name="r8" bitsize="32"/>
+    <reg name="r9" bitsize="32"/>
+    <reg name="r10" bitsize="32"/>
+    <reg name="r11" bitsize="32"/>
+    <reg name="r12" bitsize="32"/>
+    <reg name="sp" bitsize="32" type="data_ptr"/>
+    <reg name="lr" bitsize="32"/>
+    <reg name="pc" bitsize="32" type="code_ptr"/>
+    <reg name="xpsr" bitsize="32"/>
+  </feature>
+  <feature name="org.gnu.gdb.arm.m-system">
+    <reg name="msp" bitsize="32" type="data_ptr"/>
+    <reg name="psp" bitsize="32" type="data_ptr"/>
+    <reg name="primask" bitsize="32"/>
+    <reg name="control" bitsize="32"/>
+  </feature>
+</target>
+"#;
+
+// after the 16 core registers
+const SPECIAL_REGISTERS: [SpecialRegister; 5] = [
+    SpecialRegister::XPSR,
+    SpecialRegister::MSP,
+    SpecialRegister::PSP,
+    SpecialRegister::PRIMASK,
+    SpecialRegister::CONTROL,
+];
+const REGISTER_COUNT: u32 = 16 + SPECIAL_REGISTERS.len() as u32;
+
+// why the processor halted, reported to gdb in a stop reply
+enum Halt {
+    Stopped(StopReason),
+    // a single step completed
+    Stepped,
+}
+
+// Serves gdb connections one after the other, for as long as the listener accepts them. A
+// continue also stops when options do.
+pub fn serve(cpu: &mut Cpu, listener: &TcpListener, options: &RunOptions) -> io::Result<()> {
+    loop {
+        let (stream, _) = listener.accept()?;
+        // a connection dropping only ends that session
+        if let Err(e) = serve_connection(cpu, stream, options) {
+            eprintln!("disarm: gdb connection: {e}");
+        }
+    }
+}
+
+pub fn serve_connection(cpu: &mut Cpu, stream: TcpStream, options: &RunOptions) -> io::Result<()> {
+    stream.set_nodelay(true)?;
+    let mut session = Session {
+        cpu,
+        options,
+        writer: stream.try_clone()?,
+        reader: BufReader::new(stream),
+        no_ack: false,
+        last_halt: Halt::Stepped,
+        detached: false,
+    };
+    while !session.detached {
+        let packet = match session.read_packet()? {
+            Some(x) => x,
+            None => break,
+        };
+        if let Some(reply) = session.handle(&packet)? {
+            session.send_packet(&reply)?;
+        }
+    }
+    Ok(())
+}
+
+struct Session<'a> {
+    cpu: &'a mut Cpu,
+    options: &'a RunOptions,
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+    // acknowledgments are off after QStartNoAckMode
+    no_ack: bool,
+    // for the ? packet
+    last_halt: Halt,
+    detached: bool,
+}
+
+impl Session<'_> {
+    fn read_byte(&mut self) -> io::Result<Option<u8>> {
+        let mut byte = [0];
+        match self.reader.read(&mut byte)? {
+            0 => Ok(None),
+            _ => Ok(Some(byte[0])),
+        }
+    }
+
+    // the data of the next packet, None once gdb has closed the connection
+    fn read_packet(&mut self) -> io::Result<Option<Vec<u8>>> {
+        loop {
+            // acknowledgments, and a ctrl-c while already halted, are dropped
+            loop {
+                match self.read_byte()? {
+                    None => return Ok(None),
+                    Some(b'$') => break,
+                    Some(_) => {}
+                }
+            }
+            let mut data = Vec::new();
+            loop {
+                match self.read_byte()? {
+                    None => return Ok(None),
+                    Some(b'#') => break,
+                    Some(x) => data.push(x),
+                }
+            }
+            let mut checksum = [0; 2];
+            self.reader.read_exact(&mut checksum)?;
+            if self.no_ack {
+                return Ok(Some(data));
+            }
+            let expected = std::str::from_utf8(&checksum).ok().and_then(|x| u8::from_str_radix(x, 16).ok());
+            if expected == Some(checksum_of(&data)) {
+                self.writer.write_all(b"+")?;
+                return Ok(Some(data));
+            }
+            // gdb sends it again
+            self.writer.write_all(b"-")?;
+        }
+    }
+
+    fn send_packet(&mut self, data: &[u8]) -> io::Result<()> {
+        let mut packet = vec![b'$'];
+        for x in data {
+            match x {
+                b'$' | b'#' | b'}' | b'*' => packet.extend_from_slice(&[b'}', x ^ 0x20]),
+                _ => packet.push(*x),
+            }
+        }
+        let checksum = checksum_of(&packet[1..]);
+        packet.extend_from_slice(format!("#{checksum:02x}").as_bytes());
+        loop {
+            self.writer.write_all(&packet)?;
+            if self.no_ack {
+                return Ok(());
+            }
+            match self.read_byte()? {
+                Some(b'-') => continue,
+                _ => return Ok(()),
+            }
+        }
+    }
+
+    // the reply to a packet, None for the packets that have none
+    fn handle(&mut self, packet: &[u8]) -> io::Result<Option<Vec<u8>>> {
+        // X carries binary data after the colon, everything else is text
+        if let Some(rest) = packet.strip_prefix(b"X") {
+            return Ok(Some(self.write_binary(rest)));
+        }
+        if packet == b"QStartNoAckMode" {
+            // acknowledged once more, then acknowledgments stop
+            self.send_packet(b"OK")?;
+            self.no_ack = true;
+            return Ok(None);
+        }
+        let packet = String::from_utf8_lossy(packet).into_owned();
+        let reply = match packet.as_bytes().first() {
+            Some(b'?') => stop_reply(&self.last_halt),
+            Some(b'!') => ok(),
+            Some(b'g') => {
+                let values: Vec<u32> = (0..REGISTER_COUNT).filter_map(|x| self.read_register(x)).collect();
+                values.iter().map(|x| hex(&x.to_le_bytes())).collect::<String>().into_bytes()
+            }
+            Some(b'G') => match decode_hex(&packet[1..]) {
+                Some(values) if values.len().is_multiple_of(4) => {
+                    for (i, x) in values.chunks_exact(4).enumerate() {
+                        self.write_register(i as u32, u32::from_le_bytes(x.try_into().unwrap()));
+                    }
+                    ok()
+                }
+                _ => error(),
+            },
+            Some(b'p') => match parse_hex(&packet[1..]).and_then(|x| self.read_register(x)) {
+                Some(value) => hex(&value.to_le_bytes()).into_bytes(),
+                None => error(),
+            },
+            Some(b'P') => {
+                let (register, value) = packet[1..].split_once('=').unwrap_or_default();
+                match (parse_hex(register), decode_hex(value)) {
+                    (Some(register), Some(value)) if register < REGISTER_COUNT && value.len() == 4 => {
+                        self.write_register(register, u32::from_le_bytes(value.try_into().unwrap()));
+                        ok()
+                    }
+                    _ => error(),
+                }
+            }
+            Some(b'm') => self.read_memory(&packet[1..]),
+            Some(b'M') => {
+                let (range, data) = packet[1..].split_once(':').unwrap_or_default();
+                match (parse_range(range), decode_hex(data)) {
+                    (Some((address, _)), Some(data)) => self.write_memory(address, &data),
+                    _ => error(),
+                }
+            }
+            // an address to resume from is not supported, gdb sets PC instead
+            Some(b'c') | Some(b'C') => self.resume(false)?,
+            Some(b's') | Some(b'S') => self.resume(true)?,
+            Some(b'Z') | Some(b'z') => self.breakpoint(&packet),
+            Some(b'H') | Some(b'T') => ok(),
+            // in extended mode the program is killed, the processor reset and the session kept
+            Some(b'k') | Some(b'R') => {
+                self.reset();
+                return Ok(None);
+            }
+            Some(b'D') => {
+                self.detached = true;
+                ok()
+            }
+            Some(b'q') | Some(b'Q') => self.query(&packet)?,
+            Some(b'v') => self.v_packet(&packet)?,
+            _ => Vec::new(),
+        };
+        Ok(Some(reply))
+    }
+
+    fn query(&mut self, packet: &str) -> io::Result<Vec<u8>> {
+        if packet.starts_with("qSupported") {
+            return Ok(format!("PacketSize={PACKET_SIZE:x};qXfer:features:read+;QStartNoAckMode+;vContSupported+").into_bytes());
+        }
+        if let Some(rest) = packet.strip_prefix("qXfer:features:read:target.xml:") {
+            return Ok(match parse_range(rest) {
+                Some((offset, length)) => {
+                    let xml = TARGET_XML.as_bytes();
+                    let start = (offset as usize).min(xml.len());
+                    let end = (start + length as usize).min(xml.len());
+                    let mut reply = vec![if end == xml.len() { b'l' } else { b'm' }];
+                    reply.extend_from_slice(&xml[start..end]);
+                    reply
+                }
+                None => error(),
+            });
+        }
+        if let Some(command) = packet.strip_prefix("qRcmd,") {
+            let command = decode_hex(command).unwrap_or_default();
+            let output = self.monitor(&String::from_utf8_lossy(&command));
+            if !output.is_empty() {
+                // console output before the final reply
+                let mut message = b"O".to_vec();
+                message.extend_from_slice(hex(output.as_bytes()).as_bytes());
+                self.send_packet(&message)?;
+            }
+            return Ok(ok());
+        }
+        let reply = match packet {
+            // one thread, the processor
+            "qfThreadInfo" => b"m1".to_vec(),
+            "qsThreadInfo" => b"l".to_vec(),
+            "qC" => b"QC1".to_vec(),
+            // attached to an existing process, detaching leaves it running
+            "qAttached" => b"1".to_vec(),
+            "qSymbol::" => ok(),
+            _ => Vec::new(),
+        };
+        Ok(reply)
+    }
+
+    fn v_packet(&mut self, packet: &str) -> io::Result<Vec<u8>> {
+        if packet == "vCont?" {
+            return Ok(b"vCont;c;C;s;S".to_vec());
+        }
+        if let Some(actions) = packet.strip_prefix("vCont;") {
+            // one thread, so the first action applies
+            return match actions.as_bytes().first() {
+                Some(b'c') | Some(b'C') => self.resume(false),
+                Some(b's') | Some(b'S') => self.resume(true),
+                _ => Ok(error()),
+            };
+        }
+        if packet.starts_with("vRun") {
+            self.reset();
+            return Ok(stop_reply(&self.last_halt));
+        }
+        if packet.starts_with("vKill") {
+            self.reset();
+            return Ok(ok());
+        }
+        Ok(Vec::new())
+    }
+
+    // The output of a monitor command, the ones of OpenOCD a gdb script is likely to use.
+    fn monitor(&mut self, command: &str) -> String {
+        let words: Vec<&str> = command.split_whitespace().collect();
+        match words.as_slice() {
+            ["reset"] | ["reset", "halt"] | ["reset", "init"] => {
+                self.reset();
+                String::new()
+            }
+            // always halted when gdb can send commands
+            ["halt"] => String::new(),
+            ["arm", "semihosting", "enable"] => {
+                self.cpu.set_semihosting(true);
+                "semihosting is enabled\n".to_string()
+            }
+            ["arm", "semihosting", "disable"] => {
+                self.cpu.set_semihosting(false);
+                "semihosting is disabled\n".to_string()
+            }
+            ["arm", "semihosting"] => {
+                let state = if self.cpu.semihosting_enabled() { "enabled" } else { "disabled" };
+                format!("semihosting is {state}\n")
+            }
+            ["help"] | [] => "monitor commands: reset [halt|init], halt, arm semihosting [enable|disable]\n".to_string(),
+            _ => format!("unknown command: {command}\n"),
+        }
+    }
+
+    fn reset(&mut self) {
+        self.last_halt = match self.cpu.reset() {
+            Ok(()) => Halt::Stepped,
+            Err(fault) => Halt::Stopped(StopReason::Lockup(fault.into())),
+        };
+    }
+
+    // runs until something stops the processor or gdb interrupts, or a single instruction
+    fn resume(&mut self, step: bool) -> io::Result<Vec<u8>> {
+        let halt = if step {
+            match self.cpu.step() {
+                Ok(()) => Halt::Stepped,
+                Err(reason) => Halt::Stopped(reason),
+            }
+        } else {
+            self.continue_execution()?
+        };
+        let reply = stop_reply(&halt);
+        self.last_halt = halt;
+        Ok(reply)
+    }
+
+    // runs until the options or anything else stops it, polling for a ctrl-c now and then
+    fn continue_execution(&mut self) -> io::Result<Halt> {
+        let reader = &mut self.reader;
+        let mut executed: u64 = 0;
+        let mut error = None;
+        let reason = self.cpu.run_tracing(self.options, |_| {}, |_| {
+            executed += 1;
+            if !executed.is_multiple_of(INTERRUPT_POLL) {
+                return None;
+            }
+            match interrupted(reader) {
+                Ok(false) => None,
+                Ok(true) => Some(StopReason::Interrupted),
+                Err(e) => {
+                    error = Some(e);
+                    Some(StopReason::Interrupted)
+                }
+            }
+        });
+        match error {
+            Some(e) => Err(e),
+            None => Ok(Halt::Stopped(reason)),
+        }
+    }
+
+    fn read_register(&self, number: u32) -> Option<u32> {
+        match number {
+            0..=15 => Some(self.cpu.get_register(Register::try_from(number as u8).ok()?)),
+            _ => SPECIAL_REGISTERS.get(number as usize - 16).map(|x| self.cpu.get_special_register(*x)),
+        }
+    }
+
+    fn write_register(&mut self, number: u32, value: u32) {
+        match number {
+            0..=15 => self.cpu.set_register(Register::try_from(number as u8).unwrap(), value),
+            _ => {
+                if let Some(register) = SPECIAL_REGISTERS.get(number as usize - 16) {
+                    self.cpu.set_special_register(*register, value);
+                }
+            }
+        }
+    }
+
+    // m addr,length, the reply is shortened to fit a packet
+    fn read_memory(&mut self, range: &str) -> Vec<u8> {
+        let (address, length) = match parse_range(range) {
+            Some((address, length)) => (address, length.min(PACKET_SIZE / 2)),
+            None => return error(),
+        };
+        let mut data = Vec::new();
+        for i in 0..length {
+            match self.cpu.read_u8(address.wrapping_add(i)) {
+                Ok(x) => data.push(x),
+                // the bytes up to an unmapped one
+                Err(_) if i > 0 => break,
+                Err(_) => return error(),
+            }
+        }
+        hex(&data).into_bytes()
+    }
+
+    fn write_memory(&mut self, address: u32, data: &[u8]) -> Vec<u8> {
+        match self.cpu.write_chunk(address, data) {
+            Ok(()) => ok(),
+            Err(_) => error(),
+        }
+    }
+
+    // X addr,length:binary data with }, #, $ and * escaped
+    fn write_binary(&mut self, packet: &[u8]) -> Vec<u8> {
+        let colon = match packet.iter().position(|x| *x == b':') {
+            Some(x) => x,
+            None => return error(),
+        };
+        let address = match parse_range(&String::from_utf8_lossy(&packet[..colon])) {
+            Some((address, _)) => address,
+            None => return error(),
+        };
+        let mut data = Vec::new();
+        let mut bytes = packet[colon + 1..].iter();
+        while let Some(x) = bytes.next() {
+            match x {
+                b'}' => data.push(bytes.next().map_or(0, |x| x ^ 0x20)),
+                _ => data.push(*x),
+            }
+        }
+        self.write_memory(address, &data)
+    }
+
+    // Z and z type,addr,kind. Software and hardware breakpoints are the same here, kind is
+    // the length for watchpoints.
+    fn breakpoint(&mut self, packet: &str) -> Vec<u8> {
+        let insert = packet.starts_with('Z');
+        let fields: Vec<&str> = packet[1..].split(',').collect();
+        let (address, length) = match (fields.get(1).and_then(|x| parse_hex(x)), fields.get(2).and_then(|x| parse_hex(x))) {
+            (Some(address), Some(length)) => (address, length),
+            _ => return error(),
+        };
+        let kind = match fields[0] {
+            "0" | "1" => {
+                if insert {
+                    self.cpu.add_breakpoint(address);
+                } else {
+                    self.cpu.remove_breakpoint(address);
+                }
+                return ok();
+            }
+            "2" => WatchKind::Write,
+            "3" => WatchKind::Read,
+            "4" => WatchKind::Access,
+            _ => return Vec::new(),
+        };
+        if insert {
+            self.cpu.add_watchpoint(address, length, kind);
+        } else {
+            self.cpu.remove_watchpoint(address, length, kind);
+        }
+        ok()
+    }
+}
+
+fn stop_reply(halt: &Halt) -> Vec<u8> {
+    let reply = match halt {
+        Halt::Stepped => format!("S{SIGTRAP:02x}"),
+        Halt::Stopped(reason) => match reason {
+            StopReason::Watchpoint(kind, address) => {
+                let name = match kind {
+                    WatchKind::Read => "rwatch",
+                    WatchKind::Write => "watch",
+                    WatchKind::Access => "awatch",
+                };
+                format!("T{SIGTRAP:02x}{name}:{address:x};")
+            }
+            // the program exited, gdb can run it again
+            StopReason::Exit(status) => format!("W{:02x}", *status as u8),
+            StopReason::DecodeError(_) => format!("S{SIGILL:02x}"),
+            StopReason::Lockup(_) => format!("S{SIGSEGV:02x}"),
+            StopReason::Interrupted => format!("S{SIGINT:02x}"),
+            _ => format!("S{SIGTRAP:02x}"),
+        },
+    };
+    reply.into_bytes()
+}
+
+// whether gdb sent a ctrl-c, or went away, while the processor was running
+fn interrupted(reader: &mut BufReader<TcpStream>) -> io::Result<bool> {
+    reader.get_ref().set_nonblocking(true)?;
+    let result = match reader.fill_buf() {
+        Ok([]) => Ok(true),
+        // after any stray acknowledgments
+        Ok(buffer) => match buffer.iter().position(|x| *x == 0x03) {
+            Some(i) => {
+                reader.consume(i + 1);
+                Ok(true)
+            }
+            None => Ok(false),
+        },
+        Err(e) if e.kind() == ErrorKind::WouldBlock => Ok(false),
+        Err(e) => Err(e),
+    };
+    reader.get_ref().set_nonblocking(false)?;
+    result
+}
+
+fn ok() -> Vec<u8> {
+    b"OK".to_vec()
+}
+
+fn error() -> Vec<u8> {
+    b"E01".to_vec()
+}
+
+fn checksum_of(data: &[u8]) -> u8 {
+    data.iter().fold(0, |a, x| a.wrapping_add(*x))
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|x| format!("{x:02x}")).collect()
+}
+
+fn decode_hex(x: &str) -> Option<Vec<u8>> {
+    if !x.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..x.len()).step_by(2).map(|i| u8::from_str_radix(x.get(i..i + 2)?, 16).ok()).collect()
+}
+
+fn parse_hex(x: &str) -> Option<u32> {
+    u32::from_str_radix(x, 16).ok()
+}
+
+// addr,length
+fn parse_range(x: &str) -> Option<(u32, u32)> {
+    let (address, length) = x.split_once(',')?;
+    Some((parse_hex(address)?, parse_hex(length)?))
+}
+
+#[test]
+fn test_gdb_session() {
+    use crate::{arm_cpu::build, Program};
+
+    // gdb's side, each packet with the reply expected, an empty packet reads another reply
+    fn client(mut stream: TcpStream, exchanges: &[(&str, &str)]) {
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        let mut ack = true;
+        for (packet, expected) in exchanges {
+            if !packet.is_empty() {
+                stream.write_all(format!("${packet}#{:02x}", checksum_of(packet.as_bytes())).as_bytes()).unwrap();
+            }
+            let mut reply = Vec::new();
+            reader.read_until(b'#', &mut reply).unwrap();
+            let mut checksum = [0; 2];
+            reader.read_exact(&mut checksum).unwrap();
+            if ack {
+                stream.write_all(b"+").unwrap();
+            }
+            ack &= *packet != "QStartNoAckMode";
+            let start = reply.iter().position(|x| *x == b'$').unwrap();
+            assert_eq!(String::from_utf8_lossy(&reply[start + 1..reply.len() - 1]), *expected, "reply to {packet}");
+        }
+    }
+
+    let chunk: &[u8] = &[
+        0x01, 0x20, // movs	r0, #1
+        0x01, 0x30, // adds	r0, #1
+        0x08, 0x60, // str	r0, [r1]
+        0x00, 0xbe, // bkpt	#0
+    ];
+    let mut cpu = build();
+    cpu.load_program(&Program::build(chunk, 0x100, 0x20001000)).unwrap();
+    cpu.set_register(Register::PC, 0x100);
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let gdb = std::thread::spawn(move || {
+        // r0 to r12, sp, lr, pc, then xpsr, msp, psp, primask and control
+        let registers = [2, 0x20000000, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x20001000, 0, 0x106, 1 << 24, 0x20001000, 0, 1, 0];
+        let g: String = registers.iter().map(|x: &u32| hex(&x.to_le_bytes())).collect();
+        let semihosting = format!("O{}", hex(b"semihosting is enabled\n"));
+        let memory = format!("{:0<16384}", "02000000abcd7d01");
+        let stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        client(stream, &[
+            ("qSupported:multiprocess+;swbreak+", "PacketSize=4000;qXfer:features:read+;QStartNoAckMode+;vContSupported+"),
+            ("QStartNoAckMode", "OK"),
+            ("qXfer:features:read:target.xml:0,a", "m<?xml vers"),
+            ("?", "S05"),
+            ("p0f", "00010000"),
+            // r1 and primask
+            ("P1=00000020", "OK"),
+            ("P13=01000000", "OK"),
+            ("p13", "01000000"),
+            ("Z0,102,2", "OK"),
+            ("c", "S05"),
+            ("p0", "01000000"),
+            ("z0,102,2", "OK"),
+            ("Z2,20000000,4", "OK"),
+            ("vCont;c", "T05watch:20000000;"),
+            ("m20000000,4", "02000000"),
+            ("M20000004,2:abcd", "OK"),
+            ("X20000006,2:}]\x01", "OK"),
+            ("m20000004,4", "abcd7d01"),
+            ("mf0000000,4", "E01"),
+            ("m20000000,ffffffff", &memory),
+            // bkpt
+            ("s", "S05"),
+            // not whole registers
+            ("G000000", "E01"),
+            ("G0", "E01"),
+            ("g", &g),
+            // monitor arm semihosting
+            ("qRcmd,61726d2073656d69686f7374696e67", &semihosting),
+            ("", "OK"),
+            ("D", "OK"),
+        ]);
+    });
+    serve_connection(&mut cpu, listener.accept().unwrap().0, &RunOptions::default()).unwrap();
+    gdb.join().unwrap();
+}
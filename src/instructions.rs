@@ -0,0 +1,67 @@
+use crate::{ast::Thumb, disassemble, Error};
+
+// one decoded instruction and where in the chunk it came from
+#[derive(Debug, PartialEq, Eq)]
+pub struct Decoded<'a> {
+    pub address: u32,
+    pub size: u32,
+    pub bytes: &'a [u8],
+    pub instruction: Thumb,
+}
+
+// Walks a chunk of thumb code starting at a base address.
+//
+// An encoding that fails to decode is yielded as an error and decoding resumes at the next
+// halfword, so data islands and literal pools in the chunk do not stop the iteration.
+#[derive(Debug, Clone)]
+pub struct Instructions<'a> {
+    chunk: &'a [u8],
+    address: u32,
+}
+
+impl<'a> Instructions<'a> {
+    pub fn new(chunk: &'a [u8], address: u32) -> Instructions<'a> {
+        Instructions { chunk, address }
+    }
+
+    // address of the next instruction to be decoded
+    pub fn address(&self) -> u32 {
+        self.address
+    }
+
+    // bytes not decoded yet
+    pub fn as_slice(&self) -> &'a [u8] {
+        self.chunk
+    }
+}
+
+impl<'a> Iterator for Instructions<'a> {
+    type Item = Result<Decoded<'a>, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.chunk.is_empty() {
+            return None;
+        }
+
+        let (item, size) = match disassemble(self.chunk, self.address) {
+            Ok((instruction, rest)) => {
+                let size = self.chunk.len() - rest.len();
+                let decoded = Decoded {
+                    address: self.address,
+                    size: size as u32,
+                    bytes: &self.chunk[..size],
+                    instruction,
+                };
+                (Ok(decoded), size)
+            }
+            // nothing after a truncated instruction can be decoded
+            Err(e @ Error::Truncated { .. }) => (Err(e), self.chunk.len()),
+            // skip a single halfword to resynchronise
+            Err(e) => (Err(e), 2),
+        };
+
+        self.chunk = &self.chunk[size..];
+        self.address = self.address.wrapping_add(size as u32);
+        Some(item)
+    }
+}
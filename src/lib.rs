@@ -1,23 +1,118 @@
 use std::convert::{TryFrom, TryInto};
 use elf::{*, endian::AnyEndian};
+use num_enum::{TryFromPrimitive, TryFromPrimitiveError};
 
 pub mod arm_cpu;
 
 pub mod ast;
 use ast::*;
 
+pub mod instructions;
+use instructions::Instructions;
+
+mod display;
+pub use display::{DisplayDecoded, DisplayThumb};
+
+pub mod symbols;
+use symbols::{Listing, Symbol, SymbolKind, SymbolTable};
+
+pub mod gdb;
+
 #[derive(Debug, PartialEq, Eq)]
 pub enum Error {
     UnableToParseElf,
     UnableToReadElf,
-    ChunkNotLongEnough,
+    // the MEMORY command of a linker script could not be parsed
+    UnableToParseMemoryMap,
+    // the encoding is UNDEFINED in ARMv6-M
+    Undefined { address: u32, encoding: Encoding },
+    // the encoding is UNPREDICTABLE, for example a register the instruction does not allow
+    Unpredictable { address: u32, encoding: Encoding },
+    // the encoding is not allocated to any instruction in the decode tables
+    Unallocated { address: u32, encoding: Encoding },
+    // fewer bytes left than the instruction at address needs, len is what was left
+    Truncated { address: u32, len: usize },
+}
+
+// raw encoding of a single instruction, 32-bit encodings have the first halfword in the top bits
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Encoding {
+    Thumb16(u16),
+    Thumb32(u32),
+}
+
+impl std::fmt::Display for Encoding {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Encoding::Thumb16(x) => write!(f, "{x:04x}"),
+            Encoding::Thumb32(x) => write!(f, "{:04x} {:04x}", x >> 16, x & 0xffff),
+        }
+    }
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::UnableToParseElf => write!(f, "unable to parse elf file"),
+            Error::UnableToReadElf => write!(f, "unable to read elf file"),
+            Error::UnableToParseMemoryMap => write!(f, "unable to parse memory map"),
+            Error::Undefined { address, encoding } => write!(f, "undefined instruction {encoding} at {address:#x}"),
+            Error::Unpredictable { address, encoding } => write!(f, "unpredictable instruction {encoding} at {address:#x}"),
+            Error::Unallocated { address, encoding } => write!(f, "unallocated instruction {encoding} at {address:#x}"),
+            Error::Truncated { address, len } => write!(f, "truncated instruction at {address:#x}, only {len} bytes left"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+// why an encoding failed to decode, turned into an `Error` once the address is known
+#[derive(Debug, PartialEq, Eq)]
+enum DecodeError {
+    Undefined,
+    Unpredictable,
+    Unallocated,
+}
+
+impl DecodeError {
+    fn into_error(self, address: u32, encoding: Encoding) -> Error {
+        match self {
+            DecodeError::Undefined => Error::Undefined { address, encoding },
+            DecodeError::Unpredictable => Error::Unpredictable { address, encoding },
+            DecodeError::Unallocated => Error::Unallocated { address, encoding },
+        }
+    }
+}
+
+// the only field conversion that can fail is an invalid SYSm, which is UNPREDICTABLE
+impl<T: TryFromPrimitive> From<TryFromPrimitiveError<T>> for DecodeError {
+    fn from(_: TryFromPrimitiveError<T>) -> Self {
+        DecodeError::Unpredictable
+    }
+}
+
+// a PT_LOAD program header and its contents
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Segment<'a> {
+    // load address (LMA), where the image is stored, for example .data in flash
+    pub paddr: u32,
+    // run address (VMA)
+    pub vaddr: u32,
+    // contents from the file, zeros follow up to mem_size like for .bss
+    pub data: &'a [u8],
+    pub mem_size: u32,
+    // abi::PF_R, PF_W and PF_X
+    pub flags: u32,
 }
 
 #[derive(Debug)]
 pub struct Program<'a> {
     text: &'a [u8],
     start_addr: u32,
-    start_stack: u32,
+    // _stack_start, missing from stripped images
+    start_stack: Option<u32>,
+    symbols: SymbolTable,
+    segments: Vec<Segment<'a>>,
 }
 
 impl Program<'_> {
@@ -29,20 +124,44 @@ impl Program<'_> {
         self.text
     }
 
-    pub fn get_start_stack(&self) -> u32 {
+    pub fn get_start_stack(&self) -> Option<u32> {
         self.start_stack
     }
 
-    pub fn build(text: &[u8], start_addr: u32, start_stack: u32) -> Program {
-        Program { text: text, start_addr: start_addr, start_stack: start_stack }
+    pub fn get_symbols(&self) -> &SymbolTable {
+        &self.symbols
+    }
+
+    pub fn get_segments(&self) -> &[Segment<'_>] {
+        &self.segments
+    }
+
+    pub fn instructions(&self) -> Instructions<'_> {
+        Instructions::new(self.text, self.start_addr)
+    }
+
+    // objdump style listing of .text labelled with the symbol table
+    pub fn listing(&self) -> Listing<'_> {
+        Listing::new(self.text, self.start_addr, &self.symbols)
+    }
+
+    pub fn build(text: &[u8], start_addr: u32, start_stack: u32) -> Program<'_> {
+        let segment = Segment {
+            paddr: start_addr,
+            vaddr: start_addr,
+            data: text,
+            mem_size: text.len() as u32,
+            flags: abi::PF_R | abi::PF_X,
+        };
+        Program { text, start_addr, start_stack: Some(start_stack), symbols: SymbolTable::default(), segments: vec![segment] }
     }
 }
 
-pub fn read_elf_file<'a>(file_data: &'a Vec<u8>) -> Result<Program, Error> {
-    let file = match ElfBytes::<AnyEndian>::minimal_parse(file_data.as_slice()) {
+pub fn read_elf_file(file_data: &[u8]) -> Result<Program<'_>, Error> {
+    let file = match ElfBytes::<AnyEndian>::minimal_parse(file_data) {
         Ok(x) => x,
         Err(_) => {
-            println!("unable to parse file");
+            // not an ELF file
             return Err(Error::UnableToParseElf)
         }
     };
@@ -53,98 +172,149 @@ pub fn read_elf_file<'a>(file_data: &'a Vec<u8>) -> Result<Program, Error> {
             match x {
                 Some(n) => n,
                 None => {
-                    println!("No section .text found");
+                    // no .text section
                     return Err(Error::UnableToParseElf)
                 }
             }
         },
         Err(_) => {
-            println!("Unable to parse section table.");
+            // unable to parse the section table
             return Err(Error::UnableToParseElf);
         }
     };
 
-    let offset = text_header.sh_offset;
     let size = text_header.sh_size;
     let start_addr = text_header.sh_addr;
 
-    println!("Offset: {offset:#x} size: {size:#x}");
-
     let text_data = match file.section_data(&text_header){
         Ok(x) => match x.1 {
             Some(_) => {
-                println!(".text data is compressed aborting");
+                // .text is compressed
                 return Err(Error::UnableToParseElf)
             },
             None => x.0
         },
         Err(_) => {
-            println!("unable to parse");
             return Err(Error::UnableToParseElf)
         }
     };
 
-    let mut chunk = text_data;
+    let mut segments = vec![];
+    for header in file.segments().into_iter().flatten() {
+        if header.p_type != abi::PT_LOAD || header.p_memsz == 0 {
+            continue;
+        }
+        let data = match file.segment_data(&header) {
+            Ok(x) => x,
+            Err(_) => return Err(Error::UnableToParseElf),
+        };
+        segments.push(Segment {
+            paddr: header.p_paddr as u32,
+            vaddr: header.p_vaddr as u32,
+            data,
+            mem_size: header.p_memsz as u32,
+            flags: header.p_flags,
+        });
+    }
 
     let common = match file.find_common_data() {
         Ok(x) => x,
         Err(_) => {
-            println!("unable to parse");
             return Err(Error::UnableToParseElf)
         }
     };
 
-    let (symtab, strtab) = (common.symtab.unwrap(), common.symtab_strs.unwrap());
+    let mut stack_start: Option<u32> = None;
+    let mut symbols = vec![];
 
-    let mut stack_start_symb: Option<symbol::Symbol> = None;
+    // stripped images have no symbol table, they boot from the vector table
+    let strtab = common.symtab_strs.unwrap_or_default();
 
-    for n in symtab {
+    for n in common.symtab.into_iter().flatten() {
         let name_idx = n.st_name;
-        let name = strtab.get(name_idx as usize).unwrap();
+        let name = match strtab.get(name_idx as usize) {
+            Ok(x) => x,
+            Err(_) => return Err(Error::UnableToParseElf),
+        };
 
         if name == "_stack_start" {
-            stack_start_symb = Some(n);
+            stack_start = Some(n.st_value as u32);
+        }
+
+        // only symbols inside .text label the listing
+        let inside_text = n.st_value >= start_addr && n.st_value < start_addr + size;
+        if n.is_undefined() || n.st_shndx == abi::SHN_ABS || !inside_text || name.is_empty() {
+            continue;
         }
+        let kind = match (Symbol::mapping(name), n.st_symtype()) {
+            (Some(kind), _) => kind,
+            (None, abi::STT_FUNC) => SymbolKind::Function,
+            (None, abi::STT_OBJECT) => SymbolKind::Object,
+            (None, abi::STT_NOTYPE) => SymbolKind::NoType,
+            _ => continue,
+        };
+        symbols.push(Symbol::new(name, n.st_value as u32, n.st_size as u32, kind));
     }
 
     Ok(Program{
         start_addr: start_addr as u32,
         text: text_data,
-        start_stack: stack_start_symb.unwrap().st_value as u32,
+        start_stack: stack_start,
+        symbols: SymbolTable::new(symbols),
+        segments,
     })
 }
 
-fn disassemble32(instr: u32) -> Thumb32 {
+fn disassemble32(instr: u32) -> Result<Thumb32, DecodeError> {
     let op1 = (instr >> 27) & 0b11;
     let op2 = (instr >> 15) & 0b1;
 
-    println!("instr: {instr:032b}, op1: {op1:02b}, op2: {op2:01b}");
-
+    // A5.3 32-bit Thumb instruction encoding
+    // op1 op
+    // 01  x  UNDEFINED
+    // 10  0  UNDEFINED
+    // 10  1  Branch and miscellaneous control on page A5-86
+    // 11  x  UNDEFINED
     let thumb = match (op1, op2) {
         (0b10, 0b1) => { // Branch and miscellaneous control A5.3.1
             let op1 = (instr >> 20) & 0b1111111;
             let op2 = (instr >> 12) & 0b111;
-            println!("op1: {op1:02b}, op2: {op2:01b}");
 
             match (op1, op2) {
-                (0b1111111, 0b010) => { // always undefined
-                    println!("Undefined instruction");
-                    unimplemented!()
+                (0b1111111, 0b010) => { // Permanently UNDEFINED A6.7.70
+                    // A6.7.70 UDF encoding T2
+                    let imm4 = (instr >> 16) & 0xf;
+                    let imm12 = instr & 0xfff;
+                    Thumb32::UdfT2((imm4 << 12) | imm12)
                 },
                 (0b0111000 | 0b0111001, 0b000 | 0b010) => { // MSR (register) B4.2.3
                     let rn = ((instr >> 16) & 0xf) as u8;
                     let special = (instr & 0xff) as u8;
-                    Thumb32::MsrT1(rn.try_into().unwrap(), special.try_into().unwrap())
+                    if rn == 13 || rn == 15 {
+                        return Err(DecodeError::Unpredictable);
+                    }
+                    Thumb32::MsrT1(rn.try_into()?, special.try_into()?)
                 },
-                (0b0111011, 0b000 | 0b010) => { // Miscellaneous control instructions A5.3.1
-                    unimplemented!()
+                (0b0111011, 0b000 | 0b010) => { // Miscellaneous control instructions A5.3.2
+                    let op = (instr >> 4) & 0b1111;
+                    let option = (instr & 0b1111) as u8;
+
+                    match op {
+                        0b0100 => Thumb32::DsbT1(option), // Data Synchronization Barrier A6.7.22
+                        0b0101 => Thumb32::DmbT1(option), // Data Memory Barrier A6.7.21
+                        0b0110 => Thumb32::IsbT1(option), // Instruction Synchronization Barrier A6.7.24
+                        _ => return Err(DecodeError::Undefined),
+                    }
                 },
                 (0b0111110 | 0b0111111, 0b000 | 0b010) => { // MRS B4.2.2
                     let rd = ((instr >> 8) & 0xf) as u8;
                     let sr = (instr & 0xff) as u8;
-                    Thumb32::MrsT1(rd.try_into().unwrap(), sr.try_into().unwrap())
+                    if rd == 13 || rd == 15 {
+                        return Err(DecodeError::Unpredictable);
+                    }
+                    Thumb32::MrsT1(rd.try_into()?, sr.try_into()?)
                 },
-                (_, 0b101 | 0b111) => { // BL A6.7.13EOR
+                (_, 0b101 | 0b111) => { // BL A6.7.13
                     let j1 = (instr >> 13) & 0b1;
                     let j2 = (instr >> 11) & 0b1;
                     let s = (instr >> 26) & 0b1;
@@ -153,46 +323,56 @@ fn disassemble32(instr: u32) -> Thumb32 {
                     let i1 = (!(j1 ^ s)) & 0b1;
                     let i2 = (!(j2 ^ s)) & 0b1;
                     let imm32 = (((((s << 24) | (i1 << 23) | (i2 << 22) | (imm10 << 12) | (imm11 << 1)) << 7) as i32) >> 7) as u32;
-                    Thumb32::BlT1(imm32.try_into().unwrap())
-                },
-                _ => {
-                    println!("Undefined instruction");
-                    unimplemented!()
+                    Thumb32::BlT1(imm32)
                 },
+                _ => return Err(DecodeError::Undefined),
             }
         },
-        (_, _) => {
-            println!("Undefined instruction");
-            unimplemented!()
-        }
+        (_, _) => return Err(DecodeError::Undefined),
     };
 
-    thumb
+    Ok(thumb)
 }
 
-fn disassemble16(instr: u16) -> Thumb16 {
+fn disassemble16(instr: u16) -> Result<Thumb16, DecodeError> {
     let op6 = instr >> 10; // b15..10
-    println!("instruction {:016b} opcode {:06b}", instr, op6);
 
     let thumb16 = match op6 {
         // 00xxxx Shift (immediate), add, subtract, move, and compare on page A5-79
         0b000000..=0b001111 => {
-            println!("00xxxx Shift (immediate), add, subtract, move, and compare on page A5-79");
             let op5 = (instr >> 9) & 0b11111;
             match op5 {
                 // 000xx Logical Shift Left (a)     LSL (immediate) on page A6-135
                 0b00000..=0b00011 => {
-                    unimplemented!()
+                    // A6.7.35 LSL (immediate) T1 Encoding
+                    let imm5 = ((instr >> 6) & 0b11111) as u32;
+                    let rm = ((instr >> 3) & 0b111) as u8;
+                    let rd = (instr & 0b111) as u8;
+                    if imm5 == 0 {
+                        // (a) When opcode is 0b00000, and bits[10:6] == 0b00000, see MOV (register) on page A6-140
+                        // A6.7.40 MOV (register) T2 Encoding
+                        Thumb16::MovsRegT2(rm.try_into()?, rd.try_into()?)
+                    } else {
+                        Thumb16::LslImmT1(imm5, rm.try_into()?, rd.try_into()?)
+                    }
                 }
 
                 // 001xx Logical Shift Right        LSR (immediate) on page A6-137
                 0b00100..=0b00111 => {
-                    unimplemented!()
+                    // A6.7.37 LSR (immediate) T1 Encoding
+                    let imm32 = decode_imm_shift((instr >> 6) & 0b11111);
+                    let rm = ((instr >> 3) & 0b111) as u8;
+                    let rd = (instr & 0b111) as u8;
+                    Thumb16::LsrImmT1(imm32, rm.try_into()?, rd.try_into()?)
                 }
 
                 // 010xx Arithmetic Shift Right     ASR (immediate) on page A6-108
                 0b01000..=0b01011 => {
-                    unimplemented!()
+                    // A6.7.8 ASR (immediate) T1 Encoding
+                    let imm32 = decode_imm_shift((instr >> 6) & 0b11111);
+                    let rm = ((instr >> 3) & 0b111) as u8;
+                    let rd = (instr & 0b111) as u8;
+                    Thumb16::AsrImmT1(imm32, rm.try_into()?, rd.try_into()?)
                 }
 
                 // 01100 Add register               ADD (register) on page A6-102
@@ -202,15 +382,23 @@ fn disassemble16(instr: u16) -> Thumb16 {
                     let rn = ((instr >> 3) & 0b111) as u8;
                     let rd = (instr & 0b111) as u8;
                     Thumb16::AddsRegT1(
-                        rm.try_into().unwrap(),
-                        rn.try_into().unwrap(),
-                        rd.try_into().unwrap(),
+                        rm.try_into()?,
+                        rn.try_into()?,
+                        rd.try_into()?,
                     )
                 }
 
                 // 01101 Subtract register          SUB (register) on page A6-165
                 0b01101 => {
-                    unimplemented!()
+                    // A6.7.64 SUB (register) encoding T1
+                    let rm = ((instr >> 6) & 0b111) as u8;
+                    let rn = ((instr >> 3) & 0b111) as u8;
+                    let rd = (instr & 0b111) as u8;
+                    Thumb16::SubsRegT1(
+                        rm.try_into()?,
+                        rn.try_into()?,
+                        rd.try_into()?,
+                    )
                 }
 
                 // 01110 Add 3-bit immediate        ADD (immediate) on page A6-101
@@ -218,13 +406,17 @@ fn disassemble16(instr: u16) -> Thumb16 {
                     // A6.7.2 ADD (immediate)
                     let imm32 = ((instr >> 6) & 0b111) as u32;
                     let rn = ((instr >> 3) & 0b111) as u8;
-                    let rd = ((instr >> 0) & 0b111) as u8;
-                    Thumb16::AddsImmT1(imm32, rn.try_into().unwrap(), rd.try_into().unwrap())
+                    let rd = (instr & 0b111) as u8;
+                    Thumb16::AddsImmT1(imm32, rn.try_into()?, rd.try_into()?)
                 }
 
                 // 01111 Subtract 3-bit immediate   SUB (immediate) on page A6-164
                 0b01111 => {
-                    unimplemented!()
+                    // A6.7.63 SUB (immediate) T1 Encoding
+                    let imm32 = ((instr >> 6) & 0b111) as u32;
+                    let rn = ((instr >> 3) & 0b111) as u8;
+                    let rd = (instr & 0b111) as u8;
+                    Thumb16::SubsImmT1(imm32, rn.try_into()?, rd.try_into()?)
                 }
 
                 // 100xx Move                       MOV (immediate) on page A6-139
@@ -233,7 +425,7 @@ fn disassemble16(instr: u16) -> Thumb16 {
                     let imm32 = (instr & 0b11111111) as u32;
                     let rd = ((instr >> 8) & 0b111) as u8;
 
-                    Thumb16::MovsImmT1(rd.try_into().unwrap(), imm32)
+                    Thumb16::MovsImmT1(rd.try_into()?, imm32)
                 }
 
                 // 101xx Compare                    CMP (immediate) on page A6-117
@@ -241,252 +433,337 @@ fn disassemble16(instr: u16) -> Thumb16 {
                     // A6.7.17 CMP (immediate)
                     let imm32 = (instr & 0b11111111) as u32;
                     let rn = ((instr >> 8) & 0b111) as u8;
-                    Thumb16::CmpImmT1(rn.try_into().unwrap(), imm32)
+                    Thumb16::CmpImmT1(rn.try_into()?, imm32)
                 }
                 // 110xx Add 8-bit immediate        ADD (immediate) on page A6-101
                 0b11000..=0b11011 => {
-                    unimplemented!()
+                    // A6.7.2 ADD (immediate) T2 Encoding
+                    let rdn = ((instr >> 8) & 0b111) as u8;
+                    let imm32 = (instr & 0b11111111) as u32;
+                    Thumb16::AddsImmT2(rdn.try_into()?, imm32)
                 }
                 // 111xx Subtract 8-bit immediate   SUB (immediate) on page A6-164
                 0b11100..=0b11111 => {
-                    unimplemented!()
+                    // A6.7.63 SUB (immediate) T2 Encoding
+                    let rdn = ((instr >> 8) & 0b111) as u8;
+                    let imm32 = (instr & 0b11111111) as u32;
+                    Thumb16::SubsImmT2(rdn.try_into()?, imm32)
                 }
-                _ => unimplemented!(),
+                _ => return Err(DecodeError::Unallocated),
             }
         }
 
         // 010000 Data processing on page A5-80
         0b010000 => {
-            println!("010000 Data processing on page A5-80");
             let dp_op_index = ((instr >> 6) & 0b1111) as u8;
-            println!("index {}", dp_op_index);
-            let dp_op = DpOpcode::try_from(dp_op_index).unwrap();
-            println!("dp {:?}", dp_op);
+            let dp_op = DpOpcode::try_from(dp_op_index)?;
             let reg_3 = ((instr >> 3) & 0b111) as u8;
             let reg_0 = (instr & 0b111) as u8;
 
-            Thumb16::DataProc(dp_op, reg_3.try_into().unwrap(), reg_0.try_into().unwrap())
+            Thumb16::DataProc(dp_op, reg_3.try_into()?, reg_0.try_into()?)
         }
 
         // 010001 Special data instructions and branch and exchange on page A5-81
         0b010001 => {
-            println!("010001 Special data instructions and branch and exchange on page A5-81");
 
             let op4 = (instr >> 6) & 0b1111;
-            println!("Op4: {:04b}", op4);
 
             match op4 {
                 // 00xx Add Registers       ADD (register) on page A6-102
                 0b0000..=0b0011 => {
-                    unimplemented!()
+                    // A6.7.3 ADD (register) encoding T2
+                    let rm = ((instr >> 3) & 0b1111) as u8;
+                    let rdn = (((instr >> 4) & 0b1000) | (instr & 0b111)) as u8;
+                    if rdn == 15 && rm == 15 {
+                        return Err(DecodeError::Unpredictable);
+                    }
+                    Thumb16::AddRegT2(rm.try_into()?, rdn.try_into()?)
                 }
 
                 // 0100 UNPREDICTABLE       -
                 0b0100 => {
-                    unimplemented!()
+                    return Err(DecodeError::Unpredictable);
                 }
                 // 0101 Compare Registers   CMP (register) on page A6-118
-                0b0101 => {
-                    unimplemented!()
-                }
-
                 // 011x
-                0b0110..=0b0111 => {
-                    unimplemented!()
+                0b0101..=0b0111 => {
+                    // A6.7.18 CMP (register) encoding T2
+                    let rm = ((instr >> 3) & 0b1111) as u8;
+                    let rn = (((instr >> 4) & 0b1000) | (instr & 0b111)) as u8;
+                    if rn == 15 || rm == 15 {
+                        return Err(DecodeError::Unpredictable);
+                    }
+                    Thumb16::CmpRegT2(rm.try_into()?, rn.try_into()?)
                 }
 
                 // 10xx Move Registers      MOV (register) on page A6-140
                 0b1000..=0b1011 => {
                     let rm = ((instr >> 3) & 0b1111) as u8;
                     let rd = (((instr >> 4) & 0b1000) | (instr & 0b111)) as u8;
-                    Thumb16::MovT1(rm.try_into().unwrap(), rd.try_into().unwrap())
+                    Thumb16::MovT1(rm.try_into()?, rd.try_into()?)
                 }
                 // 110x Branch and Exchange BX on page A6-115
                 0b1100..=0b1101 => {
                     let rm = ((instr >> 3) & 0b1111) as u8;
-                    assert!(instr & 0b111 == 0);
-                    Thumb16::BxT1(rm.try_into().unwrap())
+                    if instr & 0b111 != 0 {
+                        return Err(DecodeError::Unpredictable);
+                    }
+                    Thumb16::BxT1(rm.try_into()?)
                 }
 
                 // 111x Branch with Link and Exchange BLX (register) on page A6-114
                 0b1110..=0b1111 => {
-                    unimplemented!()
+                    // A6.7.14 BLX (register) encoding T1
+                    let rm = ((instr >> 3) & 0b1111) as u8;
+                    if rm == 15 || instr & 0b111 != 0 {
+                        return Err(DecodeError::Unpredictable);
+                    }
+                    Thumb16::BlxRegT1(rm.try_into()?)
                 }
-                _ => unimplemented!(),
+                _ => return Err(DecodeError::Unallocated),
             }
         }
 
         // 01001x Load from Literal Pool, see LDR (literal) on page A6-127
         0b010010..=0b010011 => {
-            println!("01001x Load from Literal Pool, see LDR (literal) on page A6-127");
             let rt = ((instr >> 8) & 0b111) as u8;
-            let imm32 = (instr & 0b11111111) << 2;
-            Thumb16::LdrImmT1(rt.try_into().unwrap(), imm32.try_into().unwrap())
+            let imm32 = ((instr & 0b11111111) << 2) as u32;
+            Thumb16::LdrLiteralT1(rt.try_into()?, imm32)
         }
 
         // 0101xx Load/store single data item on page A5-82
         0b010100..=0b010111 => {
-            println!("0101xx Load/store single data item on page A5-82");
             let opb = (instr >> 9) & 0b111;
-            println!("opa: 0101 opB: {opb:03b}");
+            let rm: Register = (((instr >> 6) & 0b111) as u8).try_into()?;
+            let rn: Register = (((instr >> 3) & 0b111) as u8).try_into()?;
+            let rt: Register = ((instr & 0b111) as u8).try_into()?;
             match opb {
                 0b000 => {
-                    println!("0101 000 Store Register STR (register) on page A6-159");
-                    unimplemented!()
+                    // 0101 000 Store Register STR (register) on page A6-159
+                    Thumb16::StrRegT1(rm, rn, rt)
                 }
                 0b001 => {
-                    println!("0101 001 Store Register Halfword STRH (register) on page A6-163");
-                    unimplemented!()
+                    // 0101 001 Store Register Halfword STRH (register) on page A6-163
+                    Thumb16::StrhRegT1(rm, rn, rt)
                 }
                 0b010 => {
-                    println!("0101 010 Store Register Byte STRB (register) on page A6-161");
-                    unimplemented!()
+                    // 0101 010 Store Register Byte STRB (register) on page A6-161
+                    Thumb16::StrbRegT1(rm, rn, rt)
                 }
                 0b011 => {
-                    println!("0101 011 Load Register Signed Byte LDRSB (register) on page A6-133");
-                    unimplemented!()
+                    // 0101 011 Load Register Signed Byte LDRSB (register) on page A6-133
+                    Thumb16::LdrsbRegT1(rm, rn, rt)
                 }
                 0b100 => {
-                    println!("0101 100 Load Register LDR (register) on page A6-128");
-                    unimplemented!()
+                    // 0101 100 Load Register LDR (register) on page A6-128
+                    Thumb16::LdrRegT1(rm, rn, rt)
                 }
                 0b101 => {
-                    println!("0101 101 Load Register Halfword LDRH (register) on page A6-132");
-                    unimplemented!()
+                    // 0101 101 Load Register Halfword LDRH (register) on page A6-132
+                    Thumb16::LdrhRegT1(rm, rn, rt)
                 }
                 0b110 => {
-                    println!("0101 110 Load Register Byte LDRB (register) on page A6-130");
-                    unimplemented!()
+                    // 0101 110 Load Register Byte LDRB (register) on page A6-130
+                    Thumb16::LdrbRegT1(rm, rn, rt)
                 }
                 0b111 => {
-                    println!("0101 111 Load Register Signed Halfword LDRSH (register) on page A6-134");
-                    unimplemented!()
-                }
-                _ => {
-                    unimplemented!()
+                    // 0101 111 Load Register Signed Halfword LDRSH (register) on page A6-134
+                    Thumb16::LdrshRegT1(rm, rn, rt)
                 }
+                _ => return Err(DecodeError::Unallocated),
             }
         }
 
         // 011xxx Load/store single data item on page A5-82
         0b011000..=0b011111 => {
-            println!("0101xx Load/store single data item on page A5-82");
-            unimplemented!()
+            let opa = instr >> 12;
+            let opb = (instr >> 9) & 0b111;
+            let imm5 = ((instr >> 6) & 0b11111) as u32;
+            let rn: Register = (((instr >> 3) & 0b111) as u8).try_into()?;
+            let rt: Register = ((instr & 0b111) as u8).try_into()?;
+
+            match (opa, opb) {
+                (0b0110, 0b000..=0b011) => {
+                    // 0110 0xx Store Register STR (immediate) on page A6-158
+                    Thumb16::StrImmT1(imm5 << 2, rn, rt)
+                }
+                (0b0110, 0b100..=0b111) => {
+                    // 0110 1xx Load Register LDR (immediate) on page A6-126
+                    Thumb16::LdrImmT1(imm5 << 2, rn, rt)
+                }
+                (0b0111, 0b000..=0b011) => {
+                    // 0111 0xx Store Register Byte STRB (immediate) on page A6-160
+                    Thumb16::StrbImmT1(imm5, rn, rt)
+                }
+                (0b0111, 0b100..=0b111) => {
+                    // 0111 1xx Load Register Byte LDRB (immediate) on page A6-129
+                    Thumb16::LdrbImmT1(imm5, rn, rt)
+                }
+                (_, _) => return Err(DecodeError::Unallocated),
+            }
         }
 
-        // 011xxx Load/store single data item on page A5-82
+        // 100xxx Load/store single data item on page A5-82
         0b100000..=0b100111 => {
-            println!("0101xx Load/store single data item on page A5-82");
-            let opa = (instr >> 12);
+            let opa = instr >> 12;
             let opb = (instr >> 9) & 0b111;
-            println!("opa: {opa:04b} opB: {opb:03b}");
 
             match (opa, opb) {
-                (0b1000, 0b0..=0b011) => {
-                    println!("1000 0xx Store Register Halfword STRH (immediate) on page A6-162");
-                    unimplemented!()
+                (0b1000, 0b000..=0b011) => {
+                    // 1000 0xx Store Register Halfword STRH (immediate) on page A6-162
+                    let imm32 = (((instr >> 6) & 0b11111) << 1) as u32;
+                    let rn = ((instr >> 3) & 0b111) as u8;
+                    let rt = (instr & 0b111) as u8;
+                    Thumb16::StrhImmT1(imm32, rn.try_into()?, rt.try_into()?)
                 }
                 (0b1000, 0b100..=0b111) => {
-                    println!("1000 1xx Load Register Halfword LDRH (immediate) on page A6-131");
-                    unimplemented!()
+                    // 1000 1xx Load Register Halfword LDRH (immediate) on page A6-131
+                    let imm32 = (((instr >> 6) & 0b11111) << 1) as u32;
+                    let rn = ((instr >> 3) & 0b111) as u8;
+                    let rt = (instr & 0b111) as u8;
+                    Thumb16::LdrhImmT1(imm32, rn.try_into()?, rt.try_into()?)
                 }
                 (0b1001, 0b000..=0b011) => {
-                    println!("1001 0xx Store Register SP relative STR (immediate) on page A6-158");
+                    // 1001 0xx Store Register SP relative STR (immediate) on page A6-158
                     let rt = (instr >> 8) & 0b111;
                     let imm32 = ((instr & 0xff) << 2) as u32;
-                    Thumb16::STRImmT2((rt as u8).try_into().unwrap(), imm32)
+                    Thumb16::StrImmT2((rt as u8).try_into()?, imm32)
                 }
                 (0b1001, 0b100..=0b111) => {
-                    println!("1001 1xx Load Register SP relative LDR (immediate) on page A6-126");
-                    unimplemented!()
+                    // 1001 1xx Load Register SP relative LDR (immediate) on page A6-126
+                    let rt = (instr >> 8) & 0b111;
+                    let imm32 = ((instr & 0xff) << 2) as u32;
+                    Thumb16::LdrImmT2((rt as u8).try_into()?, imm32)
                 }
-                (_, _) => unimplemented!()
+                (_, _) => return Err(DecodeError::Unallocated),
             }
         }
 
         // 10100x Generate PC-relative address, see ADR on page A6-106
         0b101000..=0b101001 => {
-            println!("10100x Generate PC-relative address, see ADR on page A6-106");
-            unimplemented!()
+            // A6.7.6 ADR T1 encoding
+            let rd = (instr >> 8) & 0b111;
+            let imm32 = ((instr & 0xff) << 2) as u32;
+            Thumb16::AdrT1((rd as u8).try_into()?, imm32)
         }
 
         // 10101x Generate SP-relative address, see ADD (SP plus immediate) on page A6-104
         0b101010..=0b101011 => {
-            println!(
-                "10101x Generate SP-relative address, see ADD (SP plus immediate) on page A6-104"
-            );
             // T1 encoding
             let rd = (instr >> 8) & 0b111;
             let imm32 = ((instr & 0xff) << 2) as u32;
-            Thumb16::AddSpImmT1((rd as u8).try_into().unwrap(), imm32)
+            Thumb16::AddSpImmT1((rd as u8).try_into()?, imm32)
         }
 
         // 1011xx Miscellaneous 16-bit instructions on page A5-83
         0b101100..=0b101111 => {
-            println!("1011xx Miscellaneous 16-bit instructions on page A5-83");
-            let op = ((instr >> 5) & 0b1111111);
-            println!("OP: {op:07b}");
+            let op = (instr >> 5) & 0b1111111;
+            let rm = ((instr >> 3) & 0b111) as u8;
+            let rd = (instr & 0b111) as u8;
             match op {
                 0b0000000..=0b0000011 => { // Add Immediate to SP A6-104
-                    unimplemented!()
+                    let imm32 = ((instr & 0b1111111) << 2) as u32;
+                    Thumb16::AddSpImmT2(imm32)
                 },
                 0b0000100..=0b0000111 => { // Subtract Immediate from SP A6-166
-                    let imm32 = (instr & 0b1111111) as u32;
+                    let imm32 = ((instr & 0b1111111) << 2) as u32;
                     Thumb16::SubSpSpImmT1(imm32)
                 },
                 0b0010000..=0b0010001 => { // Signed Extend Halfword A6-169
-                    unimplemented!()
+                    Thumb16::SxthT1(rm.try_into()?, rd.try_into()?)
                 },
                 0b0010010..=0b0010011 => { // Signed Extend Byte A6-169
-                    unimplemented!()
+                    Thumb16::SxtbT1(rm.try_into()?, rd.try_into()?)
                 },
                 0b0010100..=0b0010101 => { // Unsigned Extend Halfword A6-173
-                    unimplemented!()
+                    Thumb16::UxthT1(rm.try_into()?, rd.try_into()?)
                 },
                 0b0010110..=0b0010111 => { // Unsigned Extend Byte A6-172
-                    unimplemented!()
+                    Thumb16::UxtbT1(rm.try_into()?, rd.try_into()?)
                 },
                 0b0100000..=0b0101111 => { // Push Multiple Registers A6-149
                     let registers = (((instr >> 8) & 0b1) << 14) | (instr & 0xff);
+                    if registers == 0 {
+                        return Err(DecodeError::Unpredictable);
+                    }
                     Thumb16::Push(RegisterList(registers))
                 },
+                0b0110011 => { // Change Processor State A6-119
+                    // only the I bit (PRIMASK) exists in ARMv6-M
+                    if instr & 0b1111 != 0b0010 {
+                        return Err(DecodeError::Unpredictable);
+                    }
+                    let im = (instr >> 4) & 0b1 == 1;
+                    Thumb16::Cps(im)
+                },
+                0b1010000..=0b1010001 => { // Byte-Reverse Word A6-152
+                    Thumb16::RevT1(rm.try_into()?, rd.try_into()?)
+                },
+                0b1010010..=0b1010011 => { // Byte-Reverse Packed Halfword A6-153
+                    Thumb16::Rev16T1(rm.try_into()?, rd.try_into()?)
+                },
+                0b1010110..=0b1010111 => { // Byte-Reverse Signed Halfword A6-154
+                    Thumb16::RevshT1(rm.try_into()?, rd.try_into()?)
+                },
+                0b1100000..=0b1101111 => { // Pop Multiple Registers A6-148
+                    let registers = (((instr >> 8) & 0b1) << 15) | (instr & 0xff);
+                    if registers == 0 {
+                        return Err(DecodeError::Unpredictable);
+                    }
+                    Thumb16::Pop(RegisterList(registers))
+                },
                 0b1110000..=0b1110111 => { // BKPT
                     let imm32 = (instr & 0xff) as u32;
                     Thumb16::Bkpt(imm32)
-                }
-                _ => {
-                    unimplemented!()
-                }
+                },
+                0b1111000..=0b1111111 => { // Hint instructions A5-84
+                    let op_a = (instr >> 4) & 0b1111;
+                    let op_b = instr & 0b1111;
+                    // If-Then is not part of ARMv6-M
+                    if op_b != 0 {
+                        return Err(DecodeError::Undefined);
+                    }
+                    match op_a {
+                        0b0000 => Thumb16::Nop,
+                        0b0001 => Thumb16::Yield,
+                        0b0010 => Thumb16::Wfe,
+                        0b0011 => Thumb16::Wfi,
+                        0b0100 => Thumb16::Sev,
+                        // unallocated hints execute as NOP
+                        _ => Thumb16::Nop,
+                    }
+                },
+                _ => return Err(DecodeError::Unallocated),
             }
         }
 
         // 11000x Store multiple registers, see STM, STMIA, STMEA on page A6-157
         0b110000..=0b110001 => {
-            println!("11000x Store multiple registers, see STM, STMIA, STMEA on page A6-157");
             let rn = ((instr >> 8) & 0b111) as u8;
-            let registers: u16 = (instr & 0xff);
-            println!("Registers: {registers:016b}");
-            Thumb16::Stm(rn.try_into().unwrap(), RegisterList(registers))
+            let registers: u16 = instr & 0xff;
+            if registers == 0 {
+                return Err(DecodeError::Unpredictable);
+            }
+            Thumb16::Stm(rn.try_into()?, RegisterList(registers))
         }
 
         // Register lists
         // {R2} = 0b0000000000000100
         // {R3} = 0b0000000000001000
-        // 
+        //
         // 11001x Load multiple registers, see LDM, LDMIA, LDMFD on page A6-125
         0b110010..=0b110011 => {
-            println!("11001x Load multiple registers, see LDM, LDMIA, LDMFD on page A6-125");
             let rn = ((instr >> 8) & 0b111) as u8;
-            let registers: u16 = (instr & 0xff);
-            println!("Registers: {registers:016b}");
-            Thumb16::Ldm(rn.try_into().unwrap(), RegisterList(registers))
+            let registers: u16 = instr & 0xff;
+            if registers == 0 {
+                return Err(DecodeError::Unpredictable);
+            }
+            Thumb16::Ldm(rn.try_into()?, RegisterList(registers))
         }
 
         // 1101xx Conditional branch, and Supervisor Call on page A5-84
         0b110100..=0b110111 => {
-            println!("1101xx Conditional branch, and Supervisor Call on page A5-84");
             let op4 = (instr >> 8) & 0b1111;
-            println!("OP: {op4:04b}");
             match op4 {
                 // 1110 Permanently UNDEFINED   UDF on page A6-171a
                 0b1110 => {
@@ -496,7 +773,9 @@ fn disassemble16(instr: u16) -> Thumb16 {
 
                 // 1111 Supervisor Call         SVC on page A6-167
                 0b1111 => {
-                    unimplemented!()
+                    // A6.7.66 SVC T1 encoding
+                    let imm32 = (instr & 0xff) as u32;
+                    Thumb16::Svc(imm32)
                 }
                 // not 111x Conditional branch  B on page A6-110
                 _ => {
@@ -504,30 +783,36 @@ fn disassemble16(instr: u16) -> Thumb16 {
                     let imm32 = ((((instr & 0xff) << 1) as i32) << 23) >> 23;
                     let cond: u8 = ((instr >> 8) & 0b1111) as u8;
 
-                    Thumb16::BImmT1(cond.try_into().unwrap(), imm32 as u32)
+                    Thumb16::BImmT1(cond.try_into()?, imm32 as u32)
                 }
             }
         }
 
         // 11100x Unconditional Branch, see B on page A6-110
         0b111000..=0b111001 => {
-            println!("11100x Unconditional Branch, see B on page A6-110");
-            let imm32 = (((((instr & 0b11111111111) << 1) as i32) << 20) >> 20);
+            let imm32 = (((instr & 0b11111111111) << 1) as i32) << 20 >> 20;
             Thumb16::BT2(imm32 as u32)
         }
 
-        _ => {
-            println!("illegal opcode {:b}", op6);
-            unimplemented!()
-        }
+        // 11101x and up are the first halfword of a 32-bit instruction
+        _ => return Err(DecodeError::Undefined),
     };
 
-    thumb16
+    Ok(thumb16)
+}
+
+// A6.4.2 DecodeImmShift() for LSR and ASR, a shift of 0 encodes a shift of 32
+fn decode_imm_shift(imm5: u16) -> u32 {
+    if imm5 == 0 {
+        32
+    } else {
+        imm5 as u32
+    }
 }
 
-pub fn disassemble(chunk: &[u8]) -> Result<(Thumb, &[u8]), Error> {
+pub fn disassemble(chunk: &[u8], address: u32) -> Result<(Thumb, &[u8]), Error> {
     if chunk.len() < 2 {
-        return Err(Error::ChunkNotLongEnough);
+        return Err(Error::Truncated { address, len: chunk.len() });
     }
 
     let first = chunk[1] >> 3;
@@ -536,21 +821,21 @@ pub fn disassemble(chunk: &[u8]) -> Result<(Thumb, &[u8]), Error> {
 
     let thumb = match first {
         0b11101..=0b11111 => { // 32 bit instruction
-            println!("32 bit instruction");
+            if chunk.len() < 4 {
+                return Err(Error::Truncated { address, len: chunk.len() });
+            }
             // perhaps better to use the `byteorder` crate
-            let arr0: [u8; 2] = chunk[0..=1].try_into().unwrap();
-            let arr1: [u8; 2] = chunk[2..=3].try_into().unwrap();
+            let instr = (u16::from_le_bytes([chunk[0], chunk[1]]) as u32) << 16 | (u16::from_le_bytes([chunk[2], chunk[3]]) as u32);
             rest = &chunk[4..];
-            let instr = (u16::from_le_bytes(arr0) as u32) << 16 | (u16::from_le_bytes(arr1) as u32);
-            Thumb::Thumb32(disassemble32(instr))
+            let encoding = Encoding::Thumb32(instr);
+            Thumb::Thumb32(disassemble32(instr).map_err(|e| e.into_error(address, encoding))?)
         },
         _ => { // 16 bit instruction
-            println!("16 bit instruction");
             // perhaps better to use the `byteorder` crate
-            let arr: [u8; 2] = chunk[0..=1].try_into().unwrap();
+            let instr = u16::from_le_bytes([chunk[0], chunk[1]]);
             rest = &chunk[2..];
-            let instr = u16::from_le_bytes(arr);
-            Thumb::Thumb16(disassemble16(instr))
+            let encoding = Encoding::Thumb16(instr);
+            Thumb::Thumb16(disassemble16(instr).map_err(|e| e.into_error(address, encoding))?)
         }
     };
 
@@ -564,17 +849,14 @@ fn test_inc() {
          100: 40 1c        	adds	r0, r0, #1
          102: 70 47        	bx	lr
     */
-    let mut chunk: &[u8] = &[0x40, 0x1c, 0x70, 0x47];
-
-    // ugly should use an iterator somehow
-    loop {
-        let (inst, rest) = disassemble(chunk).unwrap();
-        println!("{:?}", inst);
-        if rest.len() == 0 {
-            break;
-        };
-        chunk = rest
-    }
+    let chunk: &[u8] = &[0x40, 0x1c, 0x70, 0x47];
+
+    let instructions: Vec<_> = Instructions::new(chunk, 0x100).map(|x| x.unwrap().instruction).collect();
+
+    assert_eq!(instructions, [
+        Thumb::Thumb16(Thumb16::AddsImmT1(1, Register::R0, Register::R0)),
+        Thumb::Thumb16(Thumb16::BxT1(Register::LR)),
+    ]);
 }
 
 #[test]
@@ -592,7 +874,7 @@ fn test_cond() {
       114: 70 47        	bx	lr
     */
 
-    let mut chunk: &[u8] = &[
+    let chunk: &[u8] = &[
         0x0a, 0x28, // cmp	r0, #10
         0x02, 0xd8, // bhi	0x10e <cond_function+0xa> @ imm = #4
         0x01, 0x21, // movs	r1, #1
@@ -604,15 +886,19 @@ fn test_cond() {
         0x70, 0x47, // bx	lr
     ];
 
-    // ugly should use an iterator somehow
-    loop {
-        let (inst, rest) = disassemble(chunk).unwrap();
-        println!("{:?}", inst);
-        if rest.len() == 0 {
-            break;
-        };
-        chunk = rest
-    }
+    let decoded: Vec<_> = Instructions::new(chunk, 0x104).map(|x| x.unwrap()).collect();
+
+    assert_eq!(decoded.len(), 9);
+    assert_eq!(decoded[0].instruction, Thumb::Thumb16(Thumb16::CmpImmT1(Register::R0, 10)));
+    assert_eq!((decoded[1].address, decoded[1].size), (0x106, 2));
+    assert_eq!(decoded[1].instruction, Thumb::Thumb16(Thumb16::BImmT1(Cond::HI, 4)));
+    assert_eq!(decoded[2].instruction, Thumb::Thumb16(Thumb16::MovsImmT1(Register::R1, 1)));
+    assert_eq!(decoded[3].instruction, Thumb::Thumb16(Thumb16::AddsRegT1(Register::R0, Register::R1, Register::R0)));
+    assert_eq!(decoded[4].instruction, Thumb::Thumb16(Thumb16::BxT1(Register::LR)));
+    assert_eq!(decoded[5].address, 0x10e);
+    assert_eq!(decoded[5].instruction, Thumb::Thumb16(Thumb16::MovsImmT1(Register::R1, 0)));
+    assert_eq!(decoded[6].instruction, Thumb::Thumb16(Thumb16::DataProc(DpOpcode::MVN, Register::R1, Register::R1)));
+    assert_eq!(decoded[8].instruction, Thumb::Thumb16(Thumb16::BxT1(Register::LR)));
 }
 
 #[test]
@@ -634,7 +920,7 @@ fn test_cond2() {
      130: b0 bd        	pop	{r4, r5, r7, pc}
     */
 
-    let mut chunk: &[u8] = &[
+    let chunk: &[u8] = &[
         0xb0, 0xb5, // push	{r4, r5, r7, lr}
         0x02, 0xaf, // add	r7, sp, #8
         0x0c, 0x46, // mov	r4, r1
@@ -650,4 +936,116 @@ fn test_cond2() {
         0x40, 0x19, // adds	r0, r0, r5
         0xb0, 0xbd, // pop	{r4, r5, r7, pc}
     ];
+
+    let decoded: Vec<_> = Instructions::new(chunk, 0x116).map(|x| x.unwrap()).collect();
+
+    assert_eq!(decoded[0].instruction, Thumb::Thumb16(Thumb16::Push(RegisterList(0b0100_0000_1011_0000))));
+    assert_eq!((decoded[5].address, decoded[5].size), (0x120, 4));
+    assert_eq!(decoded[5].bytes, &[0x00, 0xf0, 0xe8, 0xfb]);
+    assert_eq!(decoded[5].instruction, Thumb::Thumb32(Thumb32::BlT1(2000)));
+    assert_eq!(decoded[6].address, 0x124);
+    assert_eq!(decoded[6].instruction, Thumb::Thumb16(Thumb16::DataProc(DpOpcode::CMP, Register::R4, Register::R5)));
+    assert_eq!(decoded[8].instruction, Thumb::Thumb16(Thumb16::SubsRegT1(Register::R0, Register::R4, Register::R0)));
+    assert_eq!(decoded[9].instruction, Thumb::Thumb16(Thumb16::Pop(RegisterList(0b1000_0000_1011_0000))));
+    assert_eq!(decoded[10].instruction, Thumb::Thumb16(Thumb16::LslImmT1(1, Register::R4, Register::R0)));
+}
+
+#[test]
+fn test_instructions_recover() {
+    /*
+      e8: de00         	udf	#0
+      ea: 0000         	movs	r0, r0
+      ec: 00 00 00 20  	.word	0x20000000
+      f0: 08 00 00 e9  	.word	0xe9000008
+      f4: 00 f0        	(first half of a bl)
+    */
+    let chunk: &[u8] = &[0x00, 0xde, 0x00, 0x00, 0x00, 0x00, 0x00, 0x20, 0x08, 0x00, 0x00, 0xe9, 0x00, 0xf0];
+    let mut instructions = Instructions::new(chunk, 0xe8);
+
+    assert_eq!(instructions.next().unwrap().unwrap().instruction, Thumb::Thumb16(Thumb16::UdfT1(0)));
+    assert_eq!(instructions.next().unwrap().unwrap().instruction, Thumb::Thumb16(Thumb16::MovsRegT2(Register::R0, Register::R0)));
+    assert_eq!(instructions.next().unwrap().unwrap().instruction, Thumb::Thumb16(Thumb16::MovsRegT2(Register::R0, Register::R0)));
+    // 0x2000 is movs r0, #0
+    assert_eq!(instructions.next().unwrap().unwrap().address, 0xee);
+    assert_eq!(instructions.next().unwrap().unwrap().address, 0xf0);
+    // e900 f000 is undefined, decoding picks up again at the next halfword
+    let encoding = Encoding::Thumb32(0xe900_f000);
+    assert_eq!(instructions.next().unwrap(), Err(Error::Undefined { address: 0xf2, encoding }));
+    assert_eq!(instructions.address(), 0xf4);
+    assert_eq!(instructions.next().unwrap(), Err(Error::Truncated { address: 0xf4, len: 2 }));
+    assert_eq!(instructions.next(), None);
+}
+
+// The lines of the objdump listing of the `inc` example with an encoding, each as its address,
+// the bytes in memory order and the text after the encoding.
+#[cfg(test)]
+fn inc_asm() -> impl Iterator<Item = (u32, Vec<u8>, &'static str)> {
+    include_str!("../app/inc.asm").lines().filter_map(|line| {
+        let (address, rest) = line.split_once(": ")?;
+        let (encoding, text) = rest.split_once('\t')?;
+        let address = u32::from_str_radix(address.trim(), 16).ok()?;
+        let encoding = encoding.trim().split(' ');
+        // data is listed byte by byte, instructions by halfword
+        let bytes = if text.starts_with('.') {
+            encoding.map(|x| u8::from_str_radix(x, 16).unwrap()).collect()
+        } else {
+            encoding.flat_map(|x| u16::from_str_radix(x, 16).unwrap().to_le_bytes()).collect()
+        };
+        Some((address, bytes, text))
+    })
+}
+
+#[test]
+fn test_inc_asm() {
+    // every instruction in the objdump listing of the `inc` example has to decode
+    for (address, chunk, text) in inc_asm() {
+        if text.starts_with('.') {
+            continue;
+        }
+        let (_, rest) = disassemble(&chunk, 0).unwrap();
+        assert!(rest.is_empty(), "{address:x}: {text}");
+    }
+}
+
+#[test]
+fn test_decode32() {
+    let cases: Vec<(&[u8], Thumb32)> = vec![
+        (&[0xbf, 0xf3, 0x4f, 0x8f], Thumb32::DsbT1(0b1111)),        // dsb sy
+        (&[0xbf, 0xf3, 0x5f, 0x8f], Thumb32::DmbT1(0b1111)),        // dmb sy
+        (&[0xbf, 0xf3, 0x6f, 0x8f], Thumb32::IsbT1(0b1111)),        // isb sy
+        (&[0xf0, 0xf7, 0x00, 0xa0], Thumb32::UdfT2(0)),             // udf.w #0
+        (&[0xff, 0xf7, 0xff, 0xaf], Thumb32::UdfT2(0xffff)),        // udf.w #65535
+        (&[0xef, 0xf3, 0x10, 0x80], Thumb32::MrsT1(Register::R0, SpecialRegister::PRIMASK)),  // mrs r0, PRIMASK
+        (&[0x80, 0xf3, 0x10, 0x88], Thumb32::MsrT1(Register::R0, SpecialRegister::PRIMASK)),  // msr PRIMASK, r0
+    ];
+
+    for (chunk, expected) in cases {
+        let (inst, rest) = disassemble(chunk, 0).unwrap();
+        assert_eq!(inst, Thumb::Thumb32(expected));
+        assert!(rest.is_empty());
+    }
+}
+
+#[test]
+fn test_decode_errors() {
+    // no halfword may panic the decoder
+    for instr in 0..=u16::MAX {
+        let _ = disassemble(&instr.to_le_bytes(), 0);
+        let _ = disassemble(&[instr.to_le_bytes(), [0xff, 0xff]].concat(), 0);
+    }
+
+    let encoding = Encoding::Thumb16(0x4700 | 0b001);
+    assert_eq!(disassemble(&[0x01, 0x47], 0x100), Err(Error::Unpredictable { address: 0x100, encoding })); // bx r0 with should-be-zero bits set
+    let encoding = Encoding::Thumb16(0xbc00);
+    assert_eq!(disassemble(&[0x00, 0xbc], 0x100), Err(Error::Unpredictable { address: 0x100, encoding })); // pop {}
+    let encoding = Encoding::Thumb16(0xbf10 | 0b1000);
+    assert_eq!(disassemble(&[0x18, 0xbf], 0x100), Err(Error::Undefined { address: 0x100, encoding })); // it ne
+    let encoding = Encoding::Thumb16(0xb100);
+    assert_eq!(disassemble(&[0x00, 0xb1], 0x100), Err(Error::Unallocated { address: 0x100, encoding })); // cbz only exists in ARMv7-M
+    let encoding = Encoding::Thumb32(0xe92d_4000);
+    assert_eq!(disassemble(&[0x2d, 0xe9, 0x00, 0x40], 0x100), Err(Error::Undefined { address: 0x100, encoding })); // push.w {lr}
+    let encoding = Encoding::Thumb32(0xf3ef_8004);
+    assert_eq!(disassemble(&[0xef, 0xf3, 0x04, 0x80], 0x100), Err(Error::Unpredictable { address: 0x100, encoding })); // mrs r0 with SYSm 4
+    assert_eq!(disassemble(&[0x00, 0xf0], 0x100), Err(Error::Truncated { address: 0x100, len: 2 }));
+    assert_eq!(disassemble(&[0x00], 0x100), Err(Error::Truncated { address: 0x100, len: 1 }));
 }
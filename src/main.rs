@@ -1,31 +1,506 @@
+use std::io::{self, BufRead, Write};
+use std::net::TcpListener;
+use std::path::PathBuf;
+use std::process::ExitCode;
+use std::time::Duration;
+
+use clap::{Args, Parser, Subcommand, ValueEnum};
+
 use disarm::*;
+use disarm::arm_cpu::*;
+use disarm::ast::Register;
+use disarm::instructions::Instructions;
+use disarm::symbols::{SymbolKind, SymbolTable};
+
+// exit codes, clap exits with 2 on usage errors
+const EXIT_OK: u8 = 0;
+// execution stopped on an instruction that could not be decoded
+const EXIT_DECODE_ERROR: u8 = 1;
+// the file could not be read or is not a usable ELF
+const EXIT_BAD_FILE: u8 = 3;
+// the instruction or cycle limit was reached before the program stopped
+const EXIT_INSTRUCTION_LIMIT: u8 = 4;
+// loading faulted or the processor locked up after a fault
+const EXIT_FAULT: u8 = 5;
+// the gdb server could not listen on its port
+const EXIT_NETWORK: u8 = 6;
+// the timeout elapsed before the program stopped
+const EXIT_TIMEOUT: u8 = 7;
+// a program exiting with a failure status n exits with EXIT_PROGRAM + n, clear of the codes
+// above, 255 for n of 191 and more
+const EXIT_PROGRAM: u8 = 64;
+
+#[derive(Parser)]
+#[command(name = "disarm", version, about = "ARMv6-M disassembler and emulator")]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    #[command(about = "Disassemble .text labelled with the symbol table")]
+    Disasm(FileArgs),
+    #[command(about = "List the symbols in .text")]
+    Symbols(FileArgs),
+    #[command(about = "Run until a breakpoint and print the registers")]
+    Run(RunArgs),
+    #[command(about = "Run printing every executed instruction")]
+    Trace(RunArgs),
+    #[command(about = "Step through the program interactively")]
+    Debug(RunArgs),
+    #[command(about = "Serve gdb `target extended-remote` connections like OpenOCD")]
+    Gdb(GdbArgs),
+}
+
+#[derive(Args)]
+struct FileArgs {
+    #[arg(help = "ELF file to load")]
+    elf: PathBuf,
+    #[arg(long, value_enum, default_value_t = Format::Text, help = "Output format")]
+    format: Format,
+}
+
+#[derive(Args)]
+struct RunArgs {
+    #[command(flatten)]
+    file: FileArgs,
+    #[arg(long, value_parser = parse_u32, default_value = "0", help = "Vector table to reset from")]
+    vtor: u32,
+    #[arg(long, value_parser = parse_u32, help = "Start address instead of the reset handler")]
+    entry: Option<u32>,
+    #[arg(long, value_parser = parse_u32, help = "Initial stack pointer instead of the one in the vector table")]
+    sp: Option<u32>,
+    #[arg(long, help = "Stop after this many instructions")]
+    max_instructions: Option<u64>,
+    #[arg(long, help = "Stop after this many cycles")]
+    max_cycles: Option<u64>,
+    #[arg(long, value_parser = parse_seconds, help = "Stop after this many seconds")]
+    timeout: Option<Duration>,
+    #[arg(long, help = "Stop on a branch to itself, like `loop {}`, that nothing can interrupt")]
+    stop_on_loop: bool,
+    #[arg(long, help = "Stop on WFI when nothing can wake the processor up")]
+    stop_on_wfi: bool,
+    #[arg(long, help = "Linker script with the MEMORY regions, like app/memory.x")]
+    memory: Option<PathBuf>,
+    #[arg(long, value_enum, default_value_t = CoreName::M0plus, help = "Processor CPUID reports")]
+    core: CoreName,
+}
 
-mod arm_cpu;
-use arm_cpu::*;
+#[derive(Args)]
+struct GdbArgs {
+    #[command(flatten)]
+    run: RunArgs,
+    #[arg(long, default_value_t = 3333, help = "Port to listen on, on localhost")]
+    port: u16,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, ValueEnum)]
+enum CoreName {
+    M0,
+    M0plus,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, ValueEnum)]
+enum Format {
+    Text,
+    Json,
+}
+
+// decimal or 0x prefixed hex
+fn parse_u32(x: &str) -> Result<u32, String> {
+    let parsed = match x.strip_prefix("0x").or_else(|| x.strip_prefix("0X")) {
+        Some(hex) => u32::from_str_radix(hex, 16),
+        None => x.parse(),
+    };
+    parsed.map_err(|e| format!("invalid address {x}: {e}"))
+}
+
+// fractions allowed
+fn parse_seconds(x: &str) -> Result<Duration, String> {
+    x.parse::<f64>()
+        .map_err(|e| e.to_string())
+        .and_then(|x| Duration::try_from_secs_f64(x).map_err(|e| e.to_string()))
+        .map_err(|e| format!("invalid timeout {x}: {e}"))
+}
 
-fn main() {
-    //let chunk: &[u8] = &[0x40, 0x1c, 0x70, 0x47];
-    //let dis = disassemble(chunk);
+fn main() -> ExitCode {
+    let cli = Cli::parse();
 
-    //println!("dis {:?}", dis);
-    let path = "app/target/thumbv6m-none-eabi/release/examples/inc";
-    println!("Trying to read elf file {path}.");
-    let path = std::path::PathBuf::from(path);
+    let path = match &cli.command {
+        Command::Disasm(x) | Command::Symbols(x) => &x.elf,
+        Command::Run(x) | Command::Trace(x) | Command::Debug(x) => &x.file.elf,
+        Command::Gdb(x) => &x.run.file.elf,
+    };
     let file_data = match std::fs::read(path) {
         Ok(x) => x,
         Err(e) => {
-            println!("Could not read file.");
-            return
+            eprintln!("disarm: could not read {}: {e}", path.display());
+            return ExitCode::from(EXIT_BAD_FILE)
         },
     };
-
     let program = match read_elf_file(&file_data) {
         Ok(x) => x,
+        Err(e) => {
+            eprintln!("disarm: {}: {e}", path.display());
+            return ExitCode::from(EXIT_BAD_FILE)
+        },
+    };
+
+    let code = match &cli.command {
+        Command::Disasm(args) => disasm(&program, args.format),
+        Command::Symbols(args) => symbols(&program, args.format),
+        Command::Run(args) => run(&program, args, false),
+        Command::Trace(args) => run(&program, args, true),
+        Command::Debug(args) => debug(&program, args),
+        Command::Gdb(args) => gdb(&program, args),
+    };
+    ExitCode::from(code)
+}
+
+fn disasm(program: &Program, format: Format) -> u8 {
+    if format == Format::Text {
+        print!("{}", program.listing());
+        return EXIT_OK
+    }
+
+    let symbols = program.get_symbols();
+    let mut lines = vec![];
+    for region in program.listing().regions() {
+        if region.data {
+            lines.push(format!(
+                "{{\"address\":{},\"data\":\"{}\"}}",
+                region.address,
+                hex(region.bytes)
+            ));
+            continue;
+        }
+        for decoded in Instructions::new(region.bytes, region.address) {
+            let line = match decoded {
+                Ok(x) => format!(
+                    "{{\"address\":{},\"bytes\":\"{}\",\"instruction\":{}}}",
+                    x.address,
+                    hex(x.bytes),
+                    json_string(&x.instruction.display_with_symbols(x.address, symbols).to_string())
+                ),
+                Err(e) => format!("{{\"error\":{}}}", json_string(&e.to_string())),
+            };
+            lines.push(line);
+        }
+    }
+    println!("[{}]", lines.join(",\n"));
+    EXIT_OK
+}
+
+fn symbols(program: &Program, format: Format) -> u8 {
+    let kind = |x: SymbolKind| match x {
+        SymbolKind::Function => "function",
+        SymbolKind::Object => "object",
+        SymbolKind::NoType => "notype",
+        SymbolKind::Code => "code",
+        SymbolKind::Data => "data",
+    };
+
+    let symbols = program.get_symbols().iter();
+    match format {
+        Format::Text => {
+            for symbol in symbols {
+                println!("{:08x} {:08x} {:<8} {}", symbol.address, symbol.size, kind(symbol.kind), symbol.name);
+            }
+        },
+        Format::Json => {
+            let lines: Vec<String> = symbols
+                .map(|x| format!(
+                    "{{\"address\":{},\"size\":{},\"kind\":\"{}\",\"name\":{}}}",
+                    x.address,
+                    x.size,
+                    kind(x.kind),
+                    json_string(&x.name)
+                ))
+                .collect();
+            println!("[{}]", lines.join(",\n"));
+        },
+    }
+    EXIT_OK
+}
+
+// a cpu reset into the program, or the exit code when that fails
+fn load(program: &Program, args: &RunArgs) -> Result<Cpu, u8> {
+    let memmory = match &args.memory {
+        Some(path) => {
+            let script = std::fs::read_to_string(path).map_err(|e| {
+                eprintln!("disarm: could not read {}: {e}", path.display());
+                EXIT_BAD_FILE
+            })?;
+            Memmory::from_memory_x(&script).map_err(|e| {
+                eprintln!("disarm: {}: {e}", path.display());
+                EXIT_BAD_FILE
+            })?
+        },
+        None => Memmory::default(),
+    };
+
+    let mut cpu = build_with_memmory(memmory);
+    cpu.set_vtor(args.vtor);
+    cpu.set_core(match args.core {
+        CoreName::M0 => Core::CortexM0,
+        CoreName::M0plus => Core::CortexM0Plus,
+    });
+    // keep stdout to the JSON
+    if args.file.format == Format::Json {
+        cpu.set_console(Box::new(io::stderr()));
+    }
+    if let Err(e) = cpu.load_program(program).and_then(|_| cpu.reset()) {
+        eprintln!("disarm: {e}");
+        return Err(EXIT_FAULT);
+    }
+    if let Some(sp) = args.sp {
+        cpu.set_register(Register::SP, sp);
+    }
+    if let Some(entry) = args.entry {
+        cpu.set_register(Register::PC, entry);
+    }
+    Ok(cpu)
+}
+
+fn run(program: &Program, args: &RunArgs, trace: bool) -> u8 {
+    let mut cpu = match load(program, args) {
+        Ok(x) => x,
+        Err(code) => return code,
+    };
+    let format = args.file.format;
+
+    let options = run_options(args);
+    let reason = if trace {
+        cpu.run_tracing(&options, |cpu| print_step(cpu, program.get_symbols(), format), |_| None)
+    } else {
+        cpu.run_with(&options)
+    };
+
+    print_stop(&cpu, &reason, format);
+    exit_code(&reason)
+}
+
+fn run_options(args: &RunArgs) -> RunOptions {
+    RunOptions {
+        max_instructions: args.max_instructions,
+        max_cycles: args.max_cycles,
+        timeout: args.timeout,
+        stop_on_self_branch: args.stop_on_loop,
+        stop_on_wfi: args.stop_on_wfi,
+    }
+}
+
+fn exit_code(reason: &StopReason) -> u8 {
+    match reason {
+        StopReason::Breakpoint(_)
+        | StopReason::AddressBreakpoint(_)
+        | StopReason::Watchpoint(..)
+        | StopReason::AddressReached(_)
+        | StopReason::StepComplete
+        | StopReason::Interrupted => EXIT_OK,
+        // the program is done, idling until reset
+        StopReason::SelfBranch(_) | StopReason::WaitForInterrupt(_) => EXIT_OK,
+        StopReason::DecodeError(_) => EXIT_DECODE_ERROR,
+        StopReason::InstructionLimit | StopReason::CycleLimit => EXIT_INSTRUCTION_LIMIT,
+        StopReason::Timeout => EXIT_TIMEOUT,
+        StopReason::Lockup(_) => EXIT_FAULT,
+        StopReason::Exit(0) => EXIT_OK,
+        StopReason::Exit(status) => status.saturating_add(EXIT_PROGRAM as u32).min(255) as u8,
+    }
+}
+
+// the instruction about to be executed
+fn print_step(cpu: &Cpu, symbols: &SymbolTable, format: Format) {
+    let pc = cpu.get_register(Register::PC);
+    let bytes = match cpu.fetch(pc) {
+        Ok(x) => x,
+        // reported as the stop reason
         Err(_) => return,
     };
+    let decoded = match Instructions::new(&bytes, pc).next() {
+        Some(Ok(x)) => x,
+        // reported as the stop reason
+        _ => return,
+    };
+
+    match format {
+        Format::Text => println!("{}", decoded.display_with_symbols(symbols)),
+        Format::Json => println!(
+            "{{\"pc\":{pc},\"bytes\":\"{}\",\"instruction\":{}}}",
+            hex(decoded.bytes),
+            json_string(&decoded.instruction.display_with_symbols(pc, symbols).to_string())
+        ),
+    }
+}
+
+fn print_stop(cpu: &Cpu, reason: &StopReason, format: Format) {
+    let mut registers: Vec<(String, u32)> = (0..16u8)
+        .map(|i| {
+            let register = Register::try_from(i).unwrap();
+            (register.to_string(), cpu.get_register(register))
+        })
+        .collect();
+    registers.push(("apsr".to_string(), cpu.get_apsr()));
+
+    match format {
+        Format::Text => {
+            println!("stopped: {reason}");
+            for (name, value) in registers {
+                println!("{name:<4} {value:#010x} {value}");
+            }
+        },
+        Format::Json => {
+            let registers: Vec<String> = registers.iter().map(|(name, value)| format!("\"{name}\":{value}")).collect();
+            println!(
+                "{{\"stop\":{},\"registers\":{{{}}}}}",
+                json_string(&reason.to_string()),
+                registers.join(",")
+            );
+        },
+    }
+}
+
+fn debug(program: &Program, args: &RunArgs) -> u8 {
+    let mut cpu = match load(program, args) {
+        Ok(x) => x,
+        Err(code) => return code,
+    };
+    let symbols = program.get_symbols();
+    let format = args.file.format;
+
+    println!("commands: s [n] step, n next, f finish, u <addr> until, b <addr> breakpoint, c continue, r registers, x <addr> [n] words, q quit");
+    let mut last = None;
+    loop {
+        print_step(&cpu, symbols, Format::Text);
+        print!("(disarm) ");
+        io::stdout().flush().unwrap();
+
+        let mut line = String::new();
+        if io::stdin().lock().read_line(&mut line).unwrap_or(0) == 0 {
+            break;
+        }
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let arg = |i: usize| words.get(i).map(|x| parse_u32(x));
+
+        match words.first().copied() {
+            Some("s") | Some("step") => {
+                let n = match arg(1) {
+                    Some(Ok(n)) => n,
+                    Some(Err(e)) => { println!("{e}"); continue },
+                    None => 1,
+                };
+                for _ in 0..n {
+                    if let Err(reason) = cpu.step() {
+                        print_stop(&cpu, &reason, format);
+                        last = Some(reason);
+                        break;
+                    }
+                }
+            },
+            Some("n") | Some("next") => {
+                let reason = cpu.step_over(&run_options(args));
+                print_stop(&cpu, &reason, format);
+                last = Some(reason);
+            },
+            Some("f") | Some("finish") => {
+                let reason = cpu.step_out(&run_options(args));
+                print_stop(&cpu, &reason, format);
+                last = Some(reason);
+            },
+            Some("u") | Some("until") => {
+                let address = match arg(1) {
+                    Some(Ok(x)) => x,
+                    Some(Err(e)) => { println!("{e}"); continue },
+                    None => { println!("u <addr>"); continue },
+                };
+                let reason = cpu.run_until(address, &run_options(args));
+                print_stop(&cpu, &reason, format);
+                last = Some(reason);
+            },
+            Some("b") | Some("break") => {
+                let address = match arg(1) {
+                    Some(Ok(x)) => x,
+                    Some(Err(e)) => { println!("{e}"); continue },
+                    None => { println!("b <addr>"); continue },
+                };
+                // toggles
+                if cpu.remove_breakpoint(address) {
+                    println!("breakpoint at {address:#x} removed");
+                } else {
+                    cpu.add_breakpoint(address);
+                    println!("breakpoint at {address:#x}");
+                }
+            },
+            Some("c") | Some("continue") => {
+                let reason = cpu.run_with(&run_options(args));
+                print_stop(&cpu, &reason, format);
+                last = Some(reason);
+            },
+            Some("r") | Some("registers") => cpu.print_registers_and_flags(),
+            Some("x") => {
+                let address = match arg(1) {
+                    Some(Ok(x)) => x,
+                    Some(Err(e)) => { println!("{e}"); continue },
+                    None => { println!("x <addr> [n]"); continue },
+                };
+                let n = match arg(2) {
+                    Some(Ok(n)) => n,
+                    Some(Err(e)) => { println!("{e}"); continue },
+                    None => 1,
+                };
+                for i in 0..n {
+                    let word_address = address.wrapping_add(4 * i);
+                    match cpu.read_u32(word_address) {
+                        Ok(x) => println!("{word_address:#010x}: {x:#010x}"),
+                        Err(e) => { println!("{e}"); break },
+                    }
+                }
+            },
+            Some("q") | Some("quit") => break,
+            Some(x) => println!("unknown command {x}"),
+            None => {},
+        }
+    }
+
+    last.as_ref().map_or(EXIT_OK, exit_code)
+}
+
+fn gdb(program: &Program, args: &GdbArgs) -> u8 {
+    let mut cpu = match load(program, &args.run) {
+        Ok(x) => x,
+        Err(code) => return code,
+    };
+    let listener = match TcpListener::bind(("127.0.0.1", args.port)) {
+        Ok(x) => x,
+        Err(e) => {
+            eprintln!("disarm: could not listen on port {}: {e}", args.port);
+            return EXIT_NETWORK;
+        },
+    };
+    eprintln!("disarm: waiting for gdb on port {}", args.port);
+    if let Err(e) = disarm::gdb::serve(&mut cpu, &listener, &run_options(&args.run)) {
+        eprintln!("disarm: {e}");
+    }
+    EXIT_NETWORK
+}
 
-    let mut cpu:Cpu = build();
-    cpu.load_program(&program);
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|x| format!("{x:02x}")).collect()
+}
 
-    cpu.start(program.get_start_addr());
+fn json_string(x: &str) -> String {
+    let mut escaped = String::from("\"");
+    for c in x.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\t' => escaped.push_str("\\t"),
+            '\n' => escaped.push_str("\\n"),
+            c if (c as u32) < 0x20 => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped.push('"');
+    escaped
 }
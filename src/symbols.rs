@@ -0,0 +1,279 @@
+// Symbols from the ELF symbol table, used to label addresses in listings the way objdump does.
+use std::fmt::{self, Display, Formatter};
+
+use crate::{display::BYTES_WIDTH, instructions::Instructions, Error};
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum SymbolKind {
+    Function,
+    Object,
+    NoType,
+    // $t mapping symbol, thumb code follows
+    Code,
+    // $d mapping symbol, a literal pool or other data follows
+    Data,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Symbol {
+    // demangled name
+    pub name: String,
+    // address, with the thumb bit cleared for functions
+    pub address: u32,
+    pub size: u32,
+    pub kind: SymbolKind,
+}
+
+impl Symbol {
+    pub fn new(name: &str, address: u32, size: u32, kind: SymbolKind) -> Symbol {
+        // keep the hash like objdump does, `inc::__cortex_m_rt_main::h11ef4a6c36885f4c`
+        let name = rustc_demangle::demangle(name).to_string();
+        // data can be at any byte
+        let address = if kind == SymbolKind::Function { address & !1 } else { address };
+        Symbol { name, address, size, kind }
+    }
+
+    // mapping symbols are named $t, $d or $t.<n>, $d.<n>
+    pub fn mapping(name: &str) -> Option<SymbolKind> {
+        match name.split('.').next() {
+            Some("$t") => Some(SymbolKind::Code),
+            Some("$d") => Some(SymbolKind::Data),
+            _ => None,
+        }
+    }
+
+    // $t symbols only switch the listing back to code, objdump never prints them as labels
+    fn is_label(&self) -> bool {
+        self.kind != SymbolKind::Code
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct SymbolTable {
+    // sorted by address
+    symbols: Vec<Symbol>,
+}
+
+impl SymbolTable {
+    pub fn new(mut symbols: Vec<Symbol>) -> SymbolTable {
+        symbols.sort_by_key(|x| x.address);
+        SymbolTable { symbols }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Symbol> {
+        self.symbols.iter()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.symbols.is_empty()
+    }
+
+    pub fn by_name(&self, name: &str) -> Option<&Symbol> {
+        self.symbols.iter().find(|x| x.name == name)
+    }
+
+    // The label printed for a symbol starting at address. Of several symbols at the same
+    // address a function wins over other symbols and those over a $d mapping symbol, then the
+    // last name in order.
+    pub fn label(&self, address: u32) -> Option<&Symbol> {
+        let rank = |x: &Symbol| match x.kind {
+            SymbolKind::Function => 2,
+            SymbolKind::Data => 0,
+            _ => 1,
+        };
+        self.symbols
+            .iter()
+            .filter(|x| x.address == address && x.is_label())
+            .max_by(|a, b| (rank(a), &a.name).cmp(&(rank(b), &b.name)))
+    }
+
+    // closest label at or before address and the offset from it
+    pub fn lookup(&self, address: u32) -> Option<(&Symbol, u32)> {
+        let start = self
+            .symbols
+            .iter()
+            .filter(|x| x.address <= address && x.is_label())
+            .map(|x| x.address)
+            .max()?;
+        self.label(start).map(|x| (x, address - start))
+    }
+
+    // data follows the last $d mapping symbol until a $t or a function starts
+    pub fn is_data(&self, address: u32) -> bool {
+        self.symbols
+            .iter()
+            .filter(|x| x.address <= address && x.kind != SymbolKind::Object && x.kind != SymbolKind::NoType)
+            .max_by_key(|x| (x.address, x.kind == SymbolKind::Data))
+            .is_some_and(|x| x.kind == SymbolKind::Data)
+    }
+
+    // first address after address where a symbol starts
+    fn next_boundary(&self, address: u32) -> Option<u32> {
+        self.symbols.iter().map(|x| x.address).find(|x| *x > address)
+    }
+
+    // `0x75e <__pre_init>` or `0xf0 <$d.21+0x4>`
+    pub fn format_address(&self, address: u32) -> String {
+        match self.lookup(address) {
+            Some((symbol, 0)) => format!("{address:#x} <{}>", symbol.name),
+            Some((symbol, offset)) => format!("{address:#x} <{}+{offset:#x}>", symbol.name),
+            None => format!("{address:#x}"),
+        }
+    }
+}
+
+// Disassembly of a section with a header at every symbol and data regions printed as
+// `.word` directives, laid out like `objdump -d`.
+pub struct Listing<'a> {
+    text: &'a [u8],
+    address: u32,
+    symbols: &'a SymbolTable,
+}
+
+impl<'a> Listing<'a> {
+    pub fn new(text: &'a [u8], address: u32, symbols: &'a SymbolTable) -> Listing<'a> {
+        Listing { text, address, symbols }
+    }
+
+    // the section split at every symbol
+    pub fn regions(&self) -> Vec<Region<'a>> {
+        let mut regions = vec![];
+        let end = self.address.wrapping_add(self.text.len() as u32);
+        let mut address = self.address;
+        while address < end {
+            // one region runs up to the next symbol
+            let region_end = match self.symbols.next_boundary(address) {
+                Some(x) if x < end => x,
+                _ => end,
+            };
+            let offset = (address - self.address) as usize;
+            regions.push(Region {
+                address,
+                bytes: &self.text[offset..offset + (region_end - address) as usize],
+                label: self.symbols.label(address),
+                data: self.symbols.is_data(address),
+            });
+            address = region_end;
+        }
+        regions
+    }
+}
+
+pub struct Region<'a> {
+    pub address: u32,
+    pub bytes: &'a [u8],
+    // symbol starting the region
+    pub label: Option<&'a Symbol>,
+    // a literal pool or other data, not code
+    pub data: bool,
+}
+
+impl Display for Listing<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Disassembly of section .text:")?;
+
+        for region in self.regions() {
+            if let Some(symbol) = region.label {
+                writeln!(f)?;
+                writeln!(f, "{:08x} <{}>:", region.address, symbol.name)?;
+            }
+
+            if region.data {
+                write_data(f, region.bytes, region.address)?;
+            } else {
+                write_code(f, region.bytes, region.address, self.symbols)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn write_code(f: &mut Formatter<'_>, region: &[u8], address: u32, symbols: &SymbolTable) -> fmt::Result {
+    let mut instructions = Instructions::new(region, address);
+    loop {
+        let (address, rest) = (instructions.address(), instructions.as_slice());
+        match instructions.next() {
+            Some(Ok(decoded)) => writeln!(f, "{}", decoded.display_with_symbols(symbols))?,
+            // odd bytes at the end of the region
+            Some(Err(Error::Truncated { .. })) if rest.len() < 2 => return write_data(f, rest, address),
+            Some(Err(_)) => {
+                let halfword = format!("{:04x}", u16::from_le_bytes([rest[0], rest[1]]));
+                writeln!(f, "{address:>8x}: {halfword:<width$}\t<unknown>", width = BYTES_WIDTH)?
+            }
+            None => return Ok(()),
+        }
+    }
+}
+
+fn write_data(f: &mut Formatter<'_>, region: &[u8], address: u32) -> fmt::Result {
+    let mut address = address;
+    let mut rest = region;
+    while !rest.is_empty() {
+        let size = match rest.len() {
+            1 => 1,
+            2 | 3 => 2,
+            _ => 4,
+        };
+        let bytes = &rest[..size];
+        let value = bytes.iter().rev().fold(0u32, |a, x| (a << 8) | *x as u32);
+        let hex: Vec<String> = bytes.iter().map(|x| format!("{x:02x}")).collect();
+        let directive = match size {
+            1 => format!(".byte\t0x{value:02x}"),
+            2 => format!(".short\t0x{value:04x}"),
+            _ => format!(".word\t0x{value:08x}"),
+        };
+        writeln!(f, "{address:>8x}: {:<width$}\t{directive}", hex.join(" "), width = BYTES_WIDTH)?;
+
+        rest = &rest[size..];
+        address = address.wrapping_add(size as u32);
+    }
+    Ok(())
+}
+
+#[test]
+fn test_listing_inc_asm() {
+    // rebuild .text and the symbol table from the reference listing and print it again
+    let reference = include_str!("../app/inc.asm");
+    let symbols = reference
+        .lines()
+        .filter_map(|line| line.strip_suffix(">:")?.split_once(" <"))
+        .map(|(address, name)| {
+            let kind = Symbol::mapping(name).unwrap_or(SymbolKind::Function);
+            Symbol::new(name, u32::from_str_radix(address, 16).unwrap(), 0, kind)
+        })
+        .collect();
+    let start = crate::inc_asm().next().unwrap().0;
+    let text: Vec<u8> = crate::inc_asm().flat_map(|(_, bytes, _)| bytes).collect();
+
+    let symbols = SymbolTable::new(symbols);
+    let listing = Listing::new(&text, start, &symbols).to_string();
+    let expected = &reference[reference.find("Disassembly").unwrap()..];
+    for (line, expected) in listing.lines().zip(expected.lines()) {
+        assert_eq!(line, expected);
+    }
+    assert_eq!(listing.lines().count(), expected.lines().count());
+}
+
+#[test]
+fn test_symbols() {
+    let symbols = SymbolTable::new(vec![
+        Symbol::new("_ZN3inc18__cortex_m_rt_main17h11ef4a6c36885f4cE", 0x13d, 0x200, SymbolKind::Function),
+        Symbol::new("$t.0", 0x100, 0, SymbolKind::Code),
+        Symbol::new("$d.1", 0x33c, 0, SymbolKind::Data),
+        Symbol::new("DefaultHandler_", 0x75c, 2, SymbolKind::Function),
+        Symbol::new("WWDG", 0x75c, 2, SymbolKind::Function),
+        Symbol::new("FLAG", 0x20000001, 1, SymbolKind::Object),
+    ]);
+
+    assert_eq!(symbols.format_address(0x13c), "0x13c <inc::__cortex_m_rt_main::h11ef4a6c36885f4c>");
+    assert_eq!(symbols.format_address(0x140), "0x140 <inc::__cortex_m_rt_main::h11ef4a6c36885f4c+0x4>");
+    assert_eq!(symbols.format_address(0x340), "0x340 <$d.1+0x4>");
+    assert_eq!(symbols.format_address(0x75c), "0x75c <WWDG>");
+    // only functions have a thumb bit
+    assert_eq!(symbols.format_address(0x20000001), "0x20000001 <FLAG>");
+    // $t is never a label
+    assert_eq!(symbols.format_address(0x102), "0x102");
+    assert!(!symbols.is_data(0x100));
+    assert!(symbols.is_data(0x340));
+    assert!(!symbols.is_data(0x75c));
+}